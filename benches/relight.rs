@@ -0,0 +1,163 @@
+// Compares the flood fill light engine with the sky light relaxation it replaced.
+// Run with: cargo bench --bench relight
+use std::time::{Duration, Instant};
+
+use bevy::{
+    math::IVec2,
+    utils::{HashMap, HashSet},
+};
+use mijocraft::{
+    biome::Biome,
+    block::{BlockRegistry, BlockType},
+    chunk::{Chunk, PlaceMode, CHUNK_AREA, CHUNK_WIDTH},
+    light::{LightEngine, MAX_LIGHT},
+    liquid::LiquidCell,
+    utils::{
+        get_chunk_position, get_global_position, get_index_from_position, get_position_from_index,
+        get_relative_position,
+    },
+};
+
+// The world is GRID_SIZE x GRID_SIZE chunks, from y = -GRID_SIZE / 2
+const GRID_SIZE: i32 = 8;
+const RUNS: u32 = 20;
+
+const DIRECTIONS: [IVec2; 4] = [IVec2::NEG_Y, IVec2::X, IVec2::Y, IVec2::NEG_X];
+
+// Hilly stone terrain with caves, and stone walls under the surface so the caves are dark
+fn bench_world(registry: &BlockRegistry) -> HashMap<IVec2, Chunk> {
+    let stone = registry.id("stone").unwrap();
+    let mut chunks = HashMap::new();
+    for cx in 0..GRID_SIZE {
+        for cy in -GRID_SIZE / 2..GRID_SIZE / 2 {
+            let chunk_position = IVec2::new(cx, cy);
+            let mut layers = [[BlockType::AIR; CHUNK_AREA]; 2];
+            for i in 0..CHUNK_AREA {
+                let p = get_global_position(chunk_position, get_position_from_index(i));
+                let surface = (p.x * 7).rem_euclid(11) / 2 + (p.x / 9).rem_euclid(5) * 3;
+                let cave = (p.x * 13 + p.y * 7).rem_euclid(23) < 5;
+                if p.y < surface && !cave {
+                    layers[PlaceMode::BLOCK as usize][i] = stone;
+                }
+                if p.y < surface - 2 {
+                    layers[PlaceMode::WALL as usize][i] = stone;
+                }
+            }
+            chunks.insert(
+                chunk_position,
+                Chunk {
+                    layers,
+                    light: [0; CHUNK_AREA],
+                    block_light: [[0; 3]; CHUNK_AREA],
+                    dirty: true,
+                    last_modified: 0,
+                    neighbors_modified: [0; 4],
+                    inhabited_time: 0,
+                    biomes: [Biome::Plains; CHUNK_WIDTH],
+                    block_entities: HashMap::new(),
+                    liquid: [LiquidCell::default(); CHUNK_AREA],
+                    scheduled_ticks: Vec::new(),
+                    signal: [[0; CHUNK_AREA]; 2],
+                    active_liquids: HashSet::new(),
+                },
+            );
+        }
+    }
+    return chunks;
+}
+
+fn every_block(chunks: &HashMap<IVec2, Chunk>) -> Vec<IVec2> {
+    let mut positions = Vec::new();
+    for chunk_position in chunks.keys() {
+        for i in 0..CHUNK_AREA {
+            positions.push(get_global_position(
+                *chunk_position,
+                get_position_from_index(i),
+            ));
+        }
+    }
+    return positions;
+}
+
+fn sky_light(chunks: &HashMap<IVec2, Chunk>, position: IVec2) -> Option<u8> {
+    let chunk_position = get_chunk_position(position);
+    let index = get_index_from_position(get_relative_position(position, chunk_position));
+    return chunks.get(&chunk_position).map(|chunk| chunk.light[index]);
+}
+
+// The sky light relaxation the flood fill replaced: 16 passes over every loaded chunk,
+// where each block takes the brightest of its neighbors minus one
+fn old_relaxation(chunks: &mut HashMap<IVec2, Chunk>, registry: &BlockRegistry) {
+    for _ in 0..16 {
+        let mut light_updates = Vec::new();
+        for (chunk_pos, chunk) in chunks.iter() {
+            let mut light = [0; CHUNK_AREA];
+            for (i, cell_light) in light.iter_mut().enumerate() {
+                let block = registry.get(chunk.layers[PlaceMode::BLOCK as usize][i]);
+                let wall = registry.get(chunk.layers[PlaceMode::WALL as usize][i]);
+                if block.transparent && wall.transparent {
+                    *cell_light = MAX_LIGHT;
+                    continue;
+                }
+
+                let global = get_global_position(*chunk_pos, get_position_from_index(i));
+                let brightest = DIRECTIONS
+                    .iter()
+                    .filter_map(|direction| sky_light(chunks, global + *direction))
+                    .max()
+                    .unwrap_or(0);
+                *cell_light = brightest.saturating_sub(1);
+            }
+            light_updates.push((*chunk_pos, light));
+        }
+
+        for (chunk_pos, light) in light_updates {
+            if let Some(chunk) = chunks.get_mut(&chunk_pos) {
+                chunk.light = light;
+                chunk.dirty = false;
+            }
+        }
+    }
+}
+
+fn time(runs: u32, mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..runs {
+        f();
+    }
+    return start.elapsed() / runs;
+}
+
+fn main() {
+    let registry = BlockRegistry::from_toml(include_str!("../assets/blocks.toml")).unwrap();
+    let world = bench_world(&registry);
+    let positions = every_block(&world);
+
+    let mut chunks = world.clone();
+    let old = time(RUNS, || old_relaxation(&mut chunks, &registry));
+
+    let mut engine = LightEngine::default();
+    let mut chunks = world.clone();
+    let whole = time(RUNS, || {
+        engine.relight(&mut chunks, &registry, &positions);
+    });
+
+    // Placing and breaking a block at the surface, which the old relaxation
+    // could only handle by running all of its passes again
+    let position = IVec2::new(40, 14);
+    let chunk_position = get_chunk_position(position);
+    let index = get_index_from_position(get_relative_position(position, chunk_position));
+    let stone = registry.id("stone").unwrap();
+    let mut placed = false;
+    let single = time(RUNS * 50, || {
+        placed = !placed;
+        let layer = &mut chunks.get_mut(&chunk_position).unwrap().layers[PlaceMode::BLOCK as usize];
+        layer[index] = if placed { stone } else { BlockType::AIR };
+        engine.relight(&mut chunks, &registry, &[position]);
+    });
+
+    println!("{} chunks, average of {} runs:", world.len(), RUNS);
+    println!("  old relaxation: {:?}", old);
+    println!("  flood fill of every block: {:?}", whole);
+    println!("  flood fill of one block change: {:?}", single);
+}
@@ -0,0 +1,87 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{
+    chunk::{Chunk, CHUNK_WIDTH},
+    utils::get_chunk_position,
+};
+
+// The biome of each column of the world, which is saved into the chunks.
+// The ids are saved into the chunk files, so don't reorder the variants.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Biome {
+    #[default]
+    Plains = 0,
+    Desert = 1,
+    Forest = 2,
+    Mountains = 3,
+}
+
+pub const BIOMES: [Biome; 4] = [
+    Biome::Plains,
+    Biome::Desert,
+    Biome::Forest,
+    Biome::Mountains,
+];
+
+pub struct BiomeSettings {
+    // The names of the block on the surface, and of the blocks under it before the stone starts
+    pub surface: &'static str,
+    pub filler: &'static str,
+    // The surface goes up and down by height_amplitude around height_offset
+    pub height_offset: f64,
+    pub height_amplitude: f64,
+    // Out of 100, how many of the tree cells have a tree
+    pub tree_chance: u64,
+}
+
+impl Biome {
+    pub fn from_id(id: u8) -> Self {
+        return match id {
+            1 => Biome::Desert,
+            2 => Biome::Forest,
+            3 => Biome::Mountains,
+            _ => Biome::Plains,
+        };
+    }
+
+    pub fn settings(self) -> BiomeSettings {
+        return match self {
+            Biome::Plains => BiomeSettings {
+                surface: "grass",
+                filler: "dirt",
+                height_offset: 0.0,
+                height_amplitude: 12.0,
+                tree_chance: 20,
+            },
+            Biome::Desert => BiomeSettings {
+                surface: "sand",
+                filler: "sand",
+                height_offset: -2.0,
+                height_amplitude: 8.0,
+                tree_chance: 0,
+            },
+            Biome::Forest => BiomeSettings {
+                surface: "grass",
+                filler: "dirt",
+                height_offset: 4.0,
+                height_amplitude: 20.0,
+                tree_chance: 85,
+            },
+            Biome::Mountains => BiomeSettings {
+                surface: "stone",
+                filler: "stone",
+                height_offset: 28.0,
+                height_amplitude: 56.0,
+                tree_chance: 0,
+            },
+        };
+    }
+}
+
+// Returns None if the chunk of the block isn't loaded
+pub fn get_biome(chunks: &HashMap<IVec2, Chunk>, block_position: IVec2) -> Option<Biome> {
+    let chunk = chunks.get(&get_chunk_position(block_position))?;
+    let x = block_position.x.rem_euclid(CHUNK_WIDTH as i32) as usize;
+    return Some(chunk.biomes[x]);
+}
@@ -0,0 +1,247 @@
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+use std::{fs, sync::Arc};
+
+use crate::{
+    block_entity::BlockEntityKind, block_tick::TickBehavior, circuit::CircuitKind,
+    item_container::ToolKind, liquid::LiquidProperties,
+};
+
+pub const BLOCK_REGISTRY_PATH: &str = "assets/blocks.toml";
+
+// The id of a block inside the BlockRegistry.
+// The id 0 is always reserved for air.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Hash, Default, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct BlockType(pub u16);
+
+impl BlockType {
+    pub const AIR: BlockType = BlockType(0);
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlockProperties {
+    pub id: u16,
+    pub name: String,
+    // Position of the block texture inside textures/blocks.png
+    #[serde(default)]
+    pub atlas_index: usize,
+    #[serde(default)]
+    pub transparent: bool,
+    #[serde(default = "default_collision")]
+    pub collision: bool,
+    #[serde(default)]
+    pub flip_horizontally: bool,
+    #[serde(default)]
+    pub flip_vertically: bool,
+    #[serde(default)]
+    pub light_emission: u8,
+    // Tint of the emitted light, each channel goes from 0.0 to 1.0
+    #[serde(default = "default_light_color")]
+    pub light_color: [f32; 3],
+    #[serde(default)]
+    pub hardness: f32,
+    // The tool that breaks this block faster
+    #[serde(default)]
+    pub tool: Option<ToolKind>,
+    // The kind of block entity the block gets when it's placed
+    #[serde(default)]
+    pub block_entity: Option<BlockEntityKind>,
+    // Whether the block falls when there's nothing under it, like sand
+    #[serde(default)]
+    pub gravity: bool,
+    // What the block does on random and scheduled ticks
+    #[serde(default)]
+    pub tick: Option<TickBehavior>,
+    // Blocks with liquid properties flow, and can be partially filled
+    #[serde(default)]
+    pub liquid: Option<LiquidProperties>,
+    // The part the block plays in circuits, like a wire or a logic gate
+    #[serde(default)]
+    pub circuit: Option<CircuitKind>,
+    // Blocks that only exist in the world, like a lit lamp, can't be held as items
+    #[serde(default = "default_item")]
+    pub item: bool,
+    // The name of the block dropped when this one is broken, if it's not the block itself
+    #[serde(default)]
+    pub drops: Option<String>,
+}
+
+fn default_collision() -> bool {
+    true
+}
+
+fn default_item() -> bool {
+    true
+}
+
+fn default_light_color() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+// How many seconds it takes to break a block for each point of hardness
+const BREAK_TIME_PER_HARDNESS: f32 = 1.5;
+// Holding the tool of a block breaks it this many times faster
+const TOOL_SPEED_MULTIPLIER: f32 = 4.0;
+
+impl BlockProperties {
+    // The light level this block emits in each of the red, green and blue channels
+    pub fn emitted_light(&self) -> [u8; 3] {
+        self.light_color
+            .map(|c| (self.light_emission as f32 * c.clamp(0.0, 1.0)).round() as u8)
+    }
+
+    // How many seconds it takes to break this block while holding the tool.
+    // Blocks with no hardness break instantly.
+    pub fn break_time(&self, tool: Option<ToolKind>) -> f32 {
+        let time = self.hardness * BREAK_TIME_PER_HARDNESS;
+        if tool.is_some() && tool == self.tool {
+            return time / TOOL_SPEED_MULTIPLIER;
+        }
+        return time;
+    }
+}
+
+#[derive(Deserialize)]
+struct BlockRegistryFile {
+    atlas_size: usize,
+    blocks: Vec<BlockProperties>,
+}
+
+// The block list is behind Arcs so the registry can be cheaply cloned into async tasks.
+#[derive(Resource, Clone, Debug)]
+pub struct BlockRegistry {
+    pub atlas_size: usize,
+    blocks: Arc<Vec<BlockProperties>>,
+    names: Arc<HashMap<String, BlockType>>,
+}
+
+impl BlockRegistry {
+    pub fn from_toml(string: &str) -> Result<Self, String> {
+        let file = toml::from_str::<BlockRegistryFile>(string).map_err(|e| e.to_string())?;
+
+        let mut blocks = file.blocks;
+        blocks.sort_by_key(|block| block.id);
+
+        for (i, block) in blocks.iter().enumerate() {
+            if block.id as usize != i {
+                return Err(format!(
+                    "block '{}' has the id {}, but {} was expected. Block ids must start at 0 and have no gaps",
+                    block.name, block.id, i
+                ));
+            }
+        }
+
+        match blocks.first() {
+            Some(air) if air.name == "air" => {}
+            _ => return Err("the block with the id 0 must be 'air'".to_string()),
+        }
+
+        let names = blocks
+            .iter()
+            .map(|block| (block.name.clone(), BlockType(block.id)))
+            .collect();
+
+        Ok(Self {
+            atlas_size: file.atlas_size,
+            blocks: Arc::new(blocks),
+            names: Arc::new(names),
+        })
+    }
+
+    // Unknown ids are treated as air
+    pub fn get(&self, block: BlockType) -> &BlockProperties {
+        self.blocks.get(block.0 as usize).unwrap_or(&self.blocks[0])
+    }
+
+    pub fn id(&self, name: &str) -> Option<BlockType> {
+        self.names.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    // The item a block gives when it's broken in survival, if any
+    pub fn dropped_block(&self, block: BlockType) -> Option<BlockType> {
+        let properties = self.get(block);
+        return match &properties.drops {
+            Some(name) => self.id(name),
+            None if properties.item => Some(block),
+            None => None,
+        };
+    }
+}
+
+pub struct BlockPlugin;
+
+impl Plugin for BlockPlugin {
+    fn build(&self, app: &mut App) {
+        let registry = fs::read_to_string(BLOCK_REGISTRY_PATH)
+            .map_err(|e| e.to_string())
+            .and_then(|string| BlockRegistry::from_toml(&string));
+
+        match registry {
+            Ok(registry) => {
+                info!("Loaded {} blocks from the block registry", registry.len());
+                app.insert_resource(registry);
+            }
+            Err(e) => panic!(
+                "Failed to load the block registry from '{}': {}",
+                BLOCK_REGISTRY_PATH, e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> BlockRegistry {
+        return BlockRegistry::from_toml(
+            r#"
+            atlas_size = 3
+
+            [[blocks]]
+            id = 0
+            name = "air"
+
+            [[blocks]]
+            id = 1
+            name = "stone"
+            hardness = 2.0
+            tool = "pickaxe"
+
+            [[blocks]]
+            id = 2
+            name = "torch"
+            "#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn matching_tool_breaks_faster() {
+        let registry = registry();
+        let stone = registry.get(registry.id("stone").unwrap());
+
+        let by_hand = stone.break_time(None);
+        assert_eq!(by_hand, 2.0 * BREAK_TIME_PER_HARDNESS);
+        assert_eq!(
+            stone.break_time(Some(ToolKind::Pickaxe)),
+            by_hand / TOOL_SPEED_MULTIPLIER
+        );
+        assert_eq!(stone.break_time(Some(ToolKind::Shovel)), by_hand);
+    }
+
+    #[test]
+    fn blocks_without_a_tool_ignore_the_held_tool() {
+        let registry = registry();
+        let torch = registry.get(registry.id("torch").unwrap());
+
+        assert_eq!(torch.break_time(None), 0.0);
+        assert_eq!(torch.break_time(Some(ToolKind::Pickaxe)), 0.0);
+    }
+}
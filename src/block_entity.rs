@@ -0,0 +1,78 @@
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chunk::Chunk,
+    item_container::{ItemContainer, ItemStack},
+    utils::{get_chunk_position, get_index_from_position, get_relative_position},
+};
+
+const CHEST_ROWS: usize = 3;
+const CHEST_COLUMNS: usize = 10;
+
+// The block entity a block gets when it's placed, set with "block_entity" in blocks.toml
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockEntityKind {
+    Chest,
+}
+
+impl BlockEntityKind {
+    pub fn create(&self) -> BlockEntity {
+        return match self {
+            BlockEntityKind::Chest => {
+                BlockEntity::Chest(ItemContainer::new(CHEST_ROWS, CHEST_COLUMNS))
+            }
+        };
+    }
+}
+
+// The state of a block that doesn't fit in its BlockType, which is saved with its chunk.
+// Only the blocks of the BLOCK layer can have one.
+// New kinds must be added as new variants at the end, as their index is what gets saved.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BlockEntity {
+    Chest(ItemContainer),
+}
+
+impl BlockEntity {
+    pub fn container(&self) -> Option<&ItemContainer> {
+        return match self {
+            BlockEntity::Chest(container) => Some(container),
+        };
+    }
+
+    pub fn container_mut(&mut self) -> Option<&mut ItemContainer> {
+        return match self {
+            BlockEntity::Chest(container) => Some(container),
+        };
+    }
+
+    // The items that fall out of the block when it's broken
+    pub fn drops(&self) -> Vec<ItemStack> {
+        let Some(container) = self.container() else {
+            return Vec::new();
+        };
+        return (0..container.len())
+            .filter_map(|index| container.get_slot(index).copied())
+            .collect();
+    }
+}
+
+pub fn get_block_entity(chunks: &HashMap<IVec2, Chunk>, position: IVec2) -> Option<&BlockEntity> {
+    let chunk_position = get_chunk_position(position);
+    let index = get_index_from_position(get_relative_position(position, chunk_position));
+    return chunks.get(&chunk_position)?.block_entities.get(&index);
+}
+
+pub fn get_block_entity_mut(
+    chunks: &mut HashMap<IVec2, Chunk>,
+    position: IVec2,
+) -> Option<&mut BlockEntity> {
+    let chunk_position = get_chunk_position(position);
+    let index = get_index_from_position(get_relative_position(position, chunk_position));
+    return chunks
+        .get_mut(&chunk_position)?
+        .block_entities
+        .get_mut(&index);
+}
@@ -0,0 +1,232 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::{
+    block::{BlockRegistry, BlockType},
+    chunk::{Chunk, PlaceMode, CHUNK_AREA},
+    chunk_manager::{Chunks, TryPlaceBlock},
+    utils::{
+        get_chunk_position, get_global_position, get_index_from_position, get_neighboring_blocks,
+        get_position_from_index, get_relative_position,
+    },
+    world::{WorldTick, WorldTickSchedule},
+};
+
+// How many random cells of each loaded chunk get a random tick on every world tick
+const RANDOM_TICKS_PER_CHUNK: usize = 1;
+
+// Leaves further than this from a tree log, counting through other leaves, decay
+const LEAF_DECAY_DISTANCE: usize = 4;
+
+const DIRECTIONS: [IVec2; 4] = [IVec2::NEG_Y, IVec2::X, IVec2::Y, IVec2::NEG_X];
+
+// What a block does when it's ticked, set with "tick" in blocks.toml
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TickBehavior {
+    // Spreads onto the dirt around it, and turns into dirt when it's covered
+    SpreadGrass,
+    // Disappears when there's no tree log close enough to hold it up
+    DecayLeaves,
+}
+
+impl TickBehavior {
+    // How many ticks after a neighbor changes the block gets its scheduled tick
+    fn delay(&self) -> u64 {
+        return match self {
+            TickBehavior::SpreadGrass => 40,
+            // Some randomness so a tree doesn't decay all at once
+            TickBehavior::DecayLeaves => rand::thread_rng().gen_range(10..60),
+        };
+    }
+}
+
+// A tick that a block in the BLOCK layer gets at a certain world tick
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScheduledTick {
+    pub index: usize,
+    pub tick: u64,
+}
+
+pub struct BlockTickPlugin;
+
+impl Plugin for BlockTickPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(WorldTickSchedule, tick_blocks);
+    }
+}
+
+// Schedules a tick for the block at the position, unless it already has an earlier one
+pub fn schedule_tick(chunks: &mut HashMap<IVec2, Chunk>, position: IVec2, tick: u64) {
+    let chunk_position = get_chunk_position(position);
+    let Some(chunk) = chunks.get_mut(&chunk_position) else {
+        return;
+    };
+    let index = get_index_from_position(get_relative_position(position, chunk_position));
+
+    match chunk
+        .scheduled_ticks
+        .iter_mut()
+        .find(|scheduled| scheduled.index == index)
+    {
+        Some(scheduled) => scheduled.tick = scheduled.tick.min(tick),
+        None => chunk.scheduled_ticks.push(ScheduledTick { index, tick }),
+    }
+}
+
+// A block changed, so the ticking blocks next to it are scheduled to react to it
+pub fn schedule_neighbor_ticks(
+    chunks: &mut HashMap<IVec2, Chunk>,
+    position: IVec2,
+    registry: &BlockRegistry,
+    current_tick: u64,
+) {
+    let Some(neighbors) = get_neighboring_blocks(chunks, position, PlaceMode::BLOCK) else {
+        return;
+    };
+    for (direction, neighbor) in DIRECTIONS.iter().zip(&neighbors[1..]) {
+        if let Some(behavior) = registry.get(*neighbor).tick {
+            schedule_tick(
+                chunks,
+                position + *direction,
+                current_tick + behavior.delay(),
+            );
+        }
+    }
+}
+
+fn tick_blocks(
+    mut chunks_res: ResMut<Chunks>,
+    mut try_place_block_ev: EventWriter<TryPlaceBlock>,
+    world_tick: Res<WorldTick>,
+    registry: Res<BlockRegistry>,
+) {
+    let mut rng = rand::thread_rng();
+    let mut ticked = Vec::new();
+
+    // The chunks are only marked as changed when a scheduled tick is taken out of them
+    let chunks = chunks_res.bypass_change_detection();
+    let mut took_scheduled = false;
+    for (chunk_position, chunk) in chunks.iter_mut() {
+        let before = chunk.scheduled_ticks.len();
+        chunk.scheduled_ticks.retain(|scheduled| {
+            if scheduled.tick > world_tick.0 {
+                return true;
+            }
+            ticked.push(get_global_position(
+                *chunk_position,
+                get_position_from_index(scheduled.index),
+            ));
+            return false;
+        });
+        took_scheduled |= chunk.scheduled_ticks.len() != before;
+
+        for _ in 0..RANDOM_TICKS_PER_CHUNK {
+            let index = rng.gen_range(0..CHUNK_AREA);
+            ticked.push(get_global_position(
+                *chunk_position,
+                get_position_from_index(index),
+            ));
+        }
+    }
+    if took_scheduled {
+        chunks_res.set_changed();
+    }
+
+    for position in ticked {
+        let Some(neighbors) = get_neighboring_blocks(&chunks_res, position, PlaceMode::BLOCK)
+        else {
+            continue;
+        };
+        let changes = match registry.get(neighbors[0]).tick {
+            Some(TickBehavior::SpreadGrass) => spread_grass(&chunks_res, position, &registry),
+            Some(TickBehavior::DecayLeaves) => decay_leaves(&chunks_res, position, &registry),
+            None => Vec::new(),
+        };
+        try_place_block_ev.send_batch(changes);
+    }
+}
+
+// A change made by a ticking block, which goes through TryPlaceBlock like any other.
+// It only happens if the block it was decided for is still there when it's applied.
+fn world_change(position: IVec2, replaces: BlockType, block_type: BlockType) -> TryPlaceBlock {
+    let chunk_position = get_chunk_position(position);
+    return TryPlaceBlock {
+        position: get_relative_position(position, chunk_position),
+        chunk_position,
+        layer: PlaceMode::BLOCK,
+        block_type,
+        by_player: false,
+        replaces: Some(replaces),
+    };
+}
+
+fn spread_grass(
+    chunks: &HashMap<IVec2, Chunk>,
+    position: IVec2,
+    registry: &BlockRegistry,
+) -> Vec<TryPlaceBlock> {
+    let (Some(grass), Some(dirt)) = (registry.id("grass"), registry.id("dirt")) else {
+        return Vec::new();
+    };
+    let Some(neighbors) = get_neighboring_blocks(chunks, position, PlaceMode::BLOCK) else {
+        return Vec::new();
+    };
+
+    // Grass needs light to live, so it dies under opaque blocks
+    if !registry.get(neighbors[3]).transparent {
+        return vec![world_change(position, grass, dirt)];
+    }
+
+    // It can also spread one block up or down, so it climbs over steps
+    let mut rng = rand::thread_rng();
+    let target = position + IVec2::new(rng.gen_range(-1..=1), rng.gen_range(-1..=1));
+    if target == position {
+        return Vec::new();
+    }
+    let Some(target_neighbors) = get_neighboring_blocks(chunks, target, PlaceMode::BLOCK) else {
+        return Vec::new();
+    };
+    if target_neighbors[0] == dirt && registry.get(target_neighbors[3]).transparent {
+        return vec![world_change(target, dirt, grass)];
+    }
+    return Vec::new();
+}
+
+fn decay_leaves(
+    chunks: &HashMap<IVec2, Chunk>,
+    position: IVec2,
+    registry: &BlockRegistry,
+) -> Vec<TryPlaceBlock> {
+    let (Some(leaves), Some(log)) = (registry.id("leaves"), registry.id("tree_log")) else {
+        return Vec::new();
+    };
+
+    // Looks for a log through the leaves around, with a breadth first search
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([(position, 0)]);
+    visited.insert(position);
+    while let Some((current, distance)) = queue.pop_front() {
+        let Some(neighbors) = get_neighboring_blocks(chunks, current, PlaceMode::BLOCK) else {
+            return Vec::new();
+        };
+        for (direction, neighbor) in DIRECTIONS.iter().zip(&neighbors[1..]) {
+            let next = current + *direction;
+            // Leaves at the edge of the loaded chunks are left alone, as their tree could be in an unloaded chunk
+            if *neighbor == log || !chunks.contains_key(&get_chunk_position(next)) {
+                return Vec::new();
+            }
+            if *neighbor == leaves && distance + 1 < LEAF_DECAY_DISTANCE && visited.insert(next) {
+                queue.push_back((next, distance + 1));
+            }
+        }
+    }
+
+    return vec![world_change(position, leaves, BlockType::AIR)];
+}
@@ -3,11 +3,13 @@ pub const TILE_SIZE: usize = 32;
 pub const CHUNK_WIDTH: usize = 16;
 pub const CHUNK_AREA: usize = CHUNK_WIDTH * CHUNK_WIDTH;
 
+// The chunks next to a chunk. Opposite sides are next to each other, so i ^ 1 is the opposite of i.
+pub const NEIGHBOR_CHUNKS: [IVec2; 4] = [IVec2::X, IVec2::NEG_X, IVec2::Y, IVec2::NEG_Y];
+
 const VERTICES_PER_BLOCK: usize = 4;
 const INDICES_PER_BLOCK: usize = 6;
-
-const CHUNK_MESH_SIZE: usize = CHUNK_AREA * VERTICES_PER_BLOCK;
-const CHUNK_INDEX_COUNT: usize = CHUNK_AREA * INDICES_PER_BLOCK;
+// How bright the circuit blocks without power are
+const UNPOWERED_TINT: f32 = 0.5;
 
 use bevy::{
     prelude::*,
@@ -16,83 +18,65 @@ use bevy::{
         render_asset::RenderAssetUsages,
     },
     sprite::Mesh2dHandle,
+    tasks::{AsyncComputeTaskPool, Task},
+    utils::{HashMap, HashSet},
 };
 use bevy_xpbd_2d::prelude::*;
-use enum_iterator::Sequence;
+use futures_util::FutureExt;
 use rand::{rngs::StdRng, Rng, SeedableRng};
 
 use crate::{
+    biome::Biome,
+    block::{BlockRegistry, BlockType},
+    block_entity::BlockEntity,
+    block_tick::ScheduledTick,
     chunk_manager::Chunks,
+    circuit::is_unpowered,
+    light::{combined_light, mix_light, update_light, RelightBlock, RelightChunk},
+    liquid::LiquidCell,
     utils::{
-        get_global_position, get_neighboring_blocks_with_corners, get_neighboring_lights,
-        get_neighboring_lights_with_corners, get_position_from_index,
+        get_chunk_position, get_global_position, get_index_from_position, get_position_from_index,
+        get_relative_position,
     },
+    world::WorldTime,
     GameSettings, GameState,
 };
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum PlaceMode {
     WALL = 0,
     BLOCK = 1,
 }
 
-use serde::{Deserialize, Serialize};
-
-#[derive(Clone, Copy, Debug, PartialEq, Default, PartialOrd, Serialize, Deserialize, Sequence)]
-pub enum BlockType {
-    #[default]
-    AIR = 0,
-    GRASS,
-    DIRT,
-    STONE,
-    COBBLESTONE,
-    PLANKS,
-    TREE_LOG,
-    LEAVES,
-    GLASS,
-    SIZE,
-}
-
-impl BlockType {
-    fn is_transparent(&self) -> bool {
-        match self {
-            BlockType::AIR => true,
-            BlockType::GLASS => true,
-            BlockType::LEAVES => true,
-            _ => false,
-        }
-    }
-
-    fn is_passthrough(&self) -> bool {
-        match self {
-            BlockType::AIR => true,
-            _ => false,
-        }
-    }
-
-    fn can_flip_horizontally(&self) -> bool {
-        match self {
-            BlockType::GRASS => true,
-            BlockType::DIRT => true,
-            BlockType::STONE => true,
-            BlockType::LEAVES => true,
-            _ => false,
-        }
-    }
-
-    fn can_flip_vertically(&self) -> bool {
-        match self {
-            BlockType::DIRT => true,
-            BlockType::TREE_LOG => true,
-            _ => false,
-        }
-    }
-}
-
 #[derive(Clone, Debug)]
 pub struct Chunk {
     pub layers: [[BlockType; CHUNK_AREA]; 2],
+    // Sky light
     pub light: [u8; CHUNK_AREA],
+    // Light coming from light emitting blocks, in red, green and blue
+    pub block_light: [[u8; 3]; CHUNK_AREA],
+    // True when the light array doesn't match the blocks anymore and has to be recalculated
+    pub dirty: bool,
+    // The world tick of the last time a block in this chunk was changed
+    pub last_modified: u64,
+    // The last_modified of the neighbors in NEIGHBOR_CHUNKS the last time this chunk was saved,
+    // for the ones that were loaded
+    pub neighbors_modified: [u64; 4],
+    // How many ticks this chunk has spent loaded
+    pub inhabited_time: u64,
+    // The biome of each column of the chunk
+    pub biomes: [Biome; CHUNK_WIDTH],
+    // The state of the blocks that have one, like the items of a chest, by their index in the BLOCK layer
+    pub block_entities: HashMap<usize, BlockEntity>,
+    // How full each liquid of the BLOCK layer is
+    pub liquid: [LiquidCell; CHUNK_AREA],
+    // The blocks that will be ticked at a certain world tick, in no particular order
+    pub scheduled_ticks: Vec<ScheduledTick>,
+    // The signal of each circuit block in both layers, or the state of the switches and buttons
+    pub signal: [[u8; CHUNK_AREA]; 2],
+    // The liquids of the BLOCK layer that may still flow, by their index.
+    // It isn't saved, and every liquid of a chunk is active again when it's loaded.
+    pub active_liquids: HashSet<usize>,
 }
 
 #[derive(Component)]
@@ -103,11 +87,12 @@ pub struct ChunkComponent {
 #[derive(Component)]
 pub struct ChunkLayer;
 
+// The positions of the chunks whose meshes have to be rebuilt.
+// All the events of a frame are merged, so each chunk is only remeshed once.
 #[derive(Event)]
-pub struct CalcLightChunks;
-
-#[derive(Event)]
-pub struct RemeshChunks;
+pub struct RemeshChunks {
+    pub positions: HashSet<IVec2>,
+}
 
 #[derive(Event)]
 pub struct RecollisionChunk {
@@ -118,297 +103,454 @@ pub struct ChunkPlugin;
 impl Plugin for ChunkPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<RemeshChunks>();
-        app.add_event::<CalcLightChunks>();
+        app.add_event::<RelightBlock>();
+        app.add_event::<RelightChunk>();
         app.add_event::<RecollisionChunk>();
         app.add_systems(
             Update,
-            (calculate_lighting, remesh, regenerate_collision)
+            (
+                update_light,
+                remesh,
+                apply_chunk_meshes,
+                regenerate_collision,
+            )
                 .chain()
                 .run_if(in_state(GameState::Game)),
         );
     }
 }
 
-fn calculate_lighting(mut chunks: ResMut<Chunks>, mut calc_light_ev: EventReader<CalcLightChunks>) {
-    for _ in calc_light_ev.read() {
-        // Iterate more times so it propagates
-        for _ in 0..16 {
-            // First pass: collect the light data
-            let mut light_updates = Vec::new();
-            for (chunk_pos, chunk) in chunks.iter() {
-                let mut light = [0; CHUNK_AREA];
-                for i in 0..CHUNK_AREA {
-                    if chunk.layers[PlaceMode::BLOCK as usize][i].is_transparent()
-                        && chunk.layers[PlaceMode::WALL as usize][i].is_transparent()
-                    {
-                        light[i] = 15;
-                    } else {
-                        let pos = get_position_from_index(i);
-                        let global = get_global_position(*chunk_pos, pos);
-                        if let Some(neighbors) = get_neighboring_lights(&chunks, global) {
-                            if let Some(max) = neighbors.iter().max() {
-                                if *max > 0 {
-                                    light[i] = max.saturating_sub(1);
-                                }
-                            }
-                        }
-                    }
-                }
-                light_updates.push((*chunk_pos, light));
-            }
+// The vertex and index buffers of a chunk layer, built in a task and applied to the mesh afterwards
+struct ChunkLayerMesh {
+    positions: Vec<[f32; 3]>,
+    colors: Vec<[f32; 4]>,
+    uvs: Vec<[f32; 2]>,
+    indices: Vec<u32>,
+}
+
+#[derive(Component)]
+struct ComputeChunkMesh(Task<[ChunkLayerMesh; 2]>);
+
+// The center, then down, right, up, left, and the bottom left, bottom right, top right and top left corners
+const NEIGHBOR_OFFSETS: [IVec2; 9] = [
+    IVec2::ZERO,
+    IVec2::NEG_Y,
+    IVec2::X,
+    IVec2::Y,
+    IVec2::NEG_X,
+    IVec2::new(-1, -1),
+    IVec2::new(1, -1),
+    IVec2::new(1, 1),
+    IVec2::new(-1, 1),
+];
+
+// The blocks and the light of a cell
+#[derive(Clone, Copy)]
+struct CellSnapshot {
+    layers: [BlockType; 2],
+    light: u8,
+    block_light: [u8; 3],
+}
+
+// What the mesh task needs to build the mesh of a chunk: a copy of the chunk,
+// and of the cells of its 8 neighbors that touch it,
+// since smooth lighting, ambient occlusion and liquids look at the blocks around
+struct MeshSnapshot {
+    chunk_position: IVec2,
+    chunk: Chunk,
+    // By global position. The cells of the neighbors that aren't loaded are missing.
+    border: HashMap<IVec2, CellSnapshot>,
+}
+
+impl MeshSnapshot {
+    fn new(chunks: &HashMap<IVec2, Chunk>, chunk_position: IVec2) -> Option<Self> {
+        let chunk = chunks.get(&chunk_position)?.clone();
 
-            // Second pass: apply the light updates
-            for (chunk_pos, light) in light_updates {
-                if let Some(m_chunk) = chunks.get_mut(&chunk_pos) {
-                    // Apply the light updates to m_chunk here
-                    m_chunk.light = light;
+        let origin = chunk_position * CHUNK_WIDTH as i32;
+        let inside = 0..CHUNK_WIDTH as i32;
+        let mut border = HashMap::new();
+        for y in -1..=CHUNK_WIDTH as i32 {
+            for x in -1..=CHUNK_WIDTH as i32 {
+                if inside.contains(&x) && inside.contains(&y) {
+                    continue;
+                }
+                let position = origin + IVec2::new(x, y);
+                let neighbor_position = get_chunk_position(position);
+                if let Some(neighbor) = chunks.get(&neighbor_position) {
+                    let index =
+                        get_index_from_position(get_relative_position(position, neighbor_position));
+                    border.insert(position, CellSnapshot::of(neighbor, index));
                 }
             }
         }
+
+        return Some(Self {
+            chunk_position,
+            chunk,
+            border,
+        });
+    }
+
+    fn cell(&self, position: IVec2) -> Option<CellSnapshot> {
+        if get_chunk_position(position) == self.chunk_position {
+            let index =
+                get_index_from_position(get_relative_position(position, self.chunk_position));
+            return Some(CellSnapshot::of(&self.chunk, index));
+        }
+        return self.border.get(&position).copied();
+    }
+
+    // The blocks around a position in the order of NEIGHBOR_OFFSETS, with air for the unloaded ones
+    fn neighboring_blocks(&self, position: IVec2, layer: PlaceMode) -> [BlockType; 9] {
+        return NEIGHBOR_OFFSETS.map(|offset| {
+            self.cell(position + offset)
+                .map_or(BlockType::AIR, |cell| cell.layers[layer as usize])
+        });
+    }
+
+    // The lights around a position in the order of NEIGHBOR_OFFSETS, with no light for the unloaded ones
+    fn neighboring_lights(&self, position: IVec2, sky_level: u8) -> [Vec3; 9] {
+        return NEIGHBOR_OFFSETS.map(|offset| {
+            self.cell(position + offset).map_or(Vec3::ZERO, |cell| {
+                mix_light(cell.light, cell.block_light, sky_level)
+            })
+        });
+    }
+}
+
+impl CellSnapshot {
+    fn of(chunk: &Chunk, index: usize) -> Self {
+        Self {
+            layers: [chunk.layers[0][index], chunk.layers[1][index]],
+            light: chunk.light[index],
+            block_light: chunk.block_light[index],
+        }
     }
 }
 
 fn remesh(
+    mut commands: Commands,
     mut remesh_chunk_ev: EventReader<RemeshChunks>,
     chunks: Res<Chunks>,
-    chunk_query: Query<(&Children, &ChunkComponent)>,
-    chunk_layer_query: Query<&Mesh2dHandle, With<ChunkLayer>>,
-    mut meshes: ResMut<Assets<Mesh>>,
+    chunk_query: Query<(Entity, &ChunkComponent)>,
     settings_res: Res<GameSettings>,
+    registry: Res<BlockRegistry>,
+    world_time: Res<WorldTime>,
 ) {
-    for _ in remesh_chunk_ev.read() {
-        for (chunk_children, chunk_comp) in chunk_query.iter() {
-            let Some(chunk) = chunks.get(&chunk_comp.position) else {
-                continue;
-            };
+    let sky_level = world_time.sky_light_level();
 
-            for li in 0..chunk.layers.len() {
-                let Ok(layer_mesh) = chunk_layer_query.get(chunk_children[li]) else {
-                    continue;
-                };
-                let Some(mesh) = meshes.get_mut(layer_mesh.0.id()) else {
-                    continue;
-                };
-                let mut vertex_positions = vec![[0.0; 3]; CHUNK_MESH_SIZE];
-                let mut vertex_colors = vec![[0.0; 4]; CHUNK_MESH_SIZE];
-                let mut vertex_uvs = vec![[0.0; 2]; CHUNK_MESH_SIZE];
-                let mut indices: Vec<u32> = generate_chunk_indices();
-
-                for i in 0..CHUNK_AREA {
-                    let position = get_position_from_index(i);
-                    if chunk.layers[li][i] <= BlockType::AIR {
-                        continue;
-                    }
-
-                    // Positions
-                    let pos_template = |pos: u32, x: bool| {
-                        pos as f32 * TILE_SIZE as f32 + (x as usize * TILE_SIZE) as f32
-                    };
-                    let p = |a: bool, b: bool| {
-                        [
-                            pos_template(position.x, a),
-                            pos_template(position.y, b),
-                            0.0,
-                        ]
-                    };
-                    let vertex_positions = &mut vertex_positions[i * VERTICES_PER_BLOCK..];
-                    vertex_positions[0] = p(false, false);
-                    vertex_positions[1] = p(true, false);
-                    vertex_positions[2] = p(true, true);
-                    vertex_positions[3] = p(false, true);
-
-                    // Vertex Colors
-                    // ...and also smooth lighting.
-                    let wall_darkness = settings_res.wall_darkness;
-                    let light = chunk.light[i] as f32 / 15.0;
-
-                    let color = match li == PlaceMode::WALL as usize {
-                        false => Color::srgb(light, light, light),
-                        true => Color::srgb(
-                            wall_darkness * light,
-                            wall_darkness * light,
-                            wall_darkness * light,
-                        ),
-                    };
-
-                    for vertex_color in vertex_colors[i * VERTICES_PER_BLOCK..].iter_mut().take(4) {
-                        *vertex_color = color.to_linear().to_vec4().to_array();
-                    }
-
-                    if settings_res.smooth_lighting {
-                        let global = get_global_position(chunk_comp.position, position);
-                        if let Some(neighbors) =
-                            get_neighboring_lights_with_corners(&chunks, global)
-                        {
-                            let get_color = |f_light: f32| -> [f32; 4] {
-                                if li == PlaceMode::BLOCK as usize {
-                                    return [f_light, f_light, f_light, 1.0];
-                                } else {
-                                    return [
-                                        wall_darkness * f_light,
-                                        wall_darkness * f_light,
-                                        wall_darkness * f_light,
-                                        1.0,
-                                    ];
-                                }
-                            };
-
-                            let normalize_light = |light: u8| {
-                                return light as f32 / 15.0;
-                            };
-
-                            // Bottom Left vertex
-                            let average = (
-                                normalize_light(neighbors[0]) + // Center
-                                normalize_light(neighbors[4]) + // Left
-                                normalize_light(neighbors[5]) + // Bottom Left
-                                normalize_light(neighbors[1])
-                                // Down
-                            ) / 4.0;
-                            vertex_colors[i * VERTICES_PER_BLOCK + 0] = get_color(average);
-
-                            // Bottom Right vertex
-                            let average = (
-                                normalize_light(neighbors[0]) + // Center
-                                normalize_light(neighbors[2]) + // Right
-                                normalize_light(neighbors[6]) + // Bottom Right
-                                normalize_light(neighbors[1])
-                                // Down
-                            ) / 4.0;
-                            vertex_colors[i * VERTICES_PER_BLOCK + 1] = get_color(average);
-
-                            // Top Right vertex
-                            let average = (
-                                normalize_light(neighbors[0]) + // Center
-                                normalize_light(neighbors[2]) + // Right
-                                normalize_light(neighbors[7]) + // Top Right
-                                normalize_light(neighbors[3])
-                                // Up
-                            ) / 4.0;
-                            vertex_colors[i * VERTICES_PER_BLOCK + 2] = get_color(average);
-
-                            // Top Left vertex
-                            let average = (
-                                normalize_light(neighbors[0]) + // Center
-                                normalize_light(neighbors[4]) + // Left
-                                normalize_light(neighbors[8]) + // Top Left
-                                normalize_light(neighbors[3])
-                                // Up
-                            ) / 4.0;
-                            vertex_colors[i * VERTICES_PER_BLOCK + 3] = get_color(average);
-                        }
-                    }
-
-                    // Wall Ambient Occlusion
-                    if settings_res.wall_ambient_occlusion && li == PlaceMode::WALL as usize {
-                        let global = get_global_position(chunk_comp.position, position);
-                        if let Some(neighbors) =
-                            get_neighboring_blocks_with_corners(&chunks, global, PlaceMode::BLOCK)
-                        {
-                            let ao_color: [f32; 4] = [0.1 * light, 0.1 * light, 0.1 * light, 1.0];
-
-                            // Down
-                            if !neighbors[1].is_transparent() {
-                                vertex_colors[i * VERTICES_PER_BLOCK + 0] = ao_color;
-                                vertex_colors[i * VERTICES_PER_BLOCK + 1] = ao_color;
-                            }
-
-                            // Right
-                            if !neighbors[2].is_transparent() {
-                                vertex_colors[i * VERTICES_PER_BLOCK + 1] = ao_color;
-                                vertex_colors[i * VERTICES_PER_BLOCK + 2] = ao_color;
-                            }
-
-                            // Up
-                            if !neighbors[3].is_transparent() {
-                                vertex_colors[i * VERTICES_PER_BLOCK + 2] = ao_color;
-                                vertex_colors[i * VERTICES_PER_BLOCK + 3] = ao_color;
-                            }
-
-                            // Left
-                            if !neighbors[4].is_transparent() {
-                                vertex_colors[i * VERTICES_PER_BLOCK + 0] = ao_color;
-                                vertex_colors[i * VERTICES_PER_BLOCK + 3] = ao_color;
-                            }
-
-                            // Now check for the corners!!
-                            // ===========================
-
-                            // Bottom Left
-                            if !neighbors[5].is_transparent() {
-                                vertex_colors[i * VERTICES_PER_BLOCK + 0] = ao_color;
-                                flip_quad(i, &mut indices);
-                            }
-
-                            // Bottom Right
-                            if !neighbors[6].is_transparent() {
-                                vertex_colors[i * VERTICES_PER_BLOCK + 1] = ao_color;
-                                //flip_quad(i, &mut indices);
-                            }
-
-                            // Top Right
-                            if !neighbors[7].is_transparent() {
-                                vertex_colors[i * VERTICES_PER_BLOCK + 2] = ao_color;
-                                flip_quad(i, &mut indices);
-                            }
-
-                            // Top Left
-                            if !neighbors[8].is_transparent() {
-                                vertex_colors[i * VERTICES_PER_BLOCK + 3] = ao_color;
-                                //flip_quad(i, &mut indices);
-                            }
-                        }
-                    }
-
-                    // Set block UVs
-                    let u = |a: i32| {
-                        (chunk.layers[li][i] as i32 + a) as f32
-                            / (BlockType::SIZE as i32 - 1) as f32
-                    };
-
-                    let uvs = &mut vertex_uvs[i * VERTICES_PER_BLOCK..];
-
-                    let global = (chunk_comp.position * CHUNK_WIDTH as i32) + position.as_ivec2();
-
-                    uvs[0] = [u(-1), 1.0];
-                    uvs[1] = [u(0), 1.0];
-                    uvs[2] = [u(0), 0.0];
-                    uvs[3] = [u(-1), 0.0];
-
-                    if chunk.layers[li][i].can_flip_horizontally() {
-                        if StdRng::seed_from_u64(u32::from_le_bytes(global.x.to_le_bytes()) as u64)
-                            .gen::<bool>()
-                        {
-                            uvs[0][0] = u(0);
-                            uvs[1][0] = u(-1);
-                            uvs[2][0] = u(-1);
-                            uvs[3][0] = u(0);
-                        }
-                    }
-
-                    if chunk.layers[li][i].can_flip_vertically() {
-                        if StdRng::seed_from_u64(u32::from_le_bytes(global.y.to_le_bytes()) as u64)
-                            .gen::<bool>()
-                        {
-                            uvs[0][1] = 0.0;
-                            uvs[1][1] = 0.0;
-                            uvs[2][1] = 1.0;
-                            uvs[3][1] = 1.0;
-                        }
-                    }
+    let mut pending: HashSet<IVec2> = HashSet::new();
+    for ev in remesh_chunk_ev.read() {
+        pending.extend(ev.positions.iter());
+    }
+
+    if pending.is_empty() {
+        return;
+    }
+
+    let thread_pool = AsyncComputeTaskPool::get();
+    for (entity, chunk_comp) in chunk_query.iter() {
+        if !pending.contains(&chunk_comp.position) {
+            continue;
+        }
+
+        let Some(snapshot) = MeshSnapshot::new(&chunks, chunk_comp.position) else {
+            continue;
+        };
+
+        let settings = settings_res.clone();
+        let registry = registry.clone();
+        let task = thread_pool.spawn(async move {
+            [0, 1].map(|li| mesh_chunk_layer(&snapshot, li, &settings, &registry, sky_level))
+        });
+
+        // If the chunk was still being meshed, the old task is dropped and cancelled.
+        // The chunk may already be despawned by a command queued earlier this frame.
+        commands.entity(entity).try_insert(ComputeChunkMesh(task));
+    }
+}
+
+fn mesh_chunk_layer(
+    snapshot: &MeshSnapshot,
+    li: usize,
+    settings: &GameSettings,
+    registry: &BlockRegistry,
+    sky_level: u8,
+) -> ChunkLayerMesh {
+    let chunk = &snapshot.chunk;
+    let chunk_position = snapshot.chunk_position;
+
+    // Only the blocks that aren't air get a quad
+    let mut vertex_positions: Vec<[f32; 3]> = Vec::new();
+    let mut vertex_colors: Vec<[f32; 4]> = Vec::new();
+    let mut vertex_uvs: Vec<[f32; 2]> = Vec::new();
+    let mut indices: Vec<u32> = Vec::new();
+
+    for i in 0..CHUNK_AREA {
+        let position = get_position_from_index(i);
+        if chunk.layers[li][i] == BlockType::AIR {
+            continue;
+        }
+        let properties = registry.get(chunk.layers[li][i]);
+
+        let q = vertex_positions.len() / VERTICES_PER_BLOCK;
+        vertex_positions.extend([[0.0; 3]; VERTICES_PER_BLOCK]);
+        vertex_colors.extend([[0.0; 4]; VERTICES_PER_BLOCK]);
+        vertex_uvs.extend([[0.0; 2]; VERTICES_PER_BLOCK]);
+        indices.extend(quad_indices(q));
+
+        // Positions
+        let pos_template =
+            |pos: u32, x: bool| pos as f32 * TILE_SIZE as f32 + (x as usize * TILE_SIZE) as f32;
+        let p = |a: bool, b: bool| {
+            [
+                pos_template(position.x, a),
+                pos_template(position.y, b),
+                0.0,
+            ]
+        };
+        let vertex_positions = &mut vertex_positions[q * VERTICES_PER_BLOCK..];
+        vertex_positions[0] = p(false, false);
+        vertex_positions[1] = p(true, false);
+        vertex_positions[2] = p(true, true);
+        vertex_positions[3] = p(false, true);
+
+        // Liquids are only as high as their level, unless there's more of the same liquid on top
+        let mut fill = 1.0;
+        if li == PlaceMode::BLOCK as usize && properties.liquid.is_some() {
+            let above = get_global_position(chunk_position, position) + IVec2::Y;
+            let same_above = snapshot
+                .cell(above)
+                .is_some_and(|cell| cell.layers[li] == chunk.layers[li][i]);
+            if !same_above {
+                fill = chunk.liquid[i].fill();
+                let top = (position.y as f32 + fill) * TILE_SIZE as f32;
+                vertex_positions[2][1] = top;
+                vertex_positions[3][1] = top;
+            }
+        }
+
+        // Vertex Colors
+        // ...and also smooth lighting.
+        let wall_darkness = settings.wall_darkness;
+        let mut light = combined_light(chunk, i, sky_level);
+        // Circuits without power are darker, so it's easy to see where the signal goes
+        let power_tint = match is_unpowered(chunk, li, i, registry) {
+            true => UNPOWERED_TINT,
+            false => 1.0,
+        };
+        light *= power_tint;
+
+        let color = match li == PlaceMode::WALL as usize {
+            false => Color::srgb(light.x, light.y, light.z),
+            true => Color::srgb(
+                wall_darkness * light.x,
+                wall_darkness * light.y,
+                wall_darkness * light.z,
+            ),
+        };
+
+        for vertex_color in vertex_colors[q * VERTICES_PER_BLOCK..].iter_mut().take(4) {
+            *vertex_color = color.to_linear().to_vec4().to_array();
+        }
+
+        if settings.smooth_lighting {
+            let global = get_global_position(chunk_position, position);
+            let neighbors = snapshot.neighboring_lights(global, sky_level);
+            let get_color = |f_light: Vec3| -> [f32; 4] {
+                if li == PlaceMode::BLOCK as usize {
+                    return (power_tint * f_light).extend(1.0).to_array();
+                } else {
+                    return (power_tint * wall_darkness * f_light)
+                        .extend(1.0)
+                        .to_array();
                 }
+            };
+
+            // Bottom Left vertex
+            let average = (
+                neighbors[0] + // Center
+                            neighbors[4] + // Left
+                            neighbors[5] + // Bottom Left
+                            neighbors[1]
+                // Down
+            ) / 4.0;
+            vertex_colors[q * VERTICES_PER_BLOCK + 0] = get_color(average);
+
+            // Bottom Right vertex
+            let average = (
+                neighbors[0] + // Center
+                            neighbors[2] + // Right
+                            neighbors[6] + // Bottom Right
+                            neighbors[1]
+                // Down
+            ) / 4.0;
+            vertex_colors[q * VERTICES_PER_BLOCK + 1] = get_color(average);
+
+            // Top Right vertex
+            let average = (
+                neighbors[0] + // Center
+                            neighbors[2] + // Right
+                            neighbors[7] + // Top Right
+                            neighbors[3]
+                // Up
+            ) / 4.0;
+            vertex_colors[q * VERTICES_PER_BLOCK + 2] = get_color(average);
+
+            // Top Left vertex
+            let average = (
+                neighbors[0] + // Center
+                            neighbors[4] + // Left
+                            neighbors[8] + // Top Left
+                            neighbors[3]
+                // Up
+            ) / 4.0;
+            vertex_colors[q * VERTICES_PER_BLOCK + 3] = get_color(average);
+        }
 
-                mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vertex_positions);
-                mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, vertex_colors);
-                mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, vertex_uvs);
-                mesh.insert_indices(Indices::U32(indices));
+        // Wall Ambient Occlusion
+        if settings.wall_ambient_occlusion && li == PlaceMode::WALL as usize {
+            let global = get_global_position(chunk_position, position);
+            let neighbors = snapshot.neighboring_blocks(global, PlaceMode::BLOCK);
+            let ao_color: [f32; 4] = (0.1 * light).extend(1.0).to_array();
+
+            // Down
+            if !registry.get(neighbors[1]).transparent {
+                vertex_colors[q * VERTICES_PER_BLOCK + 0] = ao_color;
+                vertex_colors[q * VERTICES_PER_BLOCK + 1] = ao_color;
+            }
+
+            // Right
+            if !registry.get(neighbors[2]).transparent {
+                vertex_colors[q * VERTICES_PER_BLOCK + 1] = ao_color;
+                vertex_colors[q * VERTICES_PER_BLOCK + 2] = ao_color;
+            }
+
+            // Up
+            if !registry.get(neighbors[3]).transparent {
+                vertex_colors[q * VERTICES_PER_BLOCK + 2] = ao_color;
+                vertex_colors[q * VERTICES_PER_BLOCK + 3] = ao_color;
+            }
+
+            // Left
+            if !registry.get(neighbors[4]).transparent {
+                vertex_colors[q * VERTICES_PER_BLOCK + 0] = ao_color;
+                vertex_colors[q * VERTICES_PER_BLOCK + 3] = ao_color;
+            }
+
+            // Now check for the corners!!
+            // ===========================
+
+            // Bottom Left
+            if !registry.get(neighbors[5]).transparent {
+                vertex_colors[q * VERTICES_PER_BLOCK + 0] = ao_color;
+                flip_quad(q, &mut indices);
+            }
+
+            // Bottom Right
+            if !registry.get(neighbors[6]).transparent {
+                vertex_colors[q * VERTICES_PER_BLOCK + 1] = ao_color;
+                //flip_quad(q, &mut indices);
+            }
+
+            // Top Right
+            if !registry.get(neighbors[7]).transparent {
+                vertex_colors[q * VERTICES_PER_BLOCK + 2] = ao_color;
+                flip_quad(q, &mut indices);
+            }
+
+            // Top Left
+            if !registry.get(neighbors[8]).transparent {
+                vertex_colors[q * VERTICES_PER_BLOCK + 3] = ao_color;
+                //flip_quad(q, &mut indices);
+            }
+        }
+
+        // Set block UVs
+        let u =
+            |a: i32| (properties.atlas_index as i32 + 1 + a) as f32 / registry.atlas_size as f32;
+
+        let uvs = &mut vertex_uvs[q * VERTICES_PER_BLOCK..];
+
+        let global = (chunk_position * CHUNK_WIDTH as i32) + position.as_ivec2();
+
+        uvs[0] = [u(-1), 1.0];
+        uvs[1] = [u(0), 1.0];
+        uvs[2] = [u(0), 1.0 - fill];
+        uvs[3] = [u(-1), 1.0 - fill];
+
+        if properties.flip_horizontally {
+            if StdRng::seed_from_u64(u32::from_le_bytes(global.x.to_le_bytes()) as u64)
+                .gen::<bool>()
+            {
+                uvs[0][0] = u(0);
+                uvs[1][0] = u(-1);
+                uvs[2][0] = u(-1);
+                uvs[3][0] = u(0);
             }
         }
+
+        if properties.flip_vertically {
+            if StdRng::seed_from_u64(u32::from_le_bytes(global.y.to_le_bytes()) as u64)
+                .gen::<bool>()
+            {
+                uvs[0][1] = 0.0;
+                uvs[1][1] = 0.0;
+                uvs[2][1] = 1.0;
+                uvs[3][1] = 1.0;
+            }
+        }
+    }
+
+    ChunkLayerMesh {
+        positions: vertex_positions,
+        colors: vertex_colors,
+        uvs: vertex_uvs,
+        indices,
+    }
+}
+
+fn apply_chunk_meshes(
+    mut commands: Commands,
+    mut task_query: Query<(Entity, &Children, &mut ComputeChunkMesh)>,
+    mut chunk_layer_query: Query<(&Mesh2dHandle, &mut Visibility), With<ChunkLayer>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    for (entity, chunk_children, mut task) in task_query.iter_mut() {
+        if !task.0.is_finished() {
+            continue;
+        }
+        let Some(layers) = (&mut task.0).now_or_never() else {
+            continue;
+        };
+        commands.entity(entity).remove::<ComputeChunkMesh>();
+
+        for (li, layer) in layers.into_iter().enumerate() {
+            let Ok((layer_mesh, mut visibility)) = chunk_layer_query.get_mut(chunk_children[li])
+            else {
+                continue;
+            };
+            let Some(mesh) = meshes.get_mut(layer_mesh.0.id()) else {
+                continue;
+            };
+
+            // Empty layers are hidden, so they aren't drawn at all
+            *visibility = match layer.indices.is_empty() {
+                true => Visibility::Hidden,
+                false => Visibility::Inherited,
+            };
+
+            mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, layer.positions);
+            mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, layer.colors);
+            mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, layer.uvs);
+            mesh.insert_indices(Indices::U32(layer.indices));
+        }
     }
 }
 
 mod collision {
-    use crate::chunk::{BlockType, CHUNK_AREA, CHUNK_WIDTH, TILE_SIZE};
+    use crate::block::{BlockRegistry, BlockType};
+    use crate::chunk::{CHUNK_AREA, CHUNK_WIDTH, TILE_SIZE};
     use bevy::prelude::{Name, Transform, TransformBundle, UVec2};
     use bevy_xpbd_2d::prelude::Collider;
 
@@ -444,17 +586,15 @@ mod collision {
         }
     }
 
-    type Chunk = [BlockType; CHUNK_AREA];
+    // Whether each block of the chunk has collision or not
+    type Chunk = [bool; CHUNK_AREA];
     type Meshes = Vec<Rectangle>;
 
     fn block_index(block: BlockPosition) -> usize {
         block.y as usize * CHUNK_WIDTH + block.x as usize
     }
-    fn collides(block: BlockType) -> bool {
-        !block.is_passthrough()
-    }
     fn block_at_collides(block: BlockPosition, chunk: &Chunk) -> bool {
-        collides(chunk[block_index(block)])
+        chunk[block_index(block)]
     }
     struct Column {
         x: u32,
@@ -530,12 +670,13 @@ mod collision {
             meshes.push(mesh(start, chunk, &meshes));
         }
     }
-    pub fn mesh_chunk(chunk: Chunk) -> Meshes {
+    pub fn mesh_chunk(blocks: &[BlockType; CHUNK_AREA], registry: &BlockRegistry) -> Meshes {
+        let chunk: Chunk = blocks.map(|btype| registry.get(btype).collision);
         let mut meshes = Vec::<Rectangle>::new();
         chunk
             .into_iter()
             .enumerate()
-            .filter(|&(_, btype)| collides(btype))
+            .filter(|&(_, collides)| collides)
             .map(|(i, _)| block_position(i))
             .for_each(|bpos| maybe_add_mesh_from(bpos, &chunk, &mut meshes));
         meshes
@@ -548,6 +689,7 @@ fn regenerate_collision(
     mut recol_chunk_ev: EventReader<RecollisionChunk>,
     chunk_query: Query<(&Children, &ChunkComponent)>,
     collider_query: Query<&Transform, With<Collider>>,
+    registry: Res<BlockRegistry>,
 ) {
     for ev in recol_chunk_ev.read() {
         let Ok((children, chunk_compo)) = chunk_query.get(ev.entity) else {
@@ -562,7 +704,7 @@ fn regenerate_collision(
             .filter(|&c| collider_query.get(*c).is_ok())
             .for_each(|&c| commands.entity(c).despawn_recursive());
 
-        collision::mesh_chunk(chunk.layers[PlaceMode::BLOCK as usize])
+        collision::mesh_chunk(&chunk.layers[PlaceMode::BLOCK as usize], &registry)
             .into_iter()
             .for_each(|mesh| {
                 commands
@@ -572,37 +714,26 @@ fn regenerate_collision(
     }
 }
 
+// The layer meshes start empty, and are filled in when the chunk is meshed
 pub fn generate_chunk_layer_mesh() -> Mesh {
-    let mut mesh_vec: Vec<[f32; 3]> = Vec::with_capacity(CHUNK_MESH_SIZE);
-    for _ in 0..CHUNK_MESH_SIZE {
-        mesh_vec.push([0.0, 0.0, 0.0]);
-    }
-
     Mesh::new(
         PrimitiveTopology::TriangleList,
         RenderAssetUsages::default(),
     )
-    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, mesh_vec)
-    .with_inserted_indices(Indices::U32(generate_chunk_indices()))
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, Vec::<[f32; 3]>::new())
+    .with_inserted_indices(Indices::U32(Vec::new()))
 }
 
-fn generate_chunk_indices() -> Vec<u32> {
-    let mut vec: Vec<u32> = vec![0; CHUNK_INDEX_COUNT];
-
-    let mut offset: usize = 0;
-    for i in (0..CHUNK_INDEX_COUNT).step_by(6) {
-        vec[i + 0] = 0 + offset as u32;
-        vec[i + 1] = 1 + offset as u32;
-        vec[i + 2] = 2 + offset as u32;
-
-        vec[i + 3] = 2 + offset as u32;
-        vec[i + 4] = 3 + offset as u32;
-        vec[i + 5] = 0 + offset as u32;
-
-        offset += 4;
-    }
-
-    return vec;
+fn quad_indices(quad_index: usize) -> [u32; INDICES_PER_BLOCK] {
+    let offset = (quad_index * VERTICES_PER_BLOCK) as u32;
+    return [
+        0 + offset,
+        1 + offset,
+        2 + offset,
+        2 + offset,
+        3 + offset,
+        0 + offset,
+    ];
 }
 
 fn flip_quad(quad_index: usize, indices: &mut Vec<u32>) {
@@ -617,3 +748,116 @@ fn flip_quad(quad_index: usize, indices: &mut Vec<u32>) {
     indices[i + 4] = 2 + offset as u32;
     indices[i + 5] = 3 + offset as u32;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{light::MAX_LIGHT, terrain::DefaultGenerator, world_generator::WorldGenerator};
+
+    fn registry() -> BlockRegistry {
+        return BlockRegistry::from_toml(include_str!("../assets/blocks.toml")).unwrap();
+    }
+
+    fn settings() -> GameSettings {
+        return GameSettings {
+            wall_ambient_occlusion: true,
+            smooth_lighting: true,
+            wall_darkness: 0.5,
+        };
+    }
+
+    fn chunk(layers: [[BlockType; CHUNK_AREA]; 2]) -> Chunk {
+        return Chunk {
+            layers,
+            light: [MAX_LIGHT; CHUNK_AREA],
+            block_light: [[0; 3]; CHUNK_AREA],
+            dirty: false,
+            last_modified: 0,
+            neighbors_modified: [0; 4],
+            inhabited_time: 0,
+            biomes: [Biome::Plains; CHUNK_WIDTH],
+            block_entities: HashMap::new(),
+            liquid: [LiquidCell::default(); CHUNK_AREA],
+            scheduled_ticks: Vec::new(),
+            signal: [[0; CHUNK_AREA]; 2],
+            active_liquids: HashSet::new(),
+        };
+    }
+
+    #[test]
+    fn all_air_chunk_has_no_vertices() {
+        let registry = registry();
+        let chunks = HashMap::from([(IVec2::ZERO, chunk([[BlockType::AIR; CHUNK_AREA]; 2]))]);
+        let snapshot = MeshSnapshot::new(&chunks, IVec2::ZERO).unwrap();
+        for li in 0..2 {
+            let mesh = mesh_chunk_layer(&snapshot, li, &settings(), &registry, 15);
+            assert!(mesh.positions.is_empty());
+            assert!(mesh.indices.is_empty());
+        }
+    }
+
+    #[test]
+    fn only_non_air_blocks_get_a_quad() {
+        let registry = registry();
+        let mut layers = [[BlockType::AIR; CHUNK_AREA]; 2];
+        layers[PlaceMode::BLOCK as usize][..10].fill(registry.id("stone").unwrap());
+        let chunks = HashMap::from([(IVec2::ZERO, chunk(layers))]);
+        let snapshot = MeshSnapshot::new(&chunks, IVec2::ZERO).unwrap();
+
+        let blocks = mesh_chunk_layer(&snapshot, 1, &settings(), &registry, 15);
+        assert_eq!(blocks.positions.len(), 10 * VERTICES_PER_BLOCK);
+        assert_eq!(blocks.indices.len(), 10 * INDICES_PER_BLOCK);
+    }
+
+    #[test]
+    fn snapshot_only_copies_the_cells_around_the_chunk() {
+        let registry = registry();
+        let stone = registry.id("stone").unwrap();
+        let mut chunks = HashMap::new();
+        for y in -1..=1 {
+            for x in -1..=1 {
+                let layers = [[BlockType::AIR; CHUNK_AREA]; 2];
+                chunks.insert(IVec2::new(x, y), chunk(layers));
+            }
+        }
+        chunks.get_mut(&IVec2::new(1, 1)).unwrap().layers[1][0] = stone;
+
+        let snapshot = MeshSnapshot::new(&chunks, IVec2::ZERO).unwrap();
+        assert_eq!(snapshot.border.len(), 4 * CHUNK_WIDTH + 4);
+        let corner = IVec2::splat(CHUNK_WIDTH as i32);
+        assert_eq!(snapshot.cell(corner).unwrap().layers[1], stone);
+        assert!(snapshot.cell(corner + IVec2::X).is_none());
+    }
+
+    // Compares the vertices of the meshes of a generated default world
+    // with the CHUNK_AREA quads per layer that every chunk used to have.
+    // Leaving out the quads of the air cells removes more than half of them.
+    #[test]
+    fn default_world_vertex_count() {
+        let registry = registry();
+        let generator = DefaultGenerator::new(&registry);
+        let mut chunks = HashMap::new();
+        for x in -8..8 {
+            for y in -4..4 {
+                let generated = generator.generate(IVec2::new(x, y), 0);
+                chunks.insert(IVec2::new(x, y), chunk([generated.walls, generated.blocks]));
+            }
+        }
+
+        let mut vertices = 0;
+        for chunk_position in chunks.keys() {
+            let snapshot = MeshSnapshot::new(&chunks, *chunk_position).unwrap();
+            for li in 0..2 {
+                let mesh = mesh_chunk_layer(&snapshot, li, &settings(), &registry, 15);
+                vertices += mesh.positions.len();
+            }
+        }
+        let full = chunks.len() * 2 * CHUNK_AREA * VERTICES_PER_BLOCK;
+        assert!(
+            vertices < full / 2,
+            "{} vertices, against {} with a quad for every block",
+            vertices,
+            full
+        );
+    }
+}
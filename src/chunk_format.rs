@@ -0,0 +1,500 @@
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use serde::{Deserialize, Serialize};
+use serde_big_array::Array;
+
+use crate::{
+    biome::Biome,
+    block::{BlockRegistry, BlockType},
+    block_entity::BlockEntity,
+    block_tick::ScheduledTick,
+    chunk::{Chunk, CHUNK_AREA, CHUNK_WIDTH},
+    liquid::LiquidCell,
+};
+
+// Every chunk saved into the region files starts with this header:
+// the magic number, followed by the format version as a little endian u16.
+// Chunks saved before the header existed don't have it, and are treated as version 0.
+const CHUNK_MAGIC: [u8; 4] = *b"MJCK";
+const HEADER_SIZE: usize = CHUNK_MAGIC.len() + 2;
+
+pub const CHUNK_FORMAT_VERSION: u16 = 1;
+
+// The names of the blocks in the order they had in the old BlockType enum,
+// which is what version 0 chunks were saved with.
+const V0_BLOCK_NAMES: [&str; 9] = [
+    "air",
+    "grass",
+    "dirt",
+    "stone",
+    "cobblestone",
+    "planks",
+    "tree_log",
+    "leaves",
+    "glass",
+];
+
+#[derive(Serialize, Deserialize)]
+struct ChunkFile {
+    sections: Vec<ChunkSection>,
+}
+
+// New kinds of data must be added as new variants at the end of this enum.
+// Never reorder or remove the variants, as their index is what gets saved.
+#[derive(Serialize, Deserialize)]
+enum ChunkSection {
+    // The layers store indices into the palette, which has the names of the blocks.
+    Blocks {
+        palette: Vec<String>,
+        layers: [Array<u16, CHUNK_AREA>; 2],
+    },
+    Light {
+        light: Array<u8, CHUNK_AREA>,
+    },
+    Metadata {
+        dirty: bool,
+        last_modified: u64,
+        inhabited_time: u64,
+    },
+    BlockLight {
+        light: Array<[u8; 3], CHUNK_AREA>,
+    },
+    Biomes {
+        biomes: [u8; CHUNK_WIDTH],
+    },
+    // The block items in the containers are indices into the palette of the Blocks section,
+    // which also has the names of the blocks that are only in the containers
+    BlockEntities {
+        entities: Vec<(u16, BlockEntity)>,
+    },
+    // The level of each liquid in the BLOCK layer, with the highest bit set for sources
+    Liquids {
+        levels: Array<u8, CHUNK_AREA>,
+    },
+    // The index of each block with a scheduled tick, and the world tick it runs at
+    ScheduledTicks {
+        ticks: Vec<(u16, u64)>,
+    },
+    // The signal of the circuit blocks in each layer
+    Signals {
+        signal: [Array<u8, CHUNK_AREA>; 2],
+    },
+    NeighborsModified {
+        neighbors_modified: [u64; 4],
+    },
+}
+
+// Numbers the blocks saved into a file in the order they are found, and keeps their names,
+// so the file can still be read after the ids in the registry change
+#[derive(Default)]
+pub struct BlockPalette {
+    pub names: Vec<String>,
+    ids: HashMap<BlockType, u16>,
+}
+
+impl BlockPalette {
+    pub fn local_id(&mut self, block: BlockType, registry: &BlockRegistry) -> u16 {
+        return *self.ids.entry(block).or_insert_with(|| {
+            self.names.push(registry.get(block).name.clone());
+            (self.names.len() - 1) as u16
+        });
+    }
+}
+
+// The blocks of a saved palette in the current registry, where the unknown ones are air
+pub fn palette_blocks(names: &[String], registry: &BlockRegistry, file: &str) -> Vec<BlockType> {
+    return names
+        .iter()
+        .map(|name| match registry.id(name) {
+            Some(id) => id,
+            None => {
+                warn!(
+                    "Unknown block '{}' in {}, replacing it with air",
+                    name, file
+                );
+                BlockType::AIR
+            }
+        })
+        .collect();
+}
+
+pub fn serialize_chunk(chunk: &Chunk, registry: &BlockRegistry) -> Result<Vec<u8>, String> {
+    let mut palette = BlockPalette::default();
+    let mut local_id = |block: BlockType| palette.local_id(block, registry);
+    let mut layers = [Array([0; CHUNK_AREA]); 2];
+
+    for (li, layer) in chunk.layers.iter().enumerate() {
+        for (i, block) in layer.iter().enumerate() {
+            layers[li][i] = local_id(*block);
+        }
+    }
+
+    let mut entities: Vec<(u16, BlockEntity)> = Vec::new();
+    for (index, entity) in chunk.block_entities.iter() {
+        let mut entity = entity.clone();
+        if let Some(container) = entity.container_mut() {
+            container.map_blocks(|block| Some(BlockType(local_id(block))));
+        }
+        entities.push((*index as u16, entity));
+    }
+    entities.sort_by_key(|(index, _)| *index);
+
+    let ticks: Vec<(u16, u64)> = chunk
+        .scheduled_ticks
+        .iter()
+        .map(|scheduled| (scheduled.index as u16, scheduled.tick))
+        .collect();
+
+    let file = ChunkFile {
+        sections: vec![
+            ChunkSection::Blocks {
+                palette: palette.names,
+                layers,
+            },
+            ChunkSection::Light {
+                light: Array(chunk.light),
+            },
+            ChunkSection::Metadata {
+                dirty: chunk.dirty,
+                last_modified: chunk.last_modified,
+                inhabited_time: chunk.inhabited_time,
+            },
+            ChunkSection::BlockLight {
+                light: Array(chunk.block_light),
+            },
+            ChunkSection::Biomes {
+                biomes: chunk.biomes.map(|biome| biome as u8),
+            },
+            ChunkSection::BlockEntities { entities },
+            ChunkSection::Liquids {
+                levels: Array(chunk.liquid.map(LiquidCell::to_byte)),
+            },
+            ChunkSection::ScheduledTicks { ticks },
+            ChunkSection::Signals {
+                signal: chunk.signal.map(Array),
+            },
+            ChunkSection::NeighborsModified {
+                neighbors_modified: chunk.neighbors_modified,
+            },
+        ],
+    };
+
+    let mut bytes = Vec::from(CHUNK_MAGIC);
+    bytes.extend_from_slice(&CHUNK_FORMAT_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut bytes, &file).map_err(|e| e.to_string())?;
+
+    Ok(bytes)
+}
+
+pub fn deserialize_chunk(bytes: &[u8], registry: &BlockRegistry) -> Result<Chunk, String> {
+    let (version, payload) = match bytes.get(0..HEADER_SIZE) {
+        Some(header) if header[0..CHUNK_MAGIC.len()] == CHUNK_MAGIC => (
+            u16::from_le_bytes([header[4], header[5]]),
+            &bytes[HEADER_SIZE..],
+        ),
+        _ => (0, bytes),
+    };
+
+    if version > CHUNK_FORMAT_VERSION {
+        return Err(format!(
+            "the chunk was saved with the format version {}, but the latest supported is {}",
+            version, CHUNK_FORMAT_VERSION
+        ));
+    }
+
+    let payload = migrate(version, payload.to_vec())?;
+    let file = bincode::deserialize::<ChunkFile>(&payload).map_err(|e| e.to_string())?;
+
+    let mut chunk = Chunk {
+        layers: [[BlockType::AIR; CHUNK_AREA]; 2],
+        light: [0; CHUNK_AREA],
+        block_light: [[0; 3]; CHUNK_AREA],
+        dirty: false,
+        last_modified: 0,
+        neighbors_modified: [0; 4],
+        inhabited_time: 0,
+        // Chunks saved before the biomes existed were generated like plains
+        biomes: [Biome::Plains; CHUNK_WIDTH],
+        block_entities: HashMap::new(),
+        // Liquids of chunks saved before the levels existed are full
+        liquid: [LiquidCell::default(); CHUNK_AREA],
+        scheduled_ticks: Vec::new(),
+        signal: [[0; CHUNK_AREA]; 2],
+        active_liquids: HashSet::new(),
+    };
+    // The Blocks section always comes before the sections that use its palette
+    let mut palette: Vec<BlockType> = Vec::new();
+    // Chunks saved before the light was stored need it to be calculated again
+    let mut has_light = false;
+    let mut has_block_light = false;
+
+    for section in file.sections {
+        match section {
+            ChunkSection::Blocks {
+                palette: names,
+                layers,
+            } => {
+                palette = palette_blocks(&names, registry, "chunk");
+
+                for li in 0..layers.len() {
+                    for i in 0..CHUNK_AREA {
+                        chunk.layers[li][i] = palette
+                            .get(layers[li][i] as usize)
+                            .copied()
+                            .unwrap_or(BlockType::AIR);
+                    }
+                }
+            }
+            ChunkSection::Light { light } => {
+                chunk.light = light.0;
+                has_light = true;
+            }
+            ChunkSection::Metadata {
+                dirty,
+                last_modified,
+                inhabited_time,
+            } => {
+                chunk.dirty = dirty;
+                chunk.last_modified = last_modified;
+                chunk.inhabited_time = inhabited_time;
+            }
+            ChunkSection::BlockLight { light } => {
+                chunk.block_light = light.0;
+                has_block_light = true;
+            }
+            ChunkSection::Biomes { biomes } => {
+                chunk.biomes = biomes.map(Biome::from_id);
+            }
+            ChunkSection::BlockEntities { entities } => {
+                for (index, mut entity) in entities {
+                    if index as usize >= CHUNK_AREA {
+                        warn!("Block entity outside of the chunk at index {}", index);
+                        continue;
+                    }
+                    // The items of unknown blocks were turned into air, which isn't an item
+                    if let Some(container) = entity.container_mut() {
+                        container.map_blocks(|block| {
+                            palette
+                                .get(block.0 as usize)
+                                .copied()
+                                .filter(|block| *block != BlockType::AIR)
+                        });
+                    }
+                    chunk.block_entities.insert(index as usize, entity);
+                }
+            }
+            ChunkSection::Liquids { levels } => {
+                chunk.liquid = levels.0.map(LiquidCell::from_byte);
+            }
+            ChunkSection::ScheduledTicks { ticks } => {
+                for (index, tick) in ticks {
+                    if index as usize >= CHUNK_AREA {
+                        warn!("Scheduled tick outside of the chunk at index {}", index);
+                        continue;
+                    }
+                    chunk.scheduled_ticks.push(ScheduledTick {
+                        index: index as usize,
+                        tick,
+                    });
+                }
+            }
+            ChunkSection::Signals { signal } => {
+                chunk.signal = signal.map(|layer| layer.0);
+            }
+            ChunkSection::NeighborsModified { neighbors_modified } => {
+                chunk.neighbors_modified = neighbors_modified;
+            }
+        }
+    }
+
+    chunk.dirty |= !has_light || !has_block_light;
+
+    Ok(chunk)
+}
+
+// Upgrades the payload of a chunk one version at a time, until it's in the latest version.
+// When the format changes, bump CHUNK_FORMAT_VERSION and add a step here
+// that converts the previous version into the new one.
+fn migrate(mut version: u16, mut payload: Vec<u8>) -> Result<Vec<u8>, String> {
+    while version < CHUNK_FORMAT_VERSION {
+        payload = match version {
+            0 => migrate_v0_to_v1(&payload)?,
+            _ => {
+                return Err(format!(
+                    "no migration from chunk format version {}",
+                    version
+                ))
+            }
+        };
+        version += 1;
+        debug!("Migrated chunk data to format version {}", version);
+    }
+
+    Ok(payload)
+}
+
+// Version 0 was the bare BlockType enum arrays, where each block was saved as its enum index.
+fn migrate_v0_to_v1(payload: &[u8]) -> Result<Vec<u8>, String> {
+    let old_layers =
+        bincode::deserialize::<[Array<u32, CHUNK_AREA>; 2]>(payload).map_err(|e| e.to_string())?;
+
+    let palette = V0_BLOCK_NAMES.iter().map(|name| name.to_string()).collect();
+    let mut layers = [Array([0; CHUNK_AREA]); 2];
+    for li in 0..layers.len() {
+        for i in 0..CHUNK_AREA {
+            let old = old_layers[li][i] as usize;
+            layers[li][i] = if old < V0_BLOCK_NAMES.len() {
+                old as u16
+            } else {
+                0
+            };
+        }
+    }
+
+    let file = ChunkFile {
+        sections: vec![ChunkSection::Blocks { palette, layers }],
+    };
+    bincode::serialize(&file).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chunk::PlaceMode,
+        item_container::{Item, ItemContainer, ItemStack, ToolKind},
+    };
+
+    fn registry() -> BlockRegistry {
+        return BlockRegistry::from_toml(include_str!("../assets/blocks.toml")).unwrap();
+    }
+
+    fn empty_chunk() -> Chunk {
+        return Chunk {
+            layers: [[BlockType::AIR; CHUNK_AREA]; 2],
+            light: [0; CHUNK_AREA],
+            block_light: [[0; 3]; CHUNK_AREA],
+            dirty: false,
+            last_modified: 0,
+            neighbors_modified: [0; 4],
+            inhabited_time: 0,
+            biomes: [Biome::Plains; CHUNK_WIDTH],
+            block_entities: HashMap::new(),
+            liquid: [LiquidCell::default(); CHUNK_AREA],
+            scheduled_ticks: Vec::new(),
+            signal: [[0; CHUNK_AREA]; 2],
+            active_liquids: HashSet::new(),
+        };
+    }
+
+    fn with_header(version: u16, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::from(CHUNK_MAGIC);
+        bytes.extend_from_slice(&version.to_le_bytes());
+        bytes.extend_from_slice(payload);
+        return bytes;
+    }
+
+    #[test]
+    fn round_trip_keeps_every_section() {
+        let registry = registry();
+        let stone = registry.id("stone").unwrap();
+        let glass = registry.id("glass").unwrap();
+        let water = registry.id("water").unwrap();
+
+        let mut chunk = empty_chunk();
+        chunk.layers[PlaceMode::WALL as usize][3] = stone;
+        chunk.layers[PlaceMode::BLOCK as usize][4] = water;
+        chunk.light[5] = 12;
+        chunk.block_light[6] = [1, 2, 3];
+        chunk.last_modified = 42;
+        chunk.neighbors_modified = [1, 2, 3, 4];
+        chunk.inhabited_time = 7;
+        chunk.biomes[2] = Biome::Desert;
+        chunk.liquid[4] = LiquidCell {
+            level: 5,
+            source: true,
+        };
+        chunk.scheduled_ticks.push(ScheduledTick {
+            index: 9,
+            tick: 100,
+        });
+        chunk.signal[PlaceMode::BLOCK as usize][10] = 15;
+
+        // Glass is only in the chest, so it has to be added to the palette
+        let mut container = ItemContainer::new(3, 10);
+        container.set_slot(0, Some(ItemStack::new(Item::Block(glass), 9)));
+        container.set_slot(1, Some(ItemStack::new(Item::Tool(ToolKind::Axe), 1)));
+        chunk
+            .block_entities
+            .insert(11, BlockEntity::Chest(container));
+
+        let bytes = serialize_chunk(&chunk, &registry).unwrap();
+        assert_eq!(bytes[..CHUNK_MAGIC.len()], CHUNK_MAGIC);
+        let loaded = deserialize_chunk(&bytes, &registry).unwrap();
+
+        assert_eq!(loaded.layers, chunk.layers);
+        assert_eq!(loaded.light, chunk.light);
+        assert_eq!(loaded.block_light, chunk.block_light);
+        assert!(!loaded.dirty);
+        assert_eq!(loaded.last_modified, chunk.last_modified);
+        assert_eq!(loaded.neighbors_modified, chunk.neighbors_modified);
+        assert_eq!(loaded.inhabited_time, chunk.inhabited_time);
+        assert_eq!(loaded.biomes, chunk.biomes);
+        assert_eq!(loaded.block_entities, chunk.block_entities);
+        assert_eq!(loaded.liquid, chunk.liquid);
+        assert_eq!(loaded.scheduled_ticks, chunk.scheduled_ticks);
+        assert_eq!(loaded.signal, chunk.signal);
+    }
+
+    // Version 0 files are the two layers as arrays of little endian u32 enum indices, without a header
+    #[test]
+    fn v0_chunk_migrates_to_the_palette() {
+        let registry = registry();
+        let mut bytes = vec![0; 2 * CHUNK_AREA * 4];
+        let mut set = |layer: usize, index: usize, old: u32| {
+            let offset = (layer * CHUNK_AREA + index) * 4;
+            bytes[offset..offset + 4].copy_from_slice(&old.to_le_bytes());
+        };
+        set(0, 0, 2);
+        set(1, 0, 3);
+        set(1, 1, 8);
+        set(1, CHUNK_AREA - 1, 1);
+        // Not a block of the old enum
+        set(1, 2, 99);
+
+        let chunk = deserialize_chunk(&bytes, &registry).unwrap();
+        let wall = &chunk.layers[PlaceMode::WALL as usize];
+        let block = &chunk.layers[PlaceMode::BLOCK as usize];
+        assert_eq!(wall[0], registry.id("dirt").unwrap());
+        assert_eq!(block[0], registry.id("stone").unwrap());
+        assert_eq!(block[1], registry.id("glass").unwrap());
+        assert_eq!(block[CHUNK_AREA - 1], registry.id("grass").unwrap());
+        assert_eq!(block[2], BlockType::AIR);
+        assert_eq!(wall[1], BlockType::AIR);
+        // The light wasn't saved yet, so it has to be calculated
+        assert!(chunk.dirty);
+    }
+
+    #[test]
+    fn future_version_is_rejected() {
+        let registry = registry();
+        let bytes = serialize_chunk(&empty_chunk(), &registry).unwrap();
+        let future = with_header(CHUNK_FORMAT_VERSION + 1, &bytes[HEADER_SIZE..]);
+        assert!(deserialize_chunk(&future, &registry).is_err());
+    }
+
+    #[test]
+    fn unknown_section_is_rejected() {
+        let registry = registry();
+        let no_sections = with_header(CHUNK_FORMAT_VERSION, &0u64.to_le_bytes());
+        assert!(deserialize_chunk(&no_sections, &registry).is_ok());
+
+        // One section, with a variant index that doesn't exist
+        let mut payload = 1u64.to_le_bytes().to_vec();
+        payload.extend_from_slice(&999u32.to_le_bytes());
+        let bytes = with_header(CHUNK_FORMAT_VERSION, &payload);
+        assert!(deserialize_chunk(&bytes, &registry).is_err());
+    }
+}
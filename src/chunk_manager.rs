@@ -1,36 +1,54 @@
+use std::collections::VecDeque;
+
 use bevy::{
     math::Vec3A,
     prelude::*,
     render::primitives::Aabb,
     sprite::{Anchor, MaterialMesh2dBundle},
     tasks::{AsyncComputeTaskPool, IoTaskPool, Task},
-    utils::HashMap,
+    utils::{HashMap, HashSet},
     window::PrimaryWindow,
 };
 use bevy_ecs::world::Command;
 use bevy_xpbd_2d::prelude::*;
 use futures_util::FutureExt;
-use noise::{Fbm, NoiseFn, Perlin};
-use std::io::ErrorKind;
 
 use crate::{
+    block::{BlockRegistry, BlockType},
+    block_tick::schedule_neighbor_ticks,
     chunk::{
-        generate_chunk_layer_mesh, BlockType, CalcLightChunks, Chunk, ChunkComponent, ChunkLayer,
-        ChunkPlugin, PlaceMode, RecollisionChunk, RemeshChunks, CHUNK_AREA, CHUNK_WIDTH, TILE_SIZE,
+        generate_chunk_layer_mesh, Chunk, ChunkComponent, ChunkLayer, ChunkPlugin, PlaceMode,
+        RecollisionChunk, RemeshChunks, CHUNK_AREA, CHUNK_WIDTH, NEIGHBOR_CHUNKS, TILE_SIZE,
     },
-    item_container::ItemContainer,
+    chunk_format::{deserialize_chunk, serialize_chunk},
+    dropped_item::DropItem,
+    falling_block::{unsupported_block, SpawnFallingBlock},
+    item_container::{Item, ItemContainer, ItemStack},
+    light::{RelightBlock, RelightChunk},
+    liquid::{wake_chunk_liquids, wake_liquids, LiquidCell},
+    region::{migrate_chunk_files, RegionStorage},
+    structure::{apply_structure_block, PendingBlocks, StructureBlock},
     utils::*,
-    world::{WorldGenPreset, WorldInfo},
+    widgets::inventory::{return_held_stack, HeldStack},
+    world::{GameMode, PlayerInventory, WorldInfo, WorldTick, WorldTime},
+    world_generator::{ChunkGenerationResult, CurrentWorldGenerator, WorldGenerators},
     GameSettings, MainCamera,
 };
-use crate::{player::Player, world::FromWorld, GameState};
+use crate::{player::Player, world::FromWorld, GamePauseState, GameState};
 
-#[derive(Event)]
+#[derive(Event, Clone)]
 pub struct TryPlaceBlock {
     pub position: UVec2,
     pub chunk_position: IVec2,
     pub layer: PlaceMode,
     pub block_type: BlockType,
+    // Blocks changed by the world itself, like falling blocks, don't need anything to be placed against,
+    // and don't use up or drop items
+    pub by_player: bool,
+    // The block that has to be in the cell for the change to happen.
+    // Changes made by the world are decided before they are applied,
+    // so they are skipped if the player changed the block in between.
+    pub replaces: Option<BlockType>,
 }
 
 #[derive(Event)]
@@ -76,6 +94,7 @@ impl Command for FinishChunkLoadingTask {
 pub struct SpawnChunk {
     pub position: IVec2,
     pub chunk: Chunk,
+    pub structures: Vec<StructureBlock>,
 }
 
 #[derive(Resource, DerefMut, Deref)]
@@ -99,6 +118,14 @@ impl Plugin for ChunkManagerPlugin {
         app.insert_resource(JustCreatedWorld(false));
 
         app.add_plugins(ChunkPlugin);
+        app.add_systems(
+            OnEnter(GameState::Game),
+            (
+                setup_region_storage,
+                setup_world_generator,
+                setup_pending_blocks,
+            ),
+        );
         app.add_systems(
             Update,
             (
@@ -113,6 +140,12 @@ impl Plugin for ChunkManagerPlugin {
                 .chain()
                 .run_if(in_state(GameState::Game)),
         );
+        app.add_systems(
+            FixedUpdate,
+            update_inhabited_time
+                .run_if(in_state(GameState::Game))
+                .run_if(in_state(GamePauseState::Running)),
+        );
         app.add_systems(OnExit(GameState::Game), clear_chunks);
     }
 }
@@ -122,18 +155,80 @@ pub fn spawn_chunk(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
     mut spawn_chunk_ev: EventReader<SpawnChunk>,
-    mut calc_light_ev: EventWriter<CalcLightChunks>,
+    mut relight_chunk_ev: EventWriter<RelightChunk>,
     mut remesh_chunk_ev: EventWriter<RemeshChunks>,
     mut recol_chunk_ev: EventWriter<RecollisionChunk>,
+    mut relight_block_ev: EventWriter<RelightBlock>,
     mut chunks_res: ResMut<Chunks>,
+    mut pending_blocks: ResMut<PendingBlocks>,
+    chunk_query: Query<(Entity, &ChunkComponent)>,
     asset_server: Res<AssetServer>,
+    registry: Res<BlockRegistry>,
 ) {
     for ev in spawn_chunk_ev.read() {
         if chunks_res.contains_key(&ev.position) {
             return;
         }
 
-        chunks_res.insert(ev.position, ev.chunk.clone());
+        // Structures of other chunks that grew into this one while it wasn't loaded
+        let mut chunk = ev.chunk.clone();
+        for block in pending_blocks.take(ev.position) {
+            if apply_structure_block(&mut chunk, ev.position, &block, &registry) {
+                chunk.dirty = true;
+            }
+        }
+
+        // The saved light is reused, unless a loaded neighbor was changed after this chunk
+        // was saved, or this chunk after the neighbor was saved,
+        // since then one of them was changed while the other wasn't loaded
+        let mut relight = Vec::new();
+        if chunk.dirty {
+            relight.push(ev.position);
+        }
+        for (i, offset) in NEIGHBOR_CHUNKS.into_iter().enumerate() {
+            let Some(neighbor) = chunks_res.get(&(ev.position + offset)) else {
+                continue;
+            };
+            if neighbor.last_modified > chunk.neighbors_modified[i]
+                || chunk.last_modified > neighbor.neighbors_modified[i ^ 1]
+            {
+                if !relight.contains(&ev.position) {
+                    relight.push(ev.position);
+                }
+                relight.push(ev.position + offset);
+            }
+        }
+
+        chunks_res.insert(ev.position, chunk);
+        wake_chunk_liquids(&mut chunks_res, ev.position, &registry);
+
+        // The structures of a new chunk are written into the loaded chunks right away,
+        // and saved for later for the others
+        let mut changed_chunks = Vec::new();
+        for block in ev.structures.iter() {
+            let chunk_pos = get_chunk_position(block.position);
+            let Some(target) = chunks_res.get_mut(&chunk_pos) else {
+                pending_blocks.push(*block);
+                continue;
+            };
+            if apply_structure_block(target, chunk_pos, block, &registry) {
+                wake_liquids(&mut chunks_res, block.position, &registry);
+                relight_block_ev.send(RelightBlock {
+                    position: block.position,
+                });
+                remesh_chunk_ev.send(RemeshChunks {
+                    positions: get_chunks_touching_block(block.position),
+                });
+                if chunk_pos != ev.position && !changed_chunks.contains(&chunk_pos) {
+                    changed_chunks.push(chunk_pos);
+                }
+            }
+        }
+        for (entity, chunk_compo) in chunk_query.iter() {
+            if changed_chunks.contains(&chunk_compo.position) {
+                recol_chunk_ev.send(RecollisionChunk { entity });
+            }
+        }
 
         let pixel_chunk_pos = ev.position.as_vec2() * CHUNK_WIDTH as f32 * TILE_SIZE as f32;
         let chunk_material_handle = materials.add(asset_server.load("textures/blocks.png"));
@@ -168,6 +263,7 @@ pub fn spawn_chunk(
                     MaterialMesh2dBundle {
                         mesh: meshes.add(generate_chunk_layer_mesh()).into(),
                         material: chunk_material_handle.clone(),
+                        visibility: Visibility::Hidden,
                         transform: Transform::from_xyz(0.0, 0.0, -1.0),
                         ..default()
                     },
@@ -183,6 +279,7 @@ pub fn spawn_chunk(
                     MaterialMesh2dBundle {
                         mesh: meshes.add(generate_chunk_layer_mesh()).into(),
                         material: chunk_material_handle,
+                        visibility: Visibility::Hidden,
                         transform: Transform::from_xyz(0.0, 0.0, 0.0),
                         ..default()
                     },
@@ -195,8 +292,13 @@ pub fn spawn_chunk(
             })
             .id();
 
-        calc_light_ev.send(CalcLightChunks);
-        remesh_chunk_ev.send(RemeshChunks);
+        for position in relight {
+            relight_chunk_ev.send(RelightChunk { position });
+        }
+        // The neighbors are remeshed too, as their borders depend on the blocks of this chunk
+        remesh_chunk_ev.send(RemeshChunks {
+            positions: get_chunks_around(ev.position),
+        });
         recol_chunk_ev.send(RecollisionChunk { entity: id });
     }
 }
@@ -204,22 +306,46 @@ pub fn spawn_chunk(
 fn try_to_place_block_event(
     mut chunks_res: ResMut<Chunks>,
     mut try_place_block_ev: EventReader<TryPlaceBlock>,
-    mut calc_light_ev: EventWriter<CalcLightChunks>,
+    mut relight_block_ev: EventWriter<RelightBlock>,
     mut remesh_chunk_ev: EventWriter<RemeshChunks>,
     mut recol_chunk_ev: EventWriter<RecollisionChunk>,
     chunk_query: Query<(Entity, &ChunkComponent)>,
+    world_tick: Res<WorldTick>,
+    world_info_res: Res<WorldInfo>,
+    mut inventory_res: ResMut<PlayerInventory>,
+    mut drop_item_ev: EventWriter<DropItem>,
+    mut spawn_falling_block_ev: EventWriter<SpawnFallingBlock>,
+    registry: Res<BlockRegistry>,
 ) {
-    for ev in try_place_block_ev.read() {
+    let survival = world_info_res.game_mode == GameMode::Survival;
+
+    // The blocks that start falling are removed through this queue too,
+    // so the blocks they were holding up fall as well, even in other chunks
+    let mut queue: VecDeque<TryPlaceBlock> = try_place_block_ev.read().cloned().collect();
+    while let Some(ev) = queue.pop_front() {
         let Some(chunk) = chunks_res.get(&ev.chunk_position) else {
             continue;
         };
         let index = get_index_from_position(ev.position);
+        if ev
+            .replaces
+            .is_some_and(|expected| chunk.layers[ev.layer as usize][index] != expected)
+        {
+            continue;
+        }
 
         if ev.block_type > BlockType::AIR {
-            // We are placing a block
-            if chunk.layers[ev.layer as usize][index] > BlockType::AIR {
+            // We are placing a block, which can also go into a liquid and replace it.
+            // The world itself can replace any block, like grass spreading onto dirt.
+            let existing = chunk.layers[ev.layer as usize][index];
+            if ev.by_player && existing > BlockType::AIR && registry.get(existing).liquid.is_none()
+            {
                 continue;
             };
+            // In survival the block has to come from the selected hotbar slot
+            if survival && ev.by_player && inventory_res.selected_block() != Some(ev.block_type) {
+                continue;
+            }
 
             let global_position = (ev.chunk_position * CHUNK_WIDTH as i32) + ev.position.as_ivec2();
             let Some(block_neighbors) =
@@ -233,17 +359,49 @@ fn try_to_place_block_event(
                 continue;
             };
 
-            if block_neighbors.iter().any(|&t| t > BlockType::AIR)
+            if !ev.by_player
+                || block_neighbors.iter().any(|&t| t > BlockType::AIR)
                 || wall_neighbors.iter().any(|&t| t > BlockType::AIR)
             {
                 let Some(chunk) = chunks_res.get_mut(&ev.chunk_position) else {
                     continue;
                 };
+                // A lamp being lit keeps its signal, but any other block starts without power
+                if registry.get(existing).circuit != registry.get(ev.block_type).circuit {
+                    chunk.signal[ev.layer as usize][index] = 0;
+                }
                 chunk.layers[ev.layer as usize][index] = ev.block_type;
+                chunk.dirty = true;
+                chunk.last_modified = world_tick.0;
+                if ev.layer == PlaceMode::BLOCK {
+                    chunk.liquid[index] = match &registry.get(ev.block_type).liquid {
+                        Some(liquid) => LiquidCell::full(liquid),
+                        None => LiquidCell::default(),
+                    };
+                    // What was stored in the replaced block falls out of it
+                    if let Some(entity) = chunk.block_entities.remove(&index) {
+                        let drop_position = (global_position.as_vec2() + 0.5) * TILE_SIZE as f32;
+                        for stack in entity.drops() {
+                            drop_item_ev.send(DropItem {
+                                position: drop_position,
+                                stack,
+                            });
+                        }
+                    }
+                    if let Some(kind) = registry.get(ev.block_type).block_entity {
+                        chunk.block_entities.insert(index, kind.create());
+                    }
+                }
+
+                if survival && ev.by_player {
+                    let slot = inventory_res.selected_index();
+                    inventory_res.take(slot, 1);
+                }
             }
         } else {
             // We are destroying a block
-            if chunk.layers[ev.layer as usize][index] <= BlockType::AIR {
+            let broken_block = chunk.layers[ev.layer as usize][index];
+            if broken_block <= BlockType::AIR {
                 continue;
             };
 
@@ -252,16 +410,87 @@ fn try_to_place_block_event(
             };
 
             chunk.layers[ev.layer as usize][index] = BlockType::AIR;
+            chunk.signal[ev.layer as usize][index] = 0;
+            chunk.dirty = true;
+            chunk.last_modified = world_tick.0;
+
+            let global_position = get_global_position(ev.chunk_position, ev.position);
+            let drop_position = (global_position.as_vec2() + 0.5) * TILE_SIZE as f32;
+            // What was stored in the block falls out of it, even in creative
+            if ev.layer == PlaceMode::BLOCK {
+                chunk.liquid[index] = LiquidCell::default();
+                if let Some(entity) = chunk.block_entities.remove(&index) {
+                    for stack in entity.drops() {
+                        drop_item_ev.send(DropItem {
+                            position: drop_position,
+                            stack,
+                        });
+                    }
+                }
+            }
+            // Liquids can't be picked up as items
+            if survival && ev.by_player && registry.get(broken_block).liquid.is_none() {
+                if let Some(dropped) = registry.dropped_block(broken_block) {
+                    drop_item_ev.send(DropItem {
+                        position: drop_position,
+                        stack: ItemStack::new(Item::Block(dropped), 1),
+                    });
+                }
+            }
         }
 
-        calc_light_ev.send(CalcLightChunks);
-        remesh_chunk_ev.send(RemeshChunks);
+        let global_position = get_global_position(ev.chunk_position, ev.position);
+        relight_block_ev.send(RelightBlock {
+            position: global_position,
+        });
+        remesh_chunk_ev.send(RemeshChunks {
+            positions: get_chunks_touching_block(global_position),
+        });
         for (entity, chunk_compo) in chunk_query.iter() {
             if chunk_compo.position == ev.chunk_position {
                 recol_chunk_ev.send(RecollisionChunk { entity });
                 break;
             }
         }
+
+        // A block affected by gravity falls when it's placed over nothing,
+        // or when the block under it is removed
+        if ev.layer == PlaceMode::BLOCK {
+            schedule_neighbor_ticks(&mut chunks_res, global_position, &registry, world_tick.0);
+            wake_liquids(&mut chunks_res, global_position, &registry);
+
+            for position in [global_position, global_position + IVec2::Y] {
+                let Some(block) = unsupported_block(&chunks_res, position, &registry) else {
+                    continue;
+                };
+                spawn_falling_block_ev.send(SpawnFallingBlock { position, block });
+                let chunk_position = get_chunk_position(position);
+                queue.push_back(TryPlaceBlock {
+                    position: get_relative_position(position, chunk_position),
+                    chunk_position,
+                    layer: PlaceMode::BLOCK,
+                    block_type: BlockType::AIR,
+                    by_player: false,
+                    replaces: Some(block),
+                });
+            }
+        }
+    }
+}
+
+// Remembers when the loaded neighbors of a chunk were last changed, right before it's saved,
+// so the light is only calculated again when it's loaded if one of them changes in the meantime
+fn record_neighbors_modified(chunks: &mut HashMap<IVec2, Chunk>, position: IVec2) {
+    let Some(mut neighbors_modified) = chunks.get(&position).map(|c| c.neighbors_modified) else {
+        return;
+    };
+    for (i, offset) in NEIGHBOR_CHUNKS.into_iter().enumerate() {
+        if let Some(neighbor) = chunks.get(&(position + offset)) {
+            neighbors_modified[i] = neighbor.last_modified;
+        }
+    }
+    if let Some(chunk) = chunks.get_mut(&position) {
+        chunk.neighbors_modified = neighbors_modified;
     }
 }
 
@@ -269,29 +498,31 @@ fn save_all_chunks(
     player_q: Query<(&Transform, &Player)>,
     mut save_chunks_ev: EventReader<SaveAllChunks>,
     mut finished_saving_ev: EventWriter<FinishedSavingChunks>,
-    chunks_res: Res<Chunks>,
+    mut chunks_res: ResMut<Chunks>,
     world_info_res: Res<WorldInfo>,
+    world_tick: Res<WorldTick>,
+    world_time: Res<WorldTime>,
+    registry: Res<BlockRegistry>,
+    storage: Res<RegionStorage>,
+    pending_blocks: Res<PendingBlocks>,
+    mut inventory_res: ResMut<PlayerInventory>,
+    mut held: ResMut<HeldStack>,
+    mut drop_item_ev: EventWriter<DropItem>,
 ) {
     for _ in save_chunks_ev.read() {
         info!("Saving all chunks...");
 
-        for (pos, chunk) in chunks_res.iter() {
-            let mut layers: [serde_big_array::Array<BlockType, CHUNK_AREA>; 2] =
-                [serde_big_array::Array([BlockType::AIR; CHUNK_AREA]); 2];
-
-            layers[0] = serde_big_array::Array(chunk.layers[0].clone());
-            layers[1] = serde_big_array::Array(chunk.layers[1].clone());
-
-            match bincode::serialize::<[serde_big_array::Array<BlockType, CHUNK_AREA>; 2]>(&layers)
-            {
-                Ok(s) => {
-                    let a = pos;
-                    let world_name = world_info_res.name.clone();
-                    match std::fs::write(format!("worlds/{}/chunks/{}.bin", world_name, a), &s) {
-                        Err(e) => error!("Error saving chunk at {}: {}", a, e),
-                        _ => {}
-                    }
-                }
+        let chunks = chunks_res.bypass_change_detection();
+        let positions: Vec<IVec2> = chunks.keys().copied().collect();
+        for pos in positions.iter() {
+            record_neighbors_modified(chunks, *pos);
+        }
+        for (pos, chunk) in chunks.iter() {
+            match serialize_chunk(chunk, &registry) {
+                Ok(s) => match storage.write_chunk(*pos, &s) {
+                    Err(e) => error!("Error saving chunk at {}: {}", pos, e),
+                    _ => {}
+                },
                 Err(e) => error!("Could not serialize chunk at {}: {}", pos, e),
             }
         }
@@ -300,11 +531,30 @@ fn save_all_chunks(
             let mut new_info = world_info_res.clone();
             new_info.player_position = Some(player_transform.translation.xy() / TILE_SIZE as f32);
             new_info.is_flying = player.noclip;
-
-            if let Ok(str) = toml::to_string(&new_info) {
-                let _ = std::fs::write(format!("worlds/{}/world.toml", new_info.name), str);
+            new_info.tick = world_tick.0;
+            new_info.time = world_time.clone();
+
+            match toml::to_string(&new_info) {
+                Ok(str) => {
+                    if let Err(e) =
+                        std::fs::write(format!("worlds/{}/world.toml", new_info.name), str)
+                    {
+                        error!("Could not write the world information into a file: {}", e);
+                    }
+                }
+                Err(e) => error!("Could not serialize world information into a string: {}", e),
             }
         }
+        pending_blocks.save(&world_info_res.name, &registry);
+        return_held_stack(
+            &mut held,
+            &mut inventory_res,
+            player_q.get_single().ok().map(|(transform, _)| transform),
+            &mut drop_item_ev,
+        );
+        if world_info_res.game_mode == GameMode::Survival {
+            inventory_res.save(&world_info_res.name, &registry);
+        }
 
         finished_saving_ev.send(FinishedSavingChunks);
     }
@@ -312,10 +562,13 @@ fn save_all_chunks(
 
 fn on_game_settings_changed(
     game_settings_res: Res<GameSettings>,
+    chunks_res: Res<Chunks>,
     mut remesh_chunk_ev: EventWriter<RemeshChunks>,
 ) {
     if game_settings_res.is_changed() {
-        remesh_chunk_ev.send(RemeshChunks);
+        remesh_chunk_ev.send(RemeshChunks {
+            positions: chunks_res.keys().copied().collect(),
+        });
     }
 }
 
@@ -325,44 +578,44 @@ fn unload_and_save_chunks(
     mut unload_chunks_ev: EventReader<UnloadChunks>,
     mut load_chunks_ev: EventWriter<LoadChunks>,
     chunk_query: Query<(Entity, &ChunkComponent, &ViewVisibility)>,
-    world_info_res: Res<WorldInfo>,
+    registry: Res<BlockRegistry>,
+    storage: Res<RegionStorage>,
 ) {
     for ev in unload_chunks_ev.read() {
         // ===================================
         // Despawn and save chunks out of view
-        for (chunk_entity, chunk_compo, chunk_visibility) in chunk_query.iter() {
-            // If a chunk is out of view, then save its blocks into a file and despawn the chunk entity
-            // Or if force bool is true (unload and load all chunks no matter if it's already there)
-            if !chunk_visibility.get() || ev.force {
-                let mut layers: [serde_big_array::Array<BlockType, CHUNK_AREA>; 2] =
-                    [serde_big_array::Array([BlockType::AIR; CHUNK_AREA]); 2];
-                if let Some(chunk) = chunks.get(&chunk_compo.position) {
-                    layers[0] = serde_big_array::Array(chunk.layers[0].clone());
-                    layers[1] = serde_big_array::Array(chunk.layers[1].clone());
-
-                    let a = chunk_compo.position;
-
-                    match bincode::serialize(&layers) {
-                        Ok(s) => {
-                            let world_name = world_info_res.name.clone();
-                            IoTaskPool::get()
-                                .spawn(async move {
-                                    match std::fs::write(
-                                        format!("worlds/{}/chunks/{}.bin", world_name, a),
-                                        &s,
-                                    ) {
-                                        Err(e) => error!("Error saving chunk at {}: {}", a, e),
-                                        _ => {}
-                                    }
-                                })
-                                .detach();
-                        }
-                        Err(e) => error!("Could not serialize chunk at {}: {}", a, e),
+        // If a chunk is out of view, then save its blocks into a file and despawn the chunk entity
+        // Or if force bool is true (unload and load all chunks no matter if it's already there)
+        let unloaded: Vec<(Entity, IVec2)> = chunk_query
+            .iter()
+            .filter(|(_, _, chunk_visibility)| !chunk_visibility.get() || ev.force)
+            .map(|(chunk_entity, chunk_compo, _)| (chunk_entity, chunk_compo.position))
+            .collect();
+        // The neighbors are recorded before any chunk is removed
+        for (_, position) in unloaded.iter() {
+            record_neighbors_modified(&mut chunks, *position);
+        }
+        for (chunk_entity, position) in unloaded {
+            if let Some(chunk) = chunks.get(&position) {
+                let a = position;
+
+                match serialize_chunk(chunk, &registry) {
+                    Ok(s) => {
+                        let storage = storage.clone();
+                        IoTaskPool::get()
+                            .spawn(async move {
+                                match storage.write_chunk(a, &s) {
+                                    Err(e) => error!("Error saving chunk at {}: {}", a, e),
+                                    _ => {}
+                                }
+                            })
+                            .detach();
                     }
-
-                    chunks.remove(&chunk_compo.position);
-                    commands.entity(chunk_entity).despawn_recursive();
+                    Err(e) => error!("Could not serialize chunk at {}: {}", a, e),
                 }
+
+                chunks.remove(&position);
+                commands.entity(chunk_entity).despawn_recursive();
             }
         }
 
@@ -376,7 +629,9 @@ fn load_chunks(
     chunks_res: ResMut<Chunks>,
     window_query: Query<&Window, With<PrimaryWindow>>,
     camera_query: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
-    world_info_res: ResMut<WorldInfo>,
+    registry: Res<BlockRegistry>,
+    storage: Res<RegionStorage>,
+    generator: Res<CurrentWorldGenerator>,
 ) {
     // ==========================
     // Load chunks from disk
@@ -401,16 +656,12 @@ fn load_chunks(
         let c_top_left = get_chunk_position(b_top_left);
         let c_bottom_right = get_chunk_position(b_bottom_right);
 
-        let world_name = world_info_res.name.clone();
-        let world_preset = world_info_res.preset.clone();
-
         // Had to make it load some extra chunks offscreen
         // to make it truly seamless
         for y in (c_bottom_right.y - 1)..(c_top_left.y + 2) {
             for x in (c_top_left.x - 1)..(c_bottom_right.x + 2) {
                 let chunk_pos = IVec2::new(x, y);
                 let already_has: bool = chunks_res.contains_key(&chunk_pos);
-                let stre = format!("worlds/{}/chunks/{}.bin", world_name, chunk_pos);
 
                 let thread_pool = AsyncComputeTaskPool::get();
                 if already_has {
@@ -422,7 +673,12 @@ fn load_chunks(
                     .insert(Name::new("Chunk Loading Async Task"));
                 commands.entity(task_entity).insert(FromWorld);
 
-                let task = thread_pool.spawn(chunk_generator_task(stre, chunk_pos, world_preset));
+                let task = thread_pool.spawn(chunk_generator_task(
+                    storage.clone(),
+                    chunk_pos,
+                    generator.clone(),
+                    registry.clone(),
+                ));
                 commands
                     .entity(task_entity)
                     .insert(ComputeChunkLoading(task));
@@ -434,122 +690,61 @@ fn load_chunks(
 // This returned Vec2 is for defining the position the player
 // should spawn in when creating a new world
 async fn chunk_generator_task(
-    stre: String,
+    storage: RegionStorage,
     chunk_pos: IVec2,
-    world_preset: WorldGenPreset,
+    generator: CurrentWorldGenerator,
+    registry: BlockRegistry,
 ) -> Result<SpawnChunk, String> {
-    let ChunkGenerationResult { blocks, walls } = match std::fs::read(stre.clone()) {
-        Ok(bytes) => {
-            match bincode::deserialize::<[serde_big_array::Array<BlockType, CHUNK_AREA>; 2]>(&bytes)
-            {
-                Ok(layers) => ChunkGenerationResult {
-                    walls: layers[0].0,
-                    blocks: layers[1].0,
-                },
-                Err(e) => {
-                    error!("Error deserializing chunk at {}: {}", chunk_pos, e);
-                    error!("File that tried to deserialize: {}", stre);
-                    return Err(format!("{}", e));
-                }
-            }
-        }
-        Err(e) => match e.kind() {
-            // If a chunk file is not found at a certain location,
-            // then it will try to generate a new one from scratch.
-            // This is where world generation goes in!
-            ErrorKind::NotFound => generate_chunk(chunk_pos, world_preset).await,
-            _ => {
-                error!("Error when trying to load chunk at {}: {}", chunk_pos, e);
-                return Err(format!("{}", e));
+    let mut structures = Vec::new();
+    let chunk = match storage.read_chunk(chunk_pos) {
+        // Chunks saved with older versions of the format are migrated here
+        Ok(Some(bytes)) => match deserialize_chunk(&bytes, &registry) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                error!("Error deserializing chunk at {}: {}", chunk_pos, e);
+                return Err(e);
             }
         },
+        // If a chunk was never saved into the region files,
+        // then it will try to generate a new one from scratch.
+        // This is where world generation goes in!
+        Ok(None) => {
+            let ChunkGenerationResult {
+                blocks,
+                walls,
+                biomes,
+                structures: generated_structures,
+            } = generator.generate(chunk_pos);
+            structures = generated_structures;
+            Chunk {
+                layers: [walls, blocks],
+                light: [0; CHUNK_AREA],
+                block_light: [[0; 3]; CHUNK_AREA],
+                dirty: true,
+                last_modified: 0,
+                neighbors_modified: [0; 4],
+                inhabited_time: 0,
+                biomes,
+                block_entities: HashMap::new(),
+                liquid: [LiquidCell::default(); CHUNK_AREA],
+                scheduled_ticks: Vec::new(),
+                signal: [[0; CHUNK_AREA]; 2],
+                active_liquids: HashSet::new(),
+            }
+        }
+        Err(e) => {
+            error!("Error when trying to load chunk at {}: {}", chunk_pos, e);
+            return Err(format!("{}", e));
+        }
     };
 
     Ok(SpawnChunk {
         position: chunk_pos,
-        chunk: Chunk {
-            layers: [walls, blocks],
-            light: [0; CHUNK_AREA],
-        },
+        chunk,
+        structures,
     })
 }
 
-struct ChunkGenerationResult {
-    blocks: [BlockType; CHUNK_AREA],
-    walls: [BlockType; CHUNK_AREA],
-}
-
-// World generation
-async fn generate_chunk(chunk_pos: IVec2, world_preset: WorldGenPreset) -> ChunkGenerationResult {
-    let mut blocks: [BlockType; CHUNK_AREA] = [BlockType::AIR; CHUNK_AREA];
-    let mut walls: [BlockType; CHUNK_AREA] = [BlockType::AIR; CHUNK_AREA];
-    match world_preset {
-        WorldGenPreset::DEFAULT => {
-            for x in 0..CHUNK_WIDTH {
-                for y in (0..CHUNK_WIDTH).rev() {
-                    let global_pos = IVec2::new(
-                        (chunk_pos.x * CHUNK_WIDTH as i32) + x as i32,
-                        (chunk_pos.y * CHUNK_WIDTH as i32) + y as i32,
-                    );
-                    //let s = (f32::sin(global_pos.x as f32 / CHUNK_WIDTH as f32) * CHUNK_WIDTH as f32).floor() as i32;
-                    let noise = Fbm::<Perlin>::new(0);
-                    let s = (noise.get([
-                        global_pos.x as f64 / CHUNK_WIDTH as f64,
-                        global_pos.x as f64 / CHUNK_WIDTH as f64,
-                    ]) * CHUNK_WIDTH as f64)
-                        .floor() as i32;
-
-                    if global_pos.y == s {
-                        blocks[get_index_from_position(UVec2::new(x as u32, y as u32))] =
-                            BlockType::GRASS;
-                        walls[get_index_from_position(UVec2::new(x as u32, y as u32))] =
-                            BlockType::GRASS;
-                    } else if global_pos.y < s {
-                        blocks[get_index_from_position(UVec2::new(x as u32, y as u32))] =
-                            BlockType::DIRT;
-                        walls[get_index_from_position(UVec2::new(x as u32, y as u32))] =
-                            BlockType::DIRT;
-                    }
-                }
-            }
-        }
-        WorldGenPreset::FLAT => {
-            if chunk_pos.y == 0 {
-                for y in 0..CHUNK_WIDTH {
-                    for x in 0..CHUNK_WIDTH {
-                        let i = get_index_from_position(UVec2::new(x as u32, y as u32));
-                        if y == CHUNK_WIDTH / 2 {
-                            blocks[i] = BlockType::GRASS;
-                            walls[i] = BlockType::GRASS;
-                        } else if y < CHUNK_WIDTH / 2 {
-                            blocks[i] = BlockType::DIRT;
-                            walls[i] = BlockType::DIRT;
-                        }
-                    }
-                }
-            } else if chunk_pos.y < 0 && chunk_pos.y >= -2 {
-                blocks = [BlockType::DIRT; CHUNK_AREA];
-                walls = [BlockType::DIRT; CHUNK_AREA];
-            } else if chunk_pos.y < -2 {
-                blocks = [BlockType::STONE; CHUNK_AREA];
-                walls = [BlockType::STONE; CHUNK_AREA];
-            }
-        }
-        WorldGenPreset::EMPTY => {
-            if chunk_pos == IVec2::ZERO {
-                for x in 0..(CHUNK_WIDTH / 2) {
-                    blocks[x] = BlockType::STONE;
-                }
-            } else if chunk_pos == IVec2::new(-1, 0) {
-                for x in 0..(CHUNK_WIDTH / 2) {
-                    blocks[(CHUNK_WIDTH / 2) + x] = BlockType::STONE;
-                }
-            }
-        }
-    };
-    ChunkGenerationResult { blocks, walls }
-}
-
 fn process_chunk_loading_tasks(
     mut commands: Commands,
     tasks_query: Query<(Entity, &ComputeChunkLoading)>,
@@ -561,6 +756,53 @@ fn process_chunk_loading_tasks(
     }
 }
 
+fn setup_region_storage(mut commands: Commands, world_info_res: Res<WorldInfo>) {
+    let storage = RegionStorage::for_world(&world_info_res.name);
+    migrate_chunk_files(&world_info_res.name, &storage);
+    commands.insert_resource(storage);
+}
+
+fn setup_world_generator(
+    mut commands: Commands,
+    world_info_res: Res<WorldInfo>,
+    world_generators: Res<WorldGenerators>,
+    registry: Res<BlockRegistry>,
+) {
+    let settings = &world_info_res.generator_settings;
+    let generator = match world_generators.create(&world_info_res.generator, &registry, settings) {
+        Some(generator) => generator,
+        None => {
+            error!(
+                "Unknown world generator '{}', using the default one instead",
+                world_info_res.generator
+            );
+            world_generators
+                .create("default", &registry, settings)
+                .expect("the default world generator is always registered")
+        }
+    };
+
+    commands.insert_resource(CurrentWorldGenerator {
+        generator: generator.into(),
+        seed: world_info_res.seed,
+    });
+}
+
+fn setup_pending_blocks(
+    mut commands: Commands,
+    world_info_res: Res<WorldInfo>,
+    registry: Res<BlockRegistry>,
+) {
+    commands.insert_resource(PendingBlocks::load(&world_info_res.name, &registry));
+}
+
+// Only saved with the chunks, so the systems watching for changed chunks don't need to run
+fn update_inhabited_time(mut chunks_res: ResMut<Chunks>) {
+    for chunk in chunks_res.bypass_change_detection().values_mut() {
+        chunk.inhabited_time += 1;
+    }
+}
+
 fn clear_chunks(mut chunks_res: ResMut<Chunks>) {
     chunks_res.clear();
 }
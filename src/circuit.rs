@@ -0,0 +1,536 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use serde::Deserialize;
+
+use crate::{
+    block::{BlockRegistry, BlockType},
+    chunk::{Chunk, PlaceMode, RemeshChunks, CHUNK_AREA},
+    chunk_manager::{Chunks, TryPlaceBlock},
+    utils::{
+        get_chunk_position, get_global_position, get_index_from_position, get_position_from_index,
+        get_relative_position,
+    },
+    world::{WorldTick, WorldTickSchedule},
+    GamePauseState, GameState,
+};
+
+// The signal of a powered source, which goes down by one for each wire it goes through
+pub const MAX_SIGNAL: u8 = 15;
+
+// How many world ticks pass between each logic tick
+const LOGIC_TICK_INTERVAL: u64 = 4;
+
+// How many logic ticks a button stays pressed for
+const BUTTON_PRESS_TICKS: u8 = 10;
+
+// The part a block plays in a circuit, set with "circuit" in blocks.toml
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitKind {
+    // Carries the signal to the circuit blocks around it, and can also be placed in the WALL layer
+    Wire,
+    // Powers the blocks around it while it's on, and is turned on and off by right clicking it
+    Switch,
+    // Powers the blocks around it for a moment after it's right clicked
+    Button,
+    // Turns into its lit version while it's powered
+    Lamp,
+    // Gates read the blocks at their left and right, and power the blocks above and under them
+    AndGate,
+    OrGate,
+    NotGate,
+}
+
+// Uses the switch or button at the position
+#[derive(Event)]
+pub struct InteractCircuit {
+    pub position: IVec2,
+    pub layer: PlaceMode,
+}
+
+pub struct CircuitPlugin;
+
+impl Plugin for CircuitPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<InteractCircuit>();
+
+        app.add_systems(WorldTickSchedule, logic_tick);
+        app.add_systems(
+            Update,
+            interact_circuits
+                .run_if(in_state(GameState::Game))
+                .run_if(in_state(GamePauseState::Running)),
+        );
+    }
+}
+
+// A cell of a layer, at a global block position
+type Cell = (IVec2, PlaceMode);
+
+// The cells a circuit block is connected to:
+// the four around it in its layer, and the one in the other layer at the same position
+fn connections((position, layer): Cell) -> [Cell; 5] {
+    let other = match layer {
+        PlaceMode::WALL => PlaceMode::BLOCK,
+        PlaceMode::BLOCK => PlaceMode::WALL,
+    };
+    return [
+        (position + IVec2::NEG_Y, layer),
+        (position + IVec2::X, layer),
+        (position + IVec2::Y, layer),
+        (position + IVec2::NEG_X, layer),
+        (position, other),
+    ];
+}
+
+fn get_circuit(
+    chunks: &HashMap<IVec2, Chunk>,
+    registry: &BlockRegistry,
+    (position, layer): Cell,
+) -> Option<(CircuitKind, u8)> {
+    let chunk_position = get_chunk_position(position);
+    let index = get_index_from_position(get_relative_position(position, chunk_position));
+    let chunk = chunks.get(&chunk_position)?;
+    let kind = registry.get(chunk.layers[layer as usize][index]).circuit?;
+    return Some((kind, chunk.signal[layer as usize][index]));
+}
+
+// Whether a switch or a button can be used at the position, looking at the BLOCK layer first
+pub fn interactive_layer(
+    chunks: &HashMap<IVec2, Chunk>,
+    registry: &BlockRegistry,
+    position: IVec2,
+) -> Option<PlaceMode> {
+    return [PlaceMode::BLOCK, PlaceMode::WALL]
+        .into_iter()
+        .find(|layer| {
+            matches!(
+                get_circuit(chunks, registry, (position, *layer)),
+                Some((CircuitKind::Switch | CircuitKind::Button, _))
+            )
+        });
+}
+
+fn interact_circuits(
+    mut interact_circuit_ev: EventReader<InteractCircuit>,
+    mut remesh_chunk_ev: EventWriter<RemeshChunks>,
+    mut chunks_res: ResMut<Chunks>,
+    registry: Res<BlockRegistry>,
+) {
+    for ev in interact_circuit_ev.read() {
+        let chunk_position = get_chunk_position(ev.position);
+        let index = get_index_from_position(get_relative_position(ev.position, chunk_position));
+        let Some(chunk) = chunks_res.get_mut(&chunk_position) else {
+            continue;
+        };
+
+        let signal = &mut chunk.signal[ev.layer as usize][index];
+        match registry.get(chunk.layers[ev.layer as usize][index]).circuit {
+            Some(CircuitKind::Switch) => {
+                *signal = if *signal > 0 { 0 } else { MAX_SIGNAL };
+            }
+            Some(CircuitKind::Button) => *signal = BUTTON_PRESS_TICKS,
+            _ => continue,
+        }
+        remesh_chunk_ev.send(RemeshChunks {
+            positions: HashSet::from([chunk_position]),
+        });
+    }
+}
+
+fn logic_tick(
+    mut chunks_res: ResMut<Chunks>,
+    mut try_place_block_ev: EventWriter<TryPlaceBlock>,
+    mut remesh_chunk_ev: EventWriter<RemeshChunks>,
+    world_tick: Res<WorldTick>,
+    registry: Res<BlockRegistry>,
+) {
+    if world_tick.0 % LOGIC_TICK_INTERVAL != 0 {
+        return;
+    }
+
+    let update = update_circuits(chunks_res.bypass_change_detection(), &registry);
+    if !update.changed_chunks.is_empty() {
+        chunks_res.set_changed();
+        remesh_chunk_ev.send(RemeshChunks {
+            positions: update.changed_chunks,
+        });
+    }
+
+    // The lamps are swapped with their lit version, which is what makes them emit light
+    for ((position, layer), block, block_type) in update.lamps {
+        let chunk_position = get_chunk_position(position);
+        try_place_block_ev.send(TryPlaceBlock {
+            position: get_relative_position(position, chunk_position),
+            chunk_position,
+            layer,
+            block_type,
+            by_player: false,
+            replaces: Some(block),
+        });
+    }
+}
+
+// What a logic tick changed, other than the signals, which are written into the chunks
+#[derive(Default)]
+struct CircuitUpdate {
+    // The chunks where a signal changed
+    changed_chunks: HashSet<IVec2>,
+    // The lamps that have to be swapped, with the block they are and the one they become
+    lamps: Vec<(Cell, BlockType, BlockType)>,
+}
+
+// Every circuit is updated at once from the state of the previous logic tick,
+// so the result doesn't depend on the order the blocks are visited in.
+// The sources and gates decide their output first, then the signal spreads through the wires
+// with a breadth first search, so each wire gets the signal of its closest source.
+fn update_circuits(chunks: &mut HashMap<IVec2, Chunk>, registry: &BlockRegistry) -> CircuitUpdate {
+    let mut update = CircuitUpdate::default();
+    let mut circuits: HashMap<Cell, (CircuitKind, u8)> = HashMap::new();
+    let mut lamps = Vec::new();
+    for (chunk_position, chunk) in chunks.iter() {
+        for layer in [PlaceMode::WALL, PlaceMode::BLOCK] {
+            for i in 0..CHUNK_AREA {
+                let block = chunk.layers[layer as usize][i];
+                if let Some(kind) = registry.get(block).circuit {
+                    let position = get_global_position(*chunk_position, get_position_from_index(i));
+                    circuits.insert((position, layer), (kind, chunk.signal[layer as usize][i]));
+                    if kind == CircuitKind::Lamp {
+                        lamps.push(((position, layer), block));
+                    }
+                }
+            }
+        }
+    }
+    if circuits.is_empty() {
+        return update;
+    }
+
+    // Lamps only show the signal, so they don't power anything
+    let powered = |cell: Cell| {
+        circuits
+            .get(&cell)
+            .is_some_and(|(kind, signal)| *kind != CircuitKind::Lamp && *signal > 0)
+    };
+
+    let mut next: HashMap<Cell, u8> = HashMap::new();
+    let mut outputs: Vec<Cell> = Vec::new();
+    for (&cell, &(kind, signal)) in circuits.iter() {
+        let (position, layer) = cell;
+        let left = powered((position + IVec2::NEG_X, layer));
+        let right = powered((position + IVec2::X, layer));
+        let gate_output = match kind {
+            CircuitKind::Switch | CircuitKind::Button => {
+                let state = match kind {
+                    CircuitKind::Button => signal.saturating_sub(1),
+                    _ => signal,
+                };
+                next.insert(cell, state);
+                if signal > 0 {
+                    outputs.extend(connections(cell));
+                }
+                continue;
+            }
+            CircuitKind::AndGate => left && right,
+            CircuitKind::OrGate => left || right,
+            CircuitKind::NotGate => !left && !right,
+            CircuitKind::Wire | CircuitKind::Lamp => continue,
+        };
+
+        next.insert(cell, if gate_output { MAX_SIGNAL } else { 0 });
+        if gate_output {
+            outputs.push((position + IVec2::Y, layer));
+            outputs.push((position + IVec2::NEG_Y, layer));
+        }
+    }
+
+    let mut lit_lamps: HashSet<Cell> = HashSet::new();
+    let mut queue: VecDeque<(Cell, u8)> = VecDeque::new();
+    for cell in outputs {
+        match circuits.get(&cell) {
+            Some((CircuitKind::Wire, _)) => queue.push_back((cell, MAX_SIGNAL)),
+            Some((CircuitKind::Lamp, _)) => {
+                lit_lamps.insert(cell);
+            }
+            _ => {}
+        }
+    }
+    while let Some((cell, signal)) = queue.pop_front() {
+        if next.get(&cell).is_some_and(|existing| *existing >= signal) {
+            continue;
+        }
+        next.insert(cell, signal);
+        for connected in connections(cell) {
+            match circuits.get(&connected) {
+                Some((CircuitKind::Wire, _)) if signal > 1 => {
+                    queue.push_back((connected, signal - 1))
+                }
+                Some((CircuitKind::Lamp, _)) => {
+                    lit_lamps.insert(connected);
+                }
+                _ => {}
+            }
+        }
+    }
+    for cell in lit_lamps.iter() {
+        next.insert(*cell, MAX_SIGNAL);
+    }
+
+    for (&cell, &(_, signal)) in circuits.iter() {
+        let new_signal = next.get(&cell).copied().unwrap_or(0);
+        if new_signal == signal {
+            continue;
+        }
+
+        let (position, layer) = cell;
+        let chunk_position = get_chunk_position(position);
+        let index = get_index_from_position(get_relative_position(position, chunk_position));
+        if let Some(chunk) = chunks.get_mut(&chunk_position) {
+            chunk.signal[layer as usize][index] = new_signal;
+        }
+        update.changed_chunks.insert(chunk_position);
+    }
+
+    let (Some(lamp), Some(lit_lamp)) = (registry.id("lamp"), registry.id("lit_lamp")) else {
+        return update;
+    };
+    for ((position, layer), block) in lamps {
+        let block_type = match lit_lamps.contains(&(position, layer)) {
+            true => lit_lamp,
+            false => lamp,
+        };
+        if block_type != block {
+            update.lamps.push(((position, layer), block, block_type));
+        }
+    }
+    return update;
+}
+
+// Circuit blocks without power are drawn darker, except the lamps, which have a lit version instead
+pub fn is_unpowered(chunk: &Chunk, layer: usize, index: usize, registry: &BlockRegistry) -> bool {
+    return match registry.get(chunk.layers[layer][index]).circuit {
+        Some(CircuitKind::Lamp) | None => false,
+        Some(_) => chunk.signal[layer][index] == 0,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{biome::Biome, chunk::CHUNK_WIDTH, liquid::LiquidCell};
+
+    fn registry() -> BlockRegistry {
+        return BlockRegistry::from_toml(include_str!("../assets/blocks.toml")).unwrap();
+    }
+
+    // Empty chunks from x = -1 to 1, on the row of chunks at y = 0
+    fn chunks() -> HashMap<IVec2, Chunk> {
+        let mut chunks = HashMap::new();
+        for x in -1..=1 {
+            chunks.insert(
+                IVec2::new(x, 0),
+                Chunk {
+                    layers: [[BlockType::AIR; CHUNK_AREA]; 2],
+                    light: [0; CHUNK_AREA],
+                    block_light: [[0; 3]; CHUNK_AREA],
+                    dirty: false,
+                    last_modified: 0,
+                    neighbors_modified: [0; 4],
+                    inhabited_time: 0,
+                    biomes: [Biome::Plains; CHUNK_WIDTH],
+                    block_entities: HashMap::new(),
+                    liquid: [LiquidCell::default(); CHUNK_AREA],
+                    scheduled_ticks: Vec::new(),
+                    signal: [[0; CHUNK_AREA]; 2],
+                    active_liquids: HashSet::new(),
+                },
+            );
+        }
+        return chunks;
+    }
+
+    fn cell_index(position: IVec2) -> (IVec2, usize) {
+        let chunk_position = get_chunk_position(position);
+        let index = get_index_from_position(get_relative_position(position, chunk_position));
+        return (chunk_position, index);
+    }
+
+    fn place(
+        chunks: &mut HashMap<IVec2, Chunk>,
+        registry: &BlockRegistry,
+        (position, layer): Cell,
+        name: &str,
+        signal: u8,
+    ) {
+        let (chunk_position, index) = cell_index(position);
+        let chunk = chunks.get_mut(&chunk_position).unwrap();
+        chunk.layers[layer as usize][index] = registry.id(name).unwrap();
+        chunk.signal[layer as usize][index] = signal;
+    }
+
+    fn signal(chunks: &HashMap<IVec2, Chunk>, (position, layer): Cell) -> u8 {
+        let (chunk_position, index) = cell_index(position);
+        return chunks[&chunk_position].signal[layer as usize][index];
+    }
+
+    fn is_lit(
+        chunks: &HashMap<IVec2, Chunk>,
+        registry: &BlockRegistry,
+        (position, layer): Cell,
+    ) -> bool {
+        let (chunk_position, index) = cell_index(position);
+        return Some(chunks[&chunk_position].layers[layer as usize][index])
+            == registry.id("lit_lamp");
+    }
+
+    // Swaps the lamps right away, instead of through TryPlaceBlock like the game does
+    fn tick(chunks: &mut HashMap<IVec2, Chunk>, registry: &BlockRegistry) {
+        let update = update_circuits(chunks, registry);
+        for ((position, layer), _, block_type) in update.lamps {
+            let (chunk_position, index) = cell_index(position);
+            chunks.get_mut(&chunk_position).unwrap().layers[layer as usize][index] = block_type;
+        }
+    }
+
+    #[test]
+    fn wire_signal_decays_with_distance() {
+        let registry = registry();
+        let mut chunks = chunks();
+        place(
+            &mut chunks,
+            &registry,
+            (IVec2::new(-2, 3), PlaceMode::BLOCK),
+            "switch",
+            MAX_SIGNAL,
+        );
+        for x in -1..20 {
+            place(
+                &mut chunks,
+                &registry,
+                (IVec2::new(x, 3), PlaceMode::BLOCK),
+                "wire",
+                0,
+            );
+        }
+
+        tick(&mut chunks, &registry);
+
+        // The wires go through the border between the chunks at x = 0
+        for x in -1..20 {
+            let expected = (MAX_SIGNAL as i32 - (x + 1)).max(0) as u8;
+            assert_eq!(
+                signal(&chunks, (IVec2::new(x, 3), PlaceMode::BLOCK)),
+                expected,
+                "wire at x = {}",
+                x
+            );
+        }
+    }
+
+    #[test]
+    fn gates_follow_their_truth_tables() {
+        let registry = registry();
+        let gates = ["and_gate", "or_gate", "not_gate"];
+        let gate = (IVec2::new(5, 5), PlaceMode::BLOCK);
+        let lamp = (IVec2::new(5, 6), PlaceMode::BLOCK);
+
+        for name in gates {
+            for (left, right) in [(false, false), (true, false), (false, true), (true, true)] {
+                let mut chunks = chunks();
+                let input = |on: bool| if on { MAX_SIGNAL } else { 0 };
+                place(
+                    &mut chunks,
+                    &registry,
+                    (IVec2::new(4, 5), PlaceMode::BLOCK),
+                    "switch",
+                    input(left),
+                );
+                place(
+                    &mut chunks,
+                    &registry,
+                    (IVec2::new(6, 5), PlaceMode::BLOCK),
+                    "switch",
+                    input(right),
+                );
+                place(&mut chunks, &registry, gate, name, 0);
+                place(&mut chunks, &registry, lamp, "lamp", 0);
+
+                tick(&mut chunks, &registry);
+
+                let output = match name {
+                    "and_gate" => left && right,
+                    "or_gate" => left || right,
+                    _ => !left && !right,
+                };
+                assert_eq!(
+                    signal(&chunks, gate) > 0,
+                    output,
+                    "{} with {} and {}",
+                    name,
+                    left,
+                    right
+                );
+                assert_eq!(
+                    is_lit(&chunks, &registry, lamp),
+                    output,
+                    "lamp of {} with {} and {}",
+                    name,
+                    left,
+                    right
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn button_releases_after_the_press_ticks() {
+        let registry = registry();
+        let mut chunks = chunks();
+        let button = (IVec2::new(3, 3), PlaceMode::BLOCK);
+        let lamp = (IVec2::new(4, 3), PlaceMode::BLOCK);
+        place(&mut chunks, &registry, button, "button", BUTTON_PRESS_TICKS);
+        place(&mut chunks, &registry, lamp, "lamp", 0);
+
+        for _ in 0..BUTTON_PRESS_TICKS {
+            tick(&mut chunks, &registry);
+            assert!(is_lit(&chunks, &registry, lamp));
+        }
+        assert_eq!(signal(&chunks, button), 0);
+
+        tick(&mut chunks, &registry);
+        assert!(!is_lit(&chunks, &registry, lamp));
+    }
+
+    #[test]
+    fn wall_wire_lights_a_lamp_in_front_of_it() {
+        let registry = registry();
+        let mut chunks = chunks();
+        let lamp = (IVec2::new(3, 8), PlaceMode::BLOCK);
+        place(
+            &mut chunks,
+            &registry,
+            (IVec2::new(0, 8), PlaceMode::WALL),
+            "switch",
+            MAX_SIGNAL,
+        );
+        for x in 1..=3 {
+            place(
+                &mut chunks,
+                &registry,
+                (IVec2::new(x, 8), PlaceMode::WALL),
+                "wire",
+                0,
+            );
+        }
+        place(&mut chunks, &registry, lamp, "lamp", 0);
+
+        tick(&mut chunks, &registry);
+
+        assert!(is_lit(&chunks, &registry, lamp));
+        assert_eq!(signal(&chunks, lamp), MAX_SIGNAL);
+    }
+}
@@ -0,0 +1,168 @@
+use bevy::prelude::*;
+use bevy_xpbd_2d::prelude::*;
+
+use crate::block::BlockRegistry;
+use crate::chunk::TILE_SIZE;
+use crate::item_container::{Item, ItemStack};
+use crate::player::{Player, PLAYER_SIZE};
+use crate::world::{FromWorld, PlayerInventory};
+use crate::{GameLayer, GamePauseState, GameState};
+
+const DROPPED_ITEM_SIZE: f32 = TILE_SIZE as f32 * 0.4;
+// Items can't be picked up right after being dropped, so they are seen popping out of the block
+const PICKUP_DELAY: f32 = 0.25;
+// Items that are never picked up disappear after this many seconds
+const DESPAWN_TIME: f32 = 300.0;
+
+// Spawns an item in the world, at a position in pixels
+#[derive(Event)]
+pub struct DropItem {
+    pub position: Vec2,
+    pub stack: ItemStack,
+}
+
+#[derive(Component)]
+pub struct DroppedItem {
+    pub stack: ItemStack,
+    age: f32,
+}
+
+#[derive(Resource)]
+struct DroppedItemTextures {
+    blocks: Handle<Image>,
+    blocks_layout: Handle<TextureAtlasLayout>,
+}
+
+pub struct DroppedItemPlugin;
+
+impl Plugin for DroppedItemPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<DropItem>();
+
+        app.add_systems(OnEnter(GameState::Game), setup);
+        // Items dropped while the game is paused, like a held stack that no longer fits, still spawn
+        app.add_systems(
+            Update,
+            (
+                spawn_dropped_items,
+                (pick_up_dropped_items, despawn_old_items)
+                    .chain()
+                    .run_if(in_state(GamePauseState::Running)),
+            )
+                .chain()
+                .run_if(in_state(GameState::Game)),
+        );
+    }
+}
+
+fn setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
+    registry: Res<BlockRegistry>,
+) {
+    let layout = TextureAtlasLayout::from_grid(
+        UVec2::splat(TILE_SIZE as u32),
+        registry.atlas_size as u32,
+        1,
+        None,
+        None,
+    );
+
+    commands.insert_resource(DroppedItemTextures {
+        blocks: asset_server.load("textures/blocks.png"),
+        blocks_layout: texture_atlas_layouts.add(layout),
+    });
+}
+
+fn spawn_dropped_items(
+    mut commands: Commands,
+    mut drop_item_ev: EventReader<DropItem>,
+    textures: Res<DroppedItemTextures>,
+    registry: Res<BlockRegistry>,
+) {
+    for ev in drop_item_ev.read() {
+        let mut item = commands.spawn((
+            Name::new("Dropped Item"),
+            SpriteBundle {
+                sprite: Sprite {
+                    custom_size: Some(Vec2::splat(DROPPED_ITEM_SIZE)),
+                    ..default()
+                },
+                transform: Transform::from_xyz(ev.position.x, ev.position.y, 1.5),
+                ..default()
+            },
+            RigidBody::Dynamic,
+            Collider::rectangle(DROPPED_ITEM_SIZE, DROPPED_ITEM_SIZE),
+            // Only the blocks stop the items, so they don't push the player or each other around
+            CollisionLayers::new(GameLayer::DroppedItems, [GameLayer::Blocks]),
+            LockedAxes::ROTATION_LOCKED,
+            Restitution::ZERO,
+            LinearVelocity(Vec2::new(
+                (rand::random::<f32>() - 0.5) * TILE_SIZE as f32 * 2.0,
+                TILE_SIZE as f32 * 3.0,
+            )),
+            DroppedItem {
+                stack: ev.stack,
+                age: 0.0,
+            },
+            FromWorld,
+        ));
+
+        // Tools don't have textures yet, so they are shown as a plain square
+        if let Item::Block(block) = ev.stack.item {
+            item.insert((
+                textures.blocks.clone(),
+                TextureAtlas {
+                    layout: textures.blocks_layout.clone(),
+                    index: registry.get(block).atlas_index,
+                },
+            ));
+        }
+    }
+}
+
+// The items are picked up as soon as they touch the player, as long as they fit in the inventory
+fn pick_up_dropped_items(
+    mut commands: Commands,
+    mut item_q: Query<(Entity, &Transform, &mut DroppedItem)>,
+    player_q: Query<&Transform, With<Player>>,
+    mut inventory_res: ResMut<PlayerInventory>,
+    time: Res<Time>,
+) {
+    let Ok(player_transform) = player_q.get_single() else {
+        return;
+    };
+
+    for (entity, transform, mut item) in item_q.iter_mut() {
+        item.age += time.delta_seconds();
+        if item.age < PICKUP_DELAY {
+            continue;
+        }
+
+        let distance = (transform.translation.xy() - player_transform.translation.xy()).abs();
+        if distance.max_element() > (PLAYER_SIZE + DROPPED_ITEM_SIZE) / 2.0 {
+            continue;
+        }
+
+        // A full inventory would otherwise be marked as changed on every frame the player touches the item
+        let rest = inventory_res.bypass_change_detection().insert(item.stack);
+        if rest == Some(item.stack) {
+            continue;
+        }
+        inventory_res.set_changed();
+
+        match rest {
+            None => commands.entity(entity).despawn_recursive(),
+            Some(rest) => item.stack = rest,
+        }
+    }
+}
+
+fn despawn_old_items(mut commands: Commands, item_q: Query<(Entity, &DroppedItem)>) {
+    for (entity, item) in item_q.iter() {
+        if item.age > DESPAWN_TIME {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
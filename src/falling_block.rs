@@ -0,0 +1,192 @@
+use bevy::{prelude::*, utils::HashMap};
+use bevy_xpbd_2d::prelude::*;
+
+use crate::block::{BlockRegistry, BlockType};
+use crate::chunk::{Chunk, PlaceMode, TILE_SIZE};
+use crate::chunk_manager::{Chunks, TryPlaceBlock};
+use crate::dropped_item::DropItem;
+use crate::item_container::{Item, ItemStack};
+use crate::utils::{get_block, get_block_position, get_chunk_position, get_relative_position};
+use crate::world::{FromWorld, GameMode, WorldInfo};
+use crate::{GamePauseState, GameState};
+
+const TERMINAL_VELOCITY: f32 = 530.0;
+
+// A block affected by gravity lost what was holding it up, at a global block position
+#[derive(Event)]
+pub struct SpawnFallingBlock {
+    pub position: IVec2,
+    pub block: BlockType,
+}
+
+#[derive(Component)]
+pub struct FallingBlock {
+    pub block: BlockType,
+    // The height of the center of the block on the last frame
+    previous_y: f32,
+}
+
+#[derive(Resource)]
+struct FallingBlockTextures {
+    blocks: Handle<Image>,
+    blocks_layout: Handle<TextureAtlasLayout>,
+}
+
+pub struct FallingBlockPlugin;
+
+impl Plugin for FallingBlockPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SpawnFallingBlock>();
+
+        app.add_systems(OnEnter(GameState::Game), setup);
+        app.add_systems(
+            Update,
+            (spawn_falling_blocks, land_falling_blocks)
+                .chain()
+                .run_if(in_state(GameState::Game))
+                .run_if(in_state(GamePauseState::Running)),
+        );
+    }
+}
+
+// Falling blocks go through air and liquids, and land on anything else
+pub fn is_passthrough(block: BlockType, registry: &BlockRegistry) -> bool {
+    return block == BlockType::AIR || registry.get(block).liquid.is_some();
+}
+
+// The block at the position, if it's affected by gravity and there's nothing under it to hold it up
+pub fn unsupported_block(
+    chunks: &HashMap<IVec2, Chunk>,
+    position: IVec2,
+    registry: &BlockRegistry,
+) -> Option<BlockType> {
+    let below = position + IVec2::NEG_Y;
+    if !chunks.contains_key(&get_chunk_position(position))
+        || !chunks.contains_key(&get_chunk_position(below))
+    {
+        return None;
+    }
+
+    let block = get_block(chunks, position, PlaceMode::BLOCK);
+    if !registry.get(block).gravity
+        || !is_passthrough(get_block(chunks, below, PlaceMode::BLOCK), registry)
+    {
+        return None;
+    }
+    return Some(block);
+}
+
+fn setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
+    registry: Res<BlockRegistry>,
+) {
+    let layout = TextureAtlasLayout::from_grid(
+        UVec2::splat(TILE_SIZE as u32),
+        registry.atlas_size as u32,
+        1,
+        None,
+        None,
+    );
+
+    commands.insert_resource(FallingBlockTextures {
+        blocks: asset_server.load("textures/blocks.png"),
+        blocks_layout: texture_atlas_layouts.add(layout),
+    });
+}
+
+fn spawn_falling_blocks(
+    mut commands: Commands,
+    mut spawn_falling_block_ev: EventReader<SpawnFallingBlock>,
+    textures: Res<FallingBlockTextures>,
+    registry: Res<BlockRegistry>,
+) {
+    for ev in spawn_falling_block_ev.read() {
+        let center = (ev.position.as_vec2() + 0.5) * TILE_SIZE as f32;
+        commands.spawn((
+            Name::new("Falling Block"),
+            SpriteBundle {
+                sprite: Sprite {
+                    custom_size: Some(Vec2::splat(TILE_SIZE as f32)),
+                    ..default()
+                },
+                texture: textures.blocks.clone(),
+                transform: Transform::from_xyz(center.x, center.y, 1.5),
+                ..default()
+            },
+            TextureAtlas {
+                layout: textures.blocks_layout.clone(),
+                index: registry.get(ev.block).atlas_index,
+            },
+            // It's moved by its velocity, and lands by looking at the blocks under it
+            // instead of colliding with them
+            RigidBody::Kinematic,
+            LinearVelocity::ZERO,
+            FallingBlock {
+                block: ev.block,
+                previous_y: center.y,
+            },
+            FromWorld,
+        ));
+    }
+}
+
+fn land_falling_blocks(
+    mut commands: Commands,
+    mut falling_q: Query<(Entity, &Transform, &mut LinearVelocity, &mut FallingBlock)>,
+    mut try_place_block_ev: EventWriter<TryPlaceBlock>,
+    mut drop_item_ev: EventWriter<DropItem>,
+    chunks_res: Res<Chunks>,
+    registry: Res<BlockRegistry>,
+    world_info_res: Res<WorldInfo>,
+    gravity: Res<Gravity>,
+    time: Res<Time>,
+) {
+    for (entity, transform, mut velocity, mut falling) in falling_q.iter_mut() {
+        velocity.y = (velocity.y + gravity.0.y * time.delta_seconds()).max(-TERMINAL_VELOCITY);
+
+        let center = transform.translation.xy();
+        let top = get_block_position(Vec2::new(center.x, falling.previous_y.max(center.y)));
+        let bottom = get_block_position(center);
+        falling.previous_y = center.y;
+
+        // Every cell the block went through since the last frame is checked from the top,
+        // so a slow frame can't make it skip the block it lands on
+        let landing = (bottom.y..=top.y).rev().find(|y| {
+            let below = IVec2::new(bottom.x, *y - 1);
+            // Unloaded chunks count as solid, so blocks don't fall out of the world
+            let lands_on_below = !chunks_res.contains_key(&get_chunk_position(below))
+                || !is_passthrough(get_block(&chunks_res, below, PlaceMode::BLOCK), &registry);
+            let cell_center = (*y as f32 + 0.5) * TILE_SIZE as f32;
+            return lands_on_below && center.y <= cell_center;
+        });
+        let Some(y) = landing else {
+            continue;
+        };
+        let cell = IVec2::new(bottom.x, y);
+
+        commands.entity(entity).despawn_recursive();
+
+        let chunk_position = get_chunk_position(cell);
+        let fits = chunks_res.contains_key(&chunk_position)
+            && is_passthrough(get_block(&chunks_res, cell, PlaceMode::BLOCK), &registry);
+        if fits {
+            let replaced = get_block(&chunks_res, cell, PlaceMode::BLOCK);
+            try_place_block_ev.send(TryPlaceBlock {
+                position: get_relative_position(cell, chunk_position),
+                chunk_position,
+                layer: PlaceMode::BLOCK,
+                block_type: falling.block,
+                by_player: false,
+                replaces: Some(replaced),
+            });
+        } else if world_info_res.game_mode == GameMode::Survival {
+            // Something took the place the block was landing on, so it breaks into an item
+            drop_item_ev.send(DropItem {
+                position: center,
+                stack: ItemStack::new(Item::Block(falling.block), 1),
+            });
+        }
+    }
+}
@@ -0,0 +1,466 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::BlockType;
+
+const MAX_BLOCK_STACK: u32 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolKind {
+    Pickaxe,
+    Axe,
+    Shovel,
+}
+
+// In the order they are shown in the creative inventory
+pub const TOOL_KINDS: [ToolKind; 3] = [ToolKind::Pickaxe, ToolKind::Axe, ToolKind::Shovel];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Item {
+    // The item of a block, which places it
+    Block(BlockType),
+    Tool(ToolKind),
+}
+
+impl Item {
+    // How many of this item fit in one slot
+    pub fn max_stack(&self) -> u32 {
+        return match self {
+            Item::Block(_) => MAX_BLOCK_STACK,
+            Item::Tool(_) => 1,
+        };
+    }
+
+    pub fn block(&self) -> Option<BlockType> {
+        return match self {
+            Item::Block(block) => Some(*block),
+            Item::Tool(_) => None,
+        };
+    }
+
+    pub fn tool(&self) -> Option<ToolKind> {
+        return match self {
+            Item::Block(_) => None,
+            Item::Tool(kind) => Some(*kind),
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ItemStack {
+    pub item: Item,
+    pub amount: u32,
+}
+
+impl ItemStack {
+    pub fn new(item: Item, amount: u32) -> Self {
+        Self { item, amount }
+    }
+
+    pub fn is_full(&self) -> bool {
+        return self.amount >= self.item.max_stack();
+    }
+
+    // Moves as many items of the other stack as fit into this one,
+    // and returns what's left of it
+    pub fn merge(&mut self, other: ItemStack) -> Option<ItemStack> {
+        if other.item != self.item {
+            return Some(other);
+        }
+
+        let moved = other
+            .amount
+            .min(self.item.max_stack().saturating_sub(self.amount));
+        self.amount += moved;
+        return stack_or_none(other.item, other.amount - moved);
+    }
+
+    // Takes up to amount items out of this stack.
+    // This stack can be left with 0 items, which the containers turn into an empty slot.
+    pub fn split(&mut self, amount: u32) -> Option<ItemStack> {
+        let taken = amount.min(self.amount);
+        self.amount -= taken;
+        return stack_or_none(self.item, taken);
+    }
+}
+
+fn stack_or_none(item: Item, amount: u32) -> Option<ItemStack> {
+    if amount == 0 {
+        return None;
+    }
+    return Some(ItemStack::new(item, amount));
+}
+
+// A grid of item slots, stored row by row.
+// The first row of the player inventory is the hotbar.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ItemContainer {
+    pub rows: usize,
+    pub columns: usize,
+    slots: Vec<Option<ItemStack>>,
+}
+
+impl ItemContainer {
+    pub fn new(rows: usize, columns: usize) -> Self {
+        Self {
+            rows,
+            columns,
+            slots: vec![None; rows * columns],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.slots.len();
+    }
+
+    pub fn index(&self, row: usize, column: usize) -> usize {
+        return row * self.columns + column;
+    }
+
+    pub fn get_item(&self, row: usize, column: usize) -> Option<&ItemStack> {
+        return self.get_slot(self.index(row, column));
+    }
+
+    // Returns the stack that was in the slot before
+    pub fn set_item(
+        &mut self,
+        row: usize,
+        column: usize,
+        stack: Option<ItemStack>,
+    ) -> Option<ItemStack> {
+        return self.set_slot(self.index(row, column), stack);
+    }
+
+    pub fn get_slot(&self, index: usize) -> Option<&ItemStack> {
+        return self.slots.get(index)?.as_ref();
+    }
+
+    // Returns the stack that was in the slot before.
+    // Stacks with no items leave the slot empty.
+    pub fn set_slot(&mut self, index: usize, stack: Option<ItemStack>) -> Option<ItemStack> {
+        let Some(slot) = self.slots.get_mut(index) else {
+            return stack;
+        };
+        let stack = stack.filter(|stack| stack.amount > 0);
+        return std::mem::replace(slot, stack);
+    }
+
+    // Adds the stack to the stacks of the same item first, then to the empty slots,
+    // and returns the items that didn't fit
+    pub fn insert(&mut self, stack: ItemStack) -> Option<ItemStack> {
+        return self.insert_in(0..self.len(), stack);
+    }
+
+    // Like insert, but only into the slots in the range
+    pub fn insert_in(&mut self, slots: Range<usize>, stack: ItemStack) -> Option<ItemStack> {
+        let Some(slots) = self.slots.get_mut(slots) else {
+            return Some(stack);
+        };
+        let mut remaining = Some(stack);
+
+        for slot in slots.iter_mut() {
+            let Some(stack) = remaining else {
+                return None;
+            };
+            if let Some(existing) = slot {
+                remaining = existing.merge(stack);
+            }
+        }
+
+        for slot in slots.iter_mut() {
+            let Some(mut stack) = remaining else {
+                return None;
+            };
+            if slot.is_none() {
+                *slot = stack.split(stack.item.max_stack());
+                remaining = stack_or_none(stack.item, stack.amount);
+            }
+        }
+
+        return remaining;
+    }
+
+    // Merges the stack into the one in the slot, and returns what didn't fit.
+    // An empty slot takes as much of the stack as an item allows.
+    pub fn insert_into(&mut self, index: usize, stack: ItemStack) -> Option<ItemStack> {
+        let Some(slot) = self.slots.get_mut(index) else {
+            return Some(stack);
+        };
+        return match slot {
+            Some(existing) => existing.merge(stack),
+            None => {
+                let mut rest = stack;
+                *slot = rest.split(stack.item.max_stack());
+                stack_or_none(rest.item, rest.amount)
+            }
+        };
+    }
+
+    // Takes up to amount items out of the slot
+    pub fn take(&mut self, index: usize, amount: u32) -> Option<ItemStack> {
+        let slot = self.slots.get_mut(index)?;
+        let taken = slot.as_mut()?.split(amount);
+        if slot.is_some_and(|stack| stack.amount == 0) {
+            *slot = None;
+        }
+        return taken;
+    }
+
+    // Takes the bigger half of the stack in the slot, leaving the rest behind
+    pub fn split(&mut self, index: usize) -> Option<ItemStack> {
+        let amount = self.get_slot(index)?.amount;
+        return self.take(index, amount.div_ceil(2));
+    }
+
+    // Removes up to amount of the item from the whole container,
+    // and returns how many were removed
+    pub fn remove(&mut self, item: Item, amount: u32) -> u32 {
+        let mut removed = 0;
+        for index in 0..self.slots.len() {
+            if removed == amount {
+                break;
+            }
+            if self.get_slot(index).is_some_and(|stack| stack.item == item) {
+                if let Some(taken) = self.take(index, amount - removed) {
+                    removed += taken.amount;
+                }
+            }
+        }
+        return removed;
+    }
+
+    pub fn count(&self, item: Item) -> u32 {
+        return self
+            .slots
+            .iter()
+            .flatten()
+            .filter(|stack| stack.item == item)
+            .map(|stack| stack.amount)
+            .sum();
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.fill(None);
+    }
+
+    // Changes the block of every block item, which is how the blocks are saved by name.
+    // The slots of the blocks mapped to None are emptied.
+    pub fn map_blocks(&mut self, mut map: impl FnMut(BlockType) -> Option<BlockType>) {
+        for slot in self.slots.iter_mut() {
+            let Some(ItemStack {
+                item: Item::Block(block),
+                ..
+            }) = slot
+            else {
+                continue;
+            };
+            match map(*block) {
+                Some(new_block) => *block = new_block,
+                None => *slot = None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIRT: Item = Item::Block(BlockType(2));
+    const STONE: Item = Item::Block(BlockType(3));
+    const PICKAXE: Item = Item::Tool(ToolKind::Pickaxe);
+
+    #[test]
+    fn merge_fills_up_to_the_stack_limit() {
+        let mut stack = ItemStack::new(DIRT, 60);
+        let overflow = stack.merge(ItemStack::new(DIRT, 10));
+        assert_eq!(stack.amount, 64);
+        assert_eq!(overflow, Some(ItemStack::new(DIRT, 6)));
+    }
+
+    #[test]
+    fn merge_without_overflow() {
+        let mut stack = ItemStack::new(DIRT, 30);
+        assert_eq!(stack.merge(ItemStack::new(DIRT, 34)), None);
+        assert!(stack.is_full());
+    }
+
+    #[test]
+    fn merge_of_different_items_gives_the_stack_back() {
+        let mut stack = ItemStack::new(DIRT, 1);
+        let other = ItemStack::new(STONE, 5);
+        assert_eq!(stack.merge(other), Some(other));
+        assert_eq!(stack.amount, 1);
+    }
+
+    #[test]
+    fn tools_do_not_stack() {
+        let mut stack = ItemStack::new(PICKAXE, 1);
+        assert_eq!(
+            stack.merge(ItemStack::new(PICKAXE, 1)),
+            Some(ItemStack::new(PICKAXE, 1))
+        );
+
+        let mut container = ItemContainer::new(1, 3);
+        assert_eq!(container.insert(ItemStack::new(PICKAXE, 2)), None);
+        assert_eq!(container.get_slot(0), Some(&ItemStack::new(PICKAXE, 1)));
+        assert_eq!(container.get_slot(1), Some(&ItemStack::new(PICKAXE, 1)));
+        assert_eq!(container.get_slot(2), None);
+    }
+
+    #[test]
+    fn split_takes_at_most_the_whole_stack() {
+        let mut stack = ItemStack::new(DIRT, 5);
+        assert_eq!(stack.split(8), Some(ItemStack::new(DIRT, 5)));
+        assert_eq!(stack.amount, 0);
+        assert_eq!(stack.split(1), None);
+    }
+
+    #[test]
+    fn insert_merges_before_using_empty_slots() {
+        let mut container = ItemContainer::new(1, 3);
+        container.set_slot(2, Some(ItemStack::new(DIRT, 60)));
+
+        assert_eq!(container.insert(ItemStack::new(DIRT, 10)), None);
+        assert_eq!(container.get_slot(2), Some(&ItemStack::new(DIRT, 64)));
+        assert_eq!(container.get_slot(0), Some(&ItemStack::new(DIRT, 6)));
+        assert_eq!(container.get_slot(1), None);
+    }
+
+    #[test]
+    fn insert_splits_big_stacks_over_several_slots() {
+        let mut container = ItemContainer::new(1, 3);
+        assert_eq!(container.insert(ItemStack::new(DIRT, 150)), None);
+        assert_eq!(container.get_slot(0), Some(&ItemStack::new(DIRT, 64)));
+        assert_eq!(container.get_slot(1), Some(&ItemStack::new(DIRT, 64)));
+        assert_eq!(container.get_slot(2), Some(&ItemStack::new(DIRT, 22)));
+    }
+
+    #[test]
+    fn insert_returns_the_overflow_when_full() {
+        let mut container = ItemContainer::new(1, 2);
+        container.set_slot(0, Some(ItemStack::new(STONE, 64)));
+        container.set_slot(1, Some(ItemStack::new(DIRT, 63)));
+
+        assert_eq!(
+            container.insert(ItemStack::new(DIRT, 5)),
+            Some(ItemStack::new(DIRT, 4))
+        );
+        assert_eq!(container.count(DIRT), 64);
+    }
+
+    #[test]
+    fn insert_in_only_uses_the_range() {
+        let mut container = ItemContainer::new(2, 2);
+        container.set_slot(0, Some(ItemStack::new(DIRT, 10)));
+
+        assert_eq!(container.insert_in(2..4, ItemStack::new(DIRT, 100)), None);
+        assert_eq!(container.get_slot(0), Some(&ItemStack::new(DIRT, 10)));
+        assert_eq!(container.get_slot(2), Some(&ItemStack::new(DIRT, 64)));
+        assert_eq!(container.get_slot(3), Some(&ItemStack::new(DIRT, 36)));
+
+        let stone = ItemStack::new(STONE, 1);
+        assert_eq!(container.insert_in(2..4, stone), Some(stone));
+        assert_eq!(container.insert_in(3..9, stone), Some(stone));
+    }
+
+    #[test]
+    fn insert_into_a_slot() {
+        let mut container = ItemContainer::new(1, 2);
+        assert_eq!(
+            container.insert_into(0, ItemStack::new(DIRT, 70)),
+            Some(ItemStack::new(DIRT, 6))
+        );
+        assert_eq!(container.get_slot(0), Some(&ItemStack::new(DIRT, 64)));
+
+        let stone = ItemStack::new(STONE, 1);
+        assert_eq!(container.insert_into(0, stone), Some(stone));
+        assert_eq!(container.insert_into(5, stone), Some(stone));
+    }
+
+    #[test]
+    fn take_empties_the_slot() {
+        let mut container = ItemContainer::new(1, 1);
+        container.set_slot(0, Some(ItemStack::new(DIRT, 3)));
+
+        assert_eq!(container.take(0, 2), Some(ItemStack::new(DIRT, 2)));
+        assert_eq!(container.take(0, 5), Some(ItemStack::new(DIRT, 1)));
+        assert_eq!(container.get_slot(0), None);
+        assert_eq!(container.take(0, 1), None);
+    }
+
+    #[test]
+    fn split_takes_the_bigger_half() {
+        let mut container = ItemContainer::new(1, 1);
+        container.set_slot(0, Some(ItemStack::new(DIRT, 5)));
+        assert_eq!(container.split(0), Some(ItemStack::new(DIRT, 3)));
+        assert_eq!(container.get_slot(0), Some(&ItemStack::new(DIRT, 2)));
+
+        container.set_slot(0, Some(ItemStack::new(DIRT, 1)));
+        assert_eq!(container.split(0), Some(ItemStack::new(DIRT, 1)));
+        assert_eq!(container.get_slot(0), None);
+    }
+
+    #[test]
+    fn remove_across_several_stacks() {
+        let mut container = ItemContainer::new(1, 3);
+        container.set_slot(0, Some(ItemStack::new(DIRT, 4)));
+        container.set_slot(1, Some(ItemStack::new(STONE, 4)));
+        container.set_slot(2, Some(ItemStack::new(DIRT, 4)));
+
+        assert_eq!(container.remove(DIRT, 6), 6);
+        assert_eq!(container.get_slot(0), None);
+        assert_eq!(container.get_slot(2), Some(&ItemStack::new(DIRT, 2)));
+        assert_eq!(container.remove(DIRT, 10), 2);
+        assert_eq!(container.count(STONE), 4);
+    }
+
+    #[test]
+    fn empty_stacks_leave_the_slot_empty() {
+        let mut container = ItemContainer::new(1, 1);
+        container.set_slot(0, Some(ItemStack::new(DIRT, 0)));
+        assert_eq!(container.get_slot(0), None);
+    }
+
+    #[test]
+    fn rows_and_columns() {
+        let mut container = ItemContainer::new(3, 10);
+        container.set_item(1, 2, Some(ItemStack::new(STONE, 1)));
+        assert_eq!(container.get_slot(12), Some(&ItemStack::new(STONE, 1)));
+        assert_eq!(container.len(), 30);
+    }
+
+    #[test]
+    fn map_blocks_leaves_the_tools_alone() {
+        let mut container = ItemContainer::new(1, 3);
+        container.set_slot(0, Some(ItemStack::new(DIRT, 5)));
+        container.set_slot(1, Some(ItemStack::new(STONE, 7)));
+        container.set_slot(2, Some(ItemStack::new(PICKAXE, 1)));
+
+        container.map_blocks(|block| match block {
+            BlockType(2) => Some(BlockType(0)),
+            _ => None,
+        });
+        assert_eq!(
+            container.get_slot(0),
+            Some(&ItemStack::new(Item::Block(BlockType(0)), 5))
+        );
+        assert_eq!(container.get_slot(1), None);
+        assert_eq!(container.get_slot(2), Some(&ItemStack::new(PICKAXE, 1)));
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let mut container = ItemContainer::new(2, 2);
+        container.set_slot(1, Some(ItemStack::new(PICKAXE, 1)));
+        container.set_slot(2, Some(ItemStack::new(DIRT, 12)));
+
+        let bytes = bincode::serialize(&container).unwrap();
+        assert_eq!(
+            bincode::deserialize::<ItemContainer>(&bytes).unwrap(),
+            container
+        );
+    }
+}
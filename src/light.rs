@@ -0,0 +1,427 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    block::BlockRegistry,
+    chunk::{Chunk, PlaceMode, RemeshChunks, CHUNK_AREA},
+    chunk_manager::Chunks,
+    utils::{
+        get_chunk_position, get_chunks_touching_block, get_global_position,
+        get_index_from_position, get_position_from_index, get_relative_position,
+    },
+};
+
+pub const MAX_LIGHT: u8 = 15;
+
+const DIRECTIONS: [IVec2; 4] = [IVec2::NEG_Y, IVec2::X, IVec2::Y, IVec2::NEG_X];
+
+// Sky light and each color of the block light spread on their own
+#[derive(Clone, Copy)]
+enum LightChannel {
+    Sky,
+    Block(usize),
+}
+
+const CHANNELS: [LightChannel; 4] = [
+    LightChannel::Sky,
+    LightChannel::Block(0),
+    LightChannel::Block(1),
+    LightChannel::Block(2),
+];
+
+// A block was placed or destroyed, so the light around it has to be updated.
+// The position is in global block coordinates.
+#[derive(Event)]
+pub struct RelightBlock {
+    pub position: IVec2,
+}
+
+// Every block of the chunk has to be relit, like when it's loaded without valid light
+#[derive(Event)]
+pub struct RelightChunk {
+    pub position: IVec2,
+}
+
+// Flood fill light engine, in the style of Minecraft's.
+//
+// When a block changes, its old light is removed with a BFS that clears every block
+// that was lit by it, and collects the brighter blocks at the edge of the cleared area.
+// Then the light of those blocks and of the light sources is spread again with another BFS.
+// Only the blocks whose light actually changes are visited,
+// and the light flows into every loaded chunk, so chunk borders need no special handling.
+// This is done separately for the sky light and the red, green and blue block light.
+#[derive(Default)]
+pub struct LightEngine {
+    add_queue: VecDeque<IVec2>,
+    remove_queue: VecDeque<(IVec2, u8)>,
+    relit: Vec<IVec2>,
+    changed_chunks: HashSet<IVec2>,
+}
+
+impl LightEngine {
+    // Returns the positions of the chunks that have to be remeshed because their light changed
+    pub fn relight(
+        &mut self,
+        chunks: &mut HashMap<IVec2, Chunk>,
+        registry: &BlockRegistry,
+        positions: &[IVec2],
+    ) -> HashSet<IVec2> {
+        self.changed_chunks.clear();
+
+        for &position in positions {
+            if let Some(chunk) = chunks.get_mut(&get_chunk_position(position)) {
+                chunk.dirty = false;
+            }
+        }
+
+        for channel in CHANNELS {
+            self.relight_channel(chunks, registry, positions, channel);
+        }
+
+        return self.changed_chunks.clone();
+    }
+
+    fn relight_channel(
+        &mut self,
+        chunks: &mut HashMap<IVec2, Chunk>,
+        registry: &BlockRegistry,
+        positions: &[IVec2],
+        channel: LightChannel,
+    ) {
+        self.relit.clear();
+
+        for &position in positions {
+            let Some(light) = get_light(chunks, position, channel) else {
+                continue;
+            };
+            self.set_light(chunks, position, channel, 0);
+            self.remove_queue.push_back((position, light));
+            self.relit.push(position);
+        }
+
+        self.propagate_removal(chunks, registry, channel);
+
+        for i in 0..self.relit.len() {
+            let position = self.relit[i];
+            let source = light_source(chunks, registry, position, channel);
+            if source > get_light(chunks, position, channel).unwrap_or(0) {
+                self.set_light(chunks, position, channel, source);
+                self.add_queue.push_back(position);
+            }
+        }
+
+        self.propagate_addition(chunks, channel);
+    }
+
+    fn propagate_removal(
+        &mut self,
+        chunks: &mut HashMap<IVec2, Chunk>,
+        registry: &BlockRegistry,
+        channel: LightChannel,
+    ) {
+        while let Some((position, light)) = self.remove_queue.pop_front() {
+            for direction in DIRECTIONS {
+                let neighbor = position + direction;
+                let Some(neighbor_light) = get_light(chunks, neighbor, channel) else {
+                    continue;
+                };
+
+                if neighbor_light != 0 && neighbor_light < light {
+                    // This block was lit by the removed light
+                    self.set_light(chunks, neighbor, channel, 0);
+                    self.remove_queue.push_back((neighbor, neighbor_light));
+
+                    // Light sources keep shining on their own
+                    let source = light_source(chunks, registry, neighbor, channel);
+                    if source > 0 {
+                        self.set_light(chunks, neighbor, channel, source);
+                        self.add_queue.push_back(neighbor);
+                    }
+                } else if neighbor_light >= light {
+                    // This block is lit by something else, so it has to spread its light again
+                    self.add_queue.push_back(neighbor);
+                }
+            }
+        }
+    }
+
+    fn propagate_addition(&mut self, chunks: &mut HashMap<IVec2, Chunk>, channel: LightChannel) {
+        while let Some(position) = self.add_queue.pop_front() {
+            let Some(light) = get_light(chunks, position, channel) else {
+                continue;
+            };
+            if light <= 1 {
+                continue;
+            }
+
+            for direction in DIRECTIONS {
+                let neighbor = position + direction;
+                let Some(neighbor_light) = get_light(chunks, neighbor, channel) else {
+                    continue;
+                };
+
+                if neighbor_light < light - 1 {
+                    self.set_light(chunks, neighbor, channel, light - 1);
+                    self.add_queue.push_back(neighbor);
+                }
+            }
+        }
+    }
+
+    fn set_light(
+        &mut self,
+        chunks: &mut HashMap<IVec2, Chunk>,
+        position: IVec2,
+        channel: LightChannel,
+        light: u8,
+    ) {
+        let chunk_pos = get_chunk_position(position);
+        if let Some(chunk) = chunks.get_mut(&chunk_pos) {
+            let index = get_index_from_position(get_relative_position(position, chunk_pos));
+            let value = match channel {
+                LightChannel::Sky => &mut chunk.light[index],
+                LightChannel::Block(c) => &mut chunk.block_light[index][c],
+            };
+            if *value != light {
+                *value = light;
+                self.changed_chunks
+                    .extend(get_chunks_touching_block(position));
+            }
+        }
+    }
+}
+
+// Returns None if the chunk of the block isn't loaded
+fn get_light(chunks: &HashMap<IVec2, Chunk>, position: IVec2, channel: LightChannel) -> Option<u8> {
+    let chunk_pos = get_chunk_position(position);
+    let chunk = chunks.get(&chunk_pos)?;
+    let index = get_index_from_position(get_relative_position(position, chunk_pos));
+    return match channel {
+        LightChannel::Sky => Some(chunk.light[index]),
+        LightChannel::Block(c) => Some(chunk.block_light[index][c]),
+    };
+}
+
+// Blocks with no block or wall in them are lit by the sky,
+// and light emitting blocks are block light sources
+fn light_source(
+    chunks: &HashMap<IVec2, Chunk>,
+    registry: &BlockRegistry,
+    position: IVec2,
+    channel: LightChannel,
+) -> u8 {
+    let chunk_pos = get_chunk_position(position);
+    let Some(chunk) = chunks.get(&chunk_pos) else {
+        return 0;
+    };
+    let index = get_index_from_position(get_relative_position(position, chunk_pos));
+
+    let block = registry.get(chunk.layers[PlaceMode::BLOCK as usize][index]);
+    let wall = registry.get(chunk.layers[PlaceMode::WALL as usize][index]);
+
+    return match channel {
+        LightChannel::Sky if block.transparent && wall.transparent => MAX_LIGHT,
+        LightChannel::Sky => 0,
+        LightChannel::Block(c) => block.emitted_light()[c].max(wall.emitted_light()[c]),
+    };
+}
+
+// The light of a block as an RGB color from 0.0 to 1.0,
+// which is the brightest of the sky light and the block light in each channel.
+// The sky light is dimmed by how far the current sky light level is from daylight.
+pub fn combined_light(chunk: &Chunk, index: usize, sky_level: u8) -> Vec3 {
+    return mix_light(chunk.light[index], chunk.block_light[index], sky_level);
+}
+
+// The same, from the sky light and the block light of a cell
+pub fn mix_light(sky: u8, block: [u8; 3], sky_level: u8) -> Vec3 {
+    let sky = sky.saturating_sub(MAX_LIGHT.saturating_sub(sky_level));
+    return Vec3::new(
+        sky.max(block[0]) as f32,
+        sky.max(block[1]) as f32,
+        sky.max(block[2]) as f32,
+    ) / MAX_LIGHT as f32;
+}
+
+pub fn update_light(
+    mut chunks: ResMut<Chunks>,
+    mut relight_block_ev: EventReader<RelightBlock>,
+    mut relight_chunk_ev: EventReader<RelightChunk>,
+    mut remesh_chunk_ev: EventWriter<RemeshChunks>,
+    mut engine: Local<LightEngine>,
+    registry: Res<BlockRegistry>,
+) {
+    let mut positions: Vec<IVec2> = relight_block_ev.read().map(|ev| ev.position).collect();
+    for ev in relight_chunk_ev.read() {
+        for i in 0..CHUNK_AREA {
+            positions.push(get_global_position(ev.position, get_position_from_index(i)));
+        }
+    }
+
+    if positions.is_empty() {
+        return;
+    }
+
+    let changed_chunks = engine.relight(&mut chunks, &registry, &positions);
+    if !changed_chunks.is_empty() {
+        remesh_chunk_ev.send(RemeshChunks {
+            positions: changed_chunks,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        biome::Biome, block::BlockType, chunk::CHUNK_WIDTH, liquid::LiquidCell,
+        utils::get_chunk_position,
+    };
+
+    // The test world is GRID_SIZE x GRID_SIZE chunks, from y = -GRID_SIZE / 2
+    const GRID_SIZE: i32 = 8;
+
+    fn registry() -> BlockRegistry {
+        return BlockRegistry::from_toml(include_str!("../assets/blocks.toml")).unwrap();
+    }
+
+    // Hilly stone terrain with caves, and stone walls under the surface so the caves are dark
+    fn test_world(registry: &BlockRegistry) -> HashMap<IVec2, Chunk> {
+        let stone = registry.id("stone").unwrap();
+        let mut chunks = HashMap::new();
+        for cx in 0..GRID_SIZE {
+            for cy in -GRID_SIZE / 2..GRID_SIZE / 2 {
+                let chunk_position = IVec2::new(cx, cy);
+                let mut layers = [[BlockType::AIR; CHUNK_AREA]; 2];
+                for i in 0..CHUNK_AREA {
+                    let p = get_global_position(chunk_position, get_position_from_index(i));
+                    let surface = (p.x * 7).rem_euclid(11) / 2 + (p.x / 9).rem_euclid(5) * 3;
+                    let cave = (p.x * 13 + p.y * 7).rem_euclid(23) < 5;
+                    if p.y < surface && !cave {
+                        layers[PlaceMode::BLOCK as usize][i] = stone;
+                    }
+                    if p.y < surface - 2 {
+                        layers[PlaceMode::WALL as usize][i] = stone;
+                    }
+                }
+                chunks.insert(
+                    chunk_position,
+                    Chunk {
+                        layers,
+                        light: [0; CHUNK_AREA],
+                        block_light: [[0; 3]; CHUNK_AREA],
+                        dirty: true,
+                        last_modified: 0,
+                        neighbors_modified: [0; 4],
+                        inhabited_time: 0,
+                        biomes: [Biome::Plains; CHUNK_WIDTH],
+                        block_entities: HashMap::new(),
+                        liquid: [LiquidCell::default(); CHUNK_AREA],
+                        scheduled_ticks: Vec::new(),
+                        signal: [[0; CHUNK_AREA]; 2],
+                        active_liquids: HashSet::new(),
+                    },
+                );
+            }
+        }
+        return chunks;
+    }
+
+    fn every_block(chunks: &HashMap<IVec2, Chunk>) -> Vec<IVec2> {
+        let mut positions = Vec::new();
+        for chunk_position in chunks.keys() {
+            for i in 0..CHUNK_AREA {
+                positions.push(get_global_position(
+                    *chunk_position,
+                    get_position_from_index(i),
+                ));
+            }
+        }
+        return positions;
+    }
+
+    // The sky light relaxation the flood fill replaced: 16 passes over every loaded chunk,
+    // where each block takes the brightest of its neighbors minus one.
+    // benches/relight.rs compares how long both take.
+    fn old_relaxation(chunks: &mut HashMap<IVec2, Chunk>, registry: &BlockRegistry) {
+        for _ in 0..16 {
+            let mut light_updates = Vec::new();
+            for (chunk_pos, chunk) in chunks.iter() {
+                let mut light = [0; CHUNK_AREA];
+                for i in 0..CHUNK_AREA {
+                    let block = registry.get(chunk.layers[PlaceMode::BLOCK as usize][i]);
+                    let wall = registry.get(chunk.layers[PlaceMode::WALL as usize][i]);
+                    if block.transparent && wall.transparent {
+                        light[i] = MAX_LIGHT;
+                        continue;
+                    }
+
+                    let global = get_global_position(*chunk_pos, get_position_from_index(i));
+                    let brightest = DIRECTIONS
+                        .iter()
+                        .filter_map(|direction| {
+                            get_light(chunks, global + *direction, LightChannel::Sky)
+                        })
+                        .max()
+                        .unwrap_or(0);
+                    light[i] = brightest.saturating_sub(1);
+                }
+                light_updates.push((*chunk_pos, light));
+            }
+
+            for (chunk_pos, light) in light_updates {
+                if let Some(chunk) = chunks.get_mut(&chunk_pos) {
+                    chunk.light = light;
+                    chunk.dirty = false;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flood_fill_matches_old_relaxation() {
+        let registry = registry();
+        let mut old = test_world(&registry);
+        let mut new = old.clone();
+
+        old_relaxation(&mut old, &registry);
+        let positions = every_block(&new);
+        LightEngine::default().relight(&mut new, &registry, &positions);
+
+        for (chunk_position, chunk) in old.iter() {
+            assert_eq!(chunk.light, new[chunk_position].light, "{}", chunk_position);
+        }
+    }
+
+    #[test]
+    fn placing_and_removing_a_block_restores_the_light() {
+        let registry = registry();
+        let mut chunks = test_world(&registry);
+        let mut engine = LightEngine::default();
+        let positions = every_block(&chunks);
+        engine.relight(&mut chunks, &registry, &positions);
+        let before = chunks.clone();
+
+        let position = IVec2::new(40, 20);
+        let chunk_position = get_chunk_position(position);
+        let index = get_index_from_position(get_relative_position(position, chunk_position));
+        let block = &mut chunks.get_mut(&chunk_position).unwrap().layers[PlaceMode::BLOCK as usize];
+        block[index] = registry.id("stone").unwrap();
+        engine.relight(&mut chunks, &registry, &[position]);
+        assert_eq!(
+            get_light(&chunks, position, LightChannel::Sky),
+            Some(MAX_LIGHT - 1)
+        );
+
+        let block = &mut chunks.get_mut(&chunk_position).unwrap().layers[PlaceMode::BLOCK as usize];
+        block[index] = BlockType::AIR;
+        engine.relight(&mut chunks, &registry, &[position]);
+        for (chunk_position, chunk) in before.iter() {
+            assert_eq!(chunk.light, chunks[chunk_position].light);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use serde::Deserialize;
+
+use crate::{
+    block::{BlockRegistry, BlockType},
+    chunk::{Chunk, PlaceMode, RemeshChunks, CHUNK_AREA},
+    chunk_manager::Chunks,
+    light::RelightBlock,
+    utils::{
+        get_chunk_position, get_chunks_touching_block, get_global_position,
+        get_index_from_position, get_position_from_index, get_relative_position,
+    },
+    world::{WorldTick, WorldTickSchedule},
+};
+
+// How many levels a cell can be filled with, where the highest is a full block
+pub const MAX_LIQUID_LEVEL: u8 = 8;
+
+// The bit of a saved cell that tells if it's a source
+const SOURCE_BIT: u8 = 0x80;
+
+// Set with a [blocks.liquid] table in blocks.toml
+#[derive(Clone, Debug, Deserialize)]
+pub struct LiquidProperties {
+    // How many world ticks pass between each step of the flow
+    #[serde(default = "default_flow_interval")]
+    pub flow_interval: u64,
+    // Whether placing the liquid makes a source, which is never drained.
+    // Otherwise the liquid is finite, and only spreads out until it runs out.
+    #[serde(default)]
+    pub sources: bool,
+    // Whether a cell between two sources, with something under it to hold it up, becomes a source too
+    #[serde(default)]
+    pub infinite: bool,
+}
+
+fn default_flow_interval() -> u64 {
+    12
+}
+
+// How much liquid a cell of the BLOCK layer has.
+// The liquid is the block in the cell, and cells without liquid have a level of 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LiquidCell {
+    pub level: u8,
+    pub source: bool,
+}
+
+impl LiquidCell {
+    // A full cell, like the ones placed by the player
+    pub fn full(properties: &LiquidProperties) -> Self {
+        Self {
+            level: MAX_LIQUID_LEVEL,
+            source: properties.sources,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        return self.level.min(MAX_LIQUID_LEVEL) | if self.source { SOURCE_BIT } else { 0 };
+    }
+
+    pub fn from_byte(byte: u8) -> Self {
+        Self {
+            level: (byte & !SOURCE_BIT).min(MAX_LIQUID_LEVEL),
+            source: byte & SOURCE_BIT != 0,
+        }
+    }
+
+    // Liquids placed by the world generator, structures or older versions of the game
+    // don't have a level yet, and are treated as full
+    pub fn amount(self) -> u8 {
+        if self.level == 0 {
+            return MAX_LIQUID_LEVEL;
+        }
+        return self.level;
+    }
+
+    // How much of the cell is filled, from 0.0 to 1.0
+    pub fn fill(self) -> f32 {
+        return self.amount() as f32 / MAX_LIQUID_LEVEL as f32;
+    }
+}
+
+pub struct LiquidPlugin;
+
+impl Plugin for LiquidPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(WorldTickSchedule, flow_liquids);
+    }
+}
+
+// What's in a cell of the BLOCK layer, or None if its chunk isn't loaded
+fn get_cell(chunks: &HashMap<IVec2, Chunk>, position: IVec2) -> Option<(BlockType, LiquidCell)> {
+    let chunk_position = get_chunk_position(position);
+    let index = get_index_from_position(get_relative_position(position, chunk_position));
+    let chunk = chunks.get(&chunk_position)?;
+    return Some((
+        chunk.layers[PlaceMode::BLOCK as usize][index],
+        chunk.liquid[index],
+    ));
+}
+
+fn set_cell(
+    chunks: &mut HashMap<IVec2, Chunk>,
+    position: IVec2,
+    block: BlockType,
+    cell: LiquidCell,
+    tick: u64,
+) {
+    let chunk_position = get_chunk_position(position);
+    let index = get_index_from_position(get_relative_position(position, chunk_position));
+    if let Some(chunk) = chunks.get_mut(&chunk_position) {
+        chunk.layers[PlaceMode::BLOCK as usize][index] = block;
+        chunk.liquid[index] = cell;
+        chunk.last_modified = tick;
+    }
+}
+
+// Makes the liquid at the position and around it flow again, after something changed there
+pub fn wake_liquids(chunks: &mut HashMap<IVec2, Chunk>, position: IVec2, registry: &BlockRegistry) {
+    for neighbor in [
+        position,
+        position + IVec2::NEG_Y,
+        position + IVec2::X,
+        position + IVec2::Y,
+        position + IVec2::NEG_X,
+    ] {
+        let chunk_position = get_chunk_position(neighbor);
+        let index = get_index_from_position(get_relative_position(neighbor, chunk_position));
+        let Some(chunk) = chunks.get_mut(&chunk_position) else {
+            continue;
+        };
+        let block = chunk.layers[PlaceMode::BLOCK as usize][index];
+        if registry.get(block).liquid.is_some() {
+            chunk.active_liquids.insert(index);
+        }
+    }
+}
+
+// Wakes every liquid of a chunk that was just loaded, and the ones at the borders of its neighbors,
+// which couldn't flow into it while it wasn't loaded
+pub fn wake_chunk_liquids(
+    chunks: &mut HashMap<IVec2, Chunk>,
+    chunk_position: IVec2,
+    registry: &BlockRegistry,
+) {
+    for i in 0..CHUNK_AREA {
+        let position = get_global_position(chunk_position, get_position_from_index(i));
+        wake_liquids(chunks, position, registry);
+    }
+}
+
+// How much of the liquid fits into the cell. Liquids only flow into air and into themselves.
+fn room_for(chunks: &HashMap<IVec2, Chunk>, position: IVec2, liquid: BlockType) -> u8 {
+    return match get_cell(chunks, position) {
+        Some((BlockType::AIR, _)) => MAX_LIQUID_LEVEL,
+        Some((block, cell)) if block == liquid => MAX_LIQUID_LEVEL - cell.amount(),
+        _ => 0,
+    };
+}
+
+fn level_at(chunks: &HashMap<IVec2, Chunk>, position: IVec2, liquid: BlockType) -> Option<u8> {
+    return match get_cell(chunks, position) {
+        Some((BlockType::AIR, _)) => Some(0),
+        Some((block, cell)) if block == liquid => Some(cell.amount()),
+        _ => None,
+    };
+}
+
+fn is_source(chunks: &HashMap<IVec2, Chunk>, position: IVec2, liquid: BlockType) -> bool {
+    return get_cell(chunks, position).is_some_and(|(block, cell)| block == liquid && cell.source);
+}
+
+// The cells whose block or level changed during a step
+#[derive(Default)]
+struct FlowChanges {
+    // Cells that turned from air into liquid or the other way around, which changes the light
+    blocks: Vec<IVec2>,
+    levels: HashSet<IVec2>,
+}
+
+fn add_liquid(
+    chunks: &mut HashMap<IVec2, Chunk>,
+    position: IVec2,
+    liquid: BlockType,
+    amount: u8,
+    tick: u64,
+    changes: &mut FlowChanges,
+) {
+    let Some((block, mut cell)) = get_cell(chunks, position) else {
+        return;
+    };
+    if block == BlockType::AIR {
+        changes.blocks.push(position);
+    }
+    cell.level += amount;
+    set_cell(chunks, position, liquid, cell, tick);
+    changes.levels.insert(position);
+}
+
+// Cellular flow: the liquid falls down first, then evens out with the cells at its sides.
+// Sideways it only flows into cells that are at least two levels lower,
+// so the liquid coming out of a source settles into a slope instead of covering everything.
+fn flow_cell(
+    chunks: &mut HashMap<IVec2, Chunk>,
+    registry: &BlockRegistry,
+    position: IVec2,
+    sides: [IVec2; 2],
+    tick: u64,
+    changes: &mut FlowChanges,
+) {
+    let Some((liquid, start)) = get_cell(chunks, position) else {
+        return;
+    };
+    let Some(properties) = registry.get(liquid).liquid.as_ref() else {
+        return;
+    };
+    let mut cell = start;
+    if cell.level == 0 {
+        cell = LiquidCell::full(properties);
+    }
+
+    let below = position + IVec2::NEG_Y;
+    let falling = cell.level.min(room_for(chunks, below, liquid));
+    if falling > 0 {
+        add_liquid(chunks, below, liquid, falling, tick, changes);
+        cell.level -= falling;
+    }
+
+    for side in sides {
+        let Some(level) = level_at(chunks, position + side, liquid) else {
+            continue;
+        };
+        if cell.level > level + 1 {
+            let amount = (cell.level - level) / 2;
+            add_liquid(chunks, position + side, liquid, amount, tick, changes);
+            cell.level -= amount;
+        }
+    }
+
+    if properties.infinite
+        && !cell.source
+        && room_for(chunks, below, liquid) == 0
+        && sides
+            .iter()
+            .all(|side| is_source(chunks, position + *side, liquid))
+    {
+        cell.source = true;
+    }
+    if cell.source {
+        cell.level = MAX_LIQUID_LEVEL;
+    }
+
+    if cell == start {
+        return;
+    }
+    if cell.level == 0 {
+        set_cell(
+            chunks,
+            position,
+            BlockType::AIR,
+            LiquidCell::default(),
+            tick,
+        );
+        changes.blocks.push(position);
+    } else {
+        set_cell(chunks, position, liquid, cell, tick);
+    }
+    changes.levels.insert(position);
+}
+
+fn flow_liquids(
+    mut chunks_res: ResMut<Chunks>,
+    mut relight_block_ev: EventWriter<RelightBlock>,
+    mut remesh_chunk_ev: EventWriter<RemeshChunks>,
+    world_tick: Res<WorldTick>,
+    registry: Res<BlockRegistry>,
+) {
+    // The chunks are only marked as changed when some liquid actually moved
+    let chunks = chunks_res.bypass_change_detection();
+
+    // Only the active cells that had liquid when the step started flow,
+    // so the liquid moves one cell per step. Every liquid flows at its own pace,
+    // and the cells that flow leave the active set until something changes around them.
+    let mut cells = Vec::new();
+    for (chunk_position, chunk) in chunks.iter_mut() {
+        let blocks = &chunk.layers[PlaceMode::BLOCK as usize];
+        chunk
+            .active_liquids
+            .retain(|&i| match registry.get(blocks[i]).liquid.as_ref() {
+                None => false,
+                Some(liquid) if world_tick.0 % liquid.flow_interval.max(1) != 0 => true,
+                Some(liquid) => {
+                    // The flow steps of the liquid are counted, since the intervals can be even
+                    let step = world_tick.0 / liquid.flow_interval.max(1);
+                    cells.push((
+                        get_global_position(*chunk_position, get_position_from_index(i)),
+                        step % 2 == 0,
+                    ));
+                    false
+                }
+            });
+    }
+    if cells.is_empty() {
+        return;
+    }
+
+    // The bottom cells flow first so they make room for the ones falling on them,
+    // and the side that goes first alternates every step so the liquid doesn't lean to one side
+    cells.sort_by_key(|(position, leftwards)| {
+        (
+            position.y,
+            if *leftwards { position.x } else { -position.x },
+        )
+    });
+
+    let mut changes = FlowChanges::default();
+    for (position, leftwards) in cells {
+        let sides = match leftwards {
+            true => [IVec2::NEG_X, IVec2::X],
+            false => [IVec2::X, IVec2::NEG_X],
+        };
+        flow_cell(
+            chunks,
+            &registry,
+            position,
+            sides,
+            world_tick.0,
+            &mut changes,
+        );
+    }
+    for position in changes.levels.iter() {
+        wake_liquids(chunks, *position, &registry);
+    }
+    if changes.levels.is_empty() {
+        return;
+    }
+    chunks_res.set_changed();
+
+    for position in changes.blocks {
+        relight_block_ev.send(RelightBlock { position });
+    }
+    let mut remesh = HashSet::new();
+    for position in changes.levels {
+        remesh.extend(get_chunks_touching_block(position));
+    }
+    remesh_chunk_ev.send(RemeshChunks { positions: remesh });
+}
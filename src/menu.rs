@@ -3,7 +3,9 @@ use crate::player::PlayerSettings;
 use crate::widgets::button::{ButtonPressed, ButtonWidgetExt, ButtonWidgetPlugin};
 use crate::widgets::game_settings::{GameSettingsWidgetExt, GameSettingsWidgetPlugin};
 use crate::widgets::player_settings::{PlayerSettingsWidgetExt, PlayerSettingsWidgetPlugin};
-use crate::world::WorldGenPreset;
+use crate::block::BlockRegistry;
+use crate::world::{WorldTime, GAME_MODES};
+use crate::world_generator::{GeneratorSettings, WorldGenerators};
 use crate::GameSettings;
 use crate::{world::WorldInfo, GameState};
 use bevy::color::palettes::css::GRAY;
@@ -20,7 +22,31 @@ use filenamify::filenamify;
 struct WorldCreationNameTextInput;
 
 #[derive(Component)]
-struct WorldGenPresetDropdown;
+struct WorldCreationSeedTextInput;
+
+#[derive(Component)]
+struct GameModeDropdown;
+
+#[derive(Component)]
+struct WorldGeneratorDropdown;
+
+// A row with a setting of a generator, which is only shown while that generator is picked
+#[derive(Component)]
+struct GeneratorSettingRow {
+    generator: String,
+}
+
+#[derive(Component)]
+struct GeneratorSettingTextInput {
+    generator: String,
+    key: String,
+}
+
+#[derive(Component)]
+struct GeneratorSettingPresetDropdown {
+    generator: String,
+    key: String,
+}
 
 #[derive(Component)]
 pub struct WorldListEntry {
@@ -84,7 +110,15 @@ impl Plugin for MenuPlugin {
         app.add_systems(OnEnter(GameState::Menu), setup_menu);
         app.add_systems(
             Update,
-            (on_refresh_world_list, world_list_entry_system).run_if(in_state(GameState::Menu)),
+            (
+                on_refresh_world_list,
+                world_list_entry_system,
+                focus_text_inputs,
+                show_generator_setting_rows,
+                apply_generator_setting_preset,
+                validate_generator_setting_inputs,
+            )
+                .run_if(in_state(GameState::Menu)),
         );
 
         app.add_systems(OnExit(GameState::Menu), destroy_menu);
@@ -251,7 +285,28 @@ fn setup_world_screen_menu(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
     mut refresh_world_list_ev: EventWriter<RefreshWorldList>,
+    world_generators: Res<WorldGenerators>,
 ) {
+    let generator_names: Vec<String> = world_generators
+        .ids()
+        .iter()
+        .map(|id| world_generators.display_name(id))
+        .collect();
+
+    // The rows of the first generator are shown, since it is the one picked at first
+    let first_generator = world_generators.ids().first().cloned().unwrap_or_default();
+    let setting_fields: Vec<_> = world_generators
+        .ids()
+        .into_iter()
+        .flat_map(|id| {
+            world_generators
+                .settings(&id)
+                .iter()
+                .map(|setting| (id.clone(), setting.clone()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
     commands.ui_builder(UiRoot).row(|row| {
         row.insert(WorldScreenMenu);
         row.named("World Screen Menu");
@@ -367,14 +422,15 @@ fn setup_world_screen_menu(
                 entries.button("< Go Back".into(), 24.0).observe(|
                     _: Trigger<ButtonPressed>,
                     mut world_creation_q: Query<&mut Visibility, With<WorldCreation>>,
-                    mut text_input_q: Query<&mut TextInputInactive, With<WorldCreationNameTextInput>>
+                    mut text_input_q: Query<&mut TextInputInactive>
                 | {
                     for mut visibility in world_creation_q.iter_mut() {
                         *visibility = Visibility::Hidden;
                     }
 
-                    let mut text_input_inactive = text_input_q.get_single_mut().unwrap();
-                    *text_input_inactive = TextInputInactive(true);
+                    for mut text_input_inactive in text_input_q.iter_mut() {
+                        *text_input_inactive = TextInputInactive(true);
+                    }
                 });
 
                 entries.row(|world_name_entry| {
@@ -409,15 +465,68 @@ fn setup_world_screen_menu(
                                 color: Color::WHITE,
                                 ..default()
                             }).with_settings(TextInputSettings { retain_on_submit: true, ..default() }).with_inactive(true),
+                        Interaction::None,
                         WorldCreationNameTextInput
                     ));
                 });
 
+                entries.row(|world_seed_entry| {
+                    world_seed_entry.style().column_gap(Val::Px(5.0)).justify_content(JustifyContent::SpaceBetween);
+
+                    world_seed_entry.spawn(TextBundle::from_section(
+                        "Seed (optional): ",
+                        TextStyle {
+                            font: asset_server.load("fonts/nokiafc22.ttf"),
+                            font_size: 24.0,
+                            color: Color::WHITE,
+                        },
+                    )).style().width(Val::Percent(100.0));
+
+                    world_seed_entry.spawn((
+                        NodeBundle {
+                            style: Style {
+                                width: Val::Percent(100.0),
+                                border: UiRect::all(Val::Px(5.0)),
+                                padding: UiRect::all(Val::Px(5.0)),
+                                max_width: Val::Px(200.0),
+                                ..default()
+                            },
+                            border_color: Color::WHITE.into(),
+                            background_color: Color::BLACK.into(),
+                            ..default()
+                        },
+                        TextInputBundle::default()
+                            .with_text_style(TextStyle {
+                                font: asset_server.load("fonts/nokiafc22.ttf"),
+                                font_size: 24.0,
+                                color: Color::WHITE,
+                                ..default()
+                            }).with_settings(TextInputSettings { retain_on_submit: true, ..default() }).with_inactive(true),
+                        Interaction::None,
+                        WorldCreationSeedTextInput
+                    ));
+                });
+
+                entries.row(|game_mode_entry| {
+                    game_mode_entry.style().column_gap(Val::Px(5.0)).justify_content(JustifyContent::SpaceBetween);
+
+                    game_mode_entry.spawn(TextBundle::from_section(
+                        "Game Mode: ",
+                        TextStyle {
+                            font: asset_server.load("fonts/nokiafc22.ttf"),
+                            font_size: 24.0,
+                            color: Color::WHITE,
+                        },
+                    ));
+
+                    game_mode_entry.dropdown(GAME_MODES.iter().map(|mode| mode.display_name()).collect::<Vec<_>>(), 0).insert(GameModeDropdown);
+                });
+
                 entries.row(|world_gen_preset_entry| {
                     world_gen_preset_entry.style().column_gap(Val::Px(5.0)).justify_content(JustifyContent::SpaceBetween);
 
                     world_gen_preset_entry.spawn(TextBundle::from_section(
-                        "World Generation Preset: ",
+                        "World Generator: ",
                         TextStyle {
                             font: asset_server.load("fonts/nokiafc22.ttf"),
                             font_size: 24.0,
@@ -425,22 +534,91 @@ fn setup_world_screen_menu(
                         },
                     ));
 
-                    world_gen_preset_entry.dropdown(vec!["Default", "Flat", "Empty"], 0).insert(WorldGenPresetDropdown);
+                    world_gen_preset_entry.dropdown(generator_names, 0).insert(WorldGeneratorDropdown);
                 });
 
+                for (generator, setting) in setting_fields {
+                    entries.row(|setting_entry| {
+                        let display = if generator == first_generator { Display::Flex } else { Display::None };
+                        setting_entry.insert(GeneratorSettingRow { generator: generator.clone() });
+                        setting_entry.style().column_gap(Val::Px(5.0)).justify_content(JustifyContent::SpaceBetween).display(display);
+
+                        setting_entry.spawn(TextBundle::from_section(
+                            format!("{}: ", setting.label),
+                            TextStyle {
+                                font: asset_server.load("fonts/nokiafc22.ttf"),
+                                font_size: 24.0,
+                                color: Color::WHITE,
+                            },
+                        ));
+
+                        if !setting.presets.is_empty() {
+                            setting_entry.dropdown(setting.presets.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>(), 0).insert(GeneratorSettingPresetDropdown {
+                                generator: generator.clone(),
+                                key: setting.key.clone(),
+                            });
+                        }
+
+                        setting_entry.spawn((
+                            NodeBundle {
+                                style: Style {
+                                    width: Val::Percent(100.0),
+                                    border: UiRect::all(Val::Px(5.0)),
+                                    padding: UiRect::all(Val::Px(5.0)),
+                                    max_width: Val::Px(300.0),
+                                    ..default()
+                                },
+                                border_color: Color::WHITE.into(),
+                                background_color: Color::BLACK.into(),
+                                ..default()
+                            },
+                            TextInputBundle::default()
+                                .with_text_style(TextStyle {
+                                    font: asset_server.load("fonts/nokiafc22.ttf"),
+                                    font_size: 16.0,
+                                    color: Color::WHITE,
+                                    ..default()
+                                }).with_value(setting.default.clone()).with_settings(TextInputSettings { retain_on_submit: true, ..default() }).with_inactive(true),
+                            Interaction::None,
+                            GeneratorSettingTextInput {
+                                generator: generator.clone(),
+                                key: setting.key.clone(),
+                            }
+                        ));
+                    });
+                }
+
                 entries.button("Create World and Play".into(), 24.0).observe(|
                     _: Trigger<ButtonPressed>,
                     mut next_state: ResMut<NextState<GameState>>,
                     text_input_query: Query<&TextInputValue, With<WorldCreationNameTextInput>>,
-                    world_preset_dropdown_q: Query<&Dropdown, With<WorldGenPresetDropdown>>,
+                    seed_input_query: Query<&TextInputValue, With<WorldCreationSeedTextInput>>,
+                    game_mode_dropdown_q: Query<&Dropdown, With<GameModeDropdown>>,
+                    world_generator_dropdown_q: Query<&Dropdown, With<WorldGeneratorDropdown>>,
+                    setting_input_q: Query<(&TextInputValue, &GeneratorSettingTextInput)>,
+                    world_generators: Res<WorldGenerators>,
+                    registry: Res<BlockRegistry>,
                     mut world_info_res: ResMut<WorldInfo>,
                     mut first_time: ResMut<JustCreatedWorld>
                 | {
-                    let preset = match world_preset_dropdown_q.single().value().unwrap() {
-                        0 => WorldGenPreset::DEFAULT,
-                        1 => WorldGenPreset::FLAT,
-                        2 => WorldGenPreset::EMPTY,
-                        _ => WorldGenPreset::DEFAULT
+                    let generator = world_generator_dropdown_q.single().value()
+                        .and_then(|i| world_generators.ids().get(i).cloned())
+                        .unwrap_or("default".to_string());
+
+                    let game_mode = game_mode_dropdown_q.single().value()
+                        .and_then(|i| GAME_MODES.get(i).copied())
+                        .unwrap_or_default();
+
+                    let generator_settings: GeneratorSettings = setting_input_q.iter()
+                        .filter(|(_, input)| input.generator == generator)
+                        .map(|(value, input)| (input.key.clone(), value.0.trim().to_string()))
+                        .collect();
+                    let generator_settings = match world_generators.validate_settings(&generator, &registry, generator_settings) {
+                        Ok(generator_settings) => generator_settings,
+                        Err(e) => {
+                            error!("The world generator settings are invalid: {}", e);
+                            return;
+                        }
                     };
 
                     let text_input = text_input_query.single();
@@ -452,12 +630,20 @@ fn setup_world_screen_menu(
                     let world_name = filenamify(text_input.0.clone()).to_lowercase().replace(" ", "_");
                     info!("World will be saved as '{}'.", world_name.clone());
 
+                    let seed = seed_from_text(&seed_input_query.single().0);
+                    info!("World seed: {}", seed);
+
                     let info = WorldInfo {
                         display_name: text_input.0.clone(),
                         name: world_name.clone(),
-                        preset,
+                        generator,
+                        seed,
                         player_position: None,
-                        is_flying: false
+                        is_flying: false,
+                        game_mode,
+                        tick: 0,
+                        time: WorldTime::default(),
+                        generator_settings
                     };
 
                     *first_time = JustCreatedWorld(true);
@@ -465,8 +651,8 @@ fn setup_world_screen_menu(
                     if let Err(e) = fs::create_dir(format!("worlds/{}", world_name.clone())) {
                         error!("Failed creating world directory for '{}': {}", world_name, e);
                     } else {
-                        if let Err(e) = fs::create_dir(format!("worlds/{}/chunks", world_name.clone())) {
-                            error!("Failed creating the chunks directory for '{}': {}", world_name, e);
+                        if let Err(e) = fs::create_dir(format!("worlds/{}/regions", world_name.clone())) {
+                            error!("Failed creating the regions directory for '{}': {}", world_name, e);
                         }
 
                         match toml::to_string(&info) {
@@ -490,6 +676,108 @@ fn setup_world_screen_menu(
     });
 }
 
+// Numbers are used as they are, and any other text is hashed into a seed.
+// An empty seed picks a random one.
+fn seed_from_text(text: &str) -> u64 {
+    let text = text.trim();
+    if text.is_empty() {
+        return rand::random();
+    }
+
+    if let Ok(seed) = text.parse::<u64>() {
+        return seed;
+    }
+    if let Ok(seed) = text.parse::<i64>() {
+        return seed as u64;
+    }
+
+    // FNV-1a, so the same text always gives the same seed
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in text.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    return hash;
+}
+
+// Only the text input that was clicked last receives the typed text
+fn focus_text_inputs(
+    interaction_q: Query<(Entity, &Interaction), (Changed<Interaction>, With<TextInputValue>)>,
+    mut text_input_q: Query<(Entity, &mut TextInputInactive)>,
+) {
+    for (pressed_entity, interaction) in interaction_q.iter() {
+        if *interaction == Interaction::Pressed {
+            for (entity, mut text_input_inactive) in text_input_q.iter_mut() {
+                *text_input_inactive = TextInputInactive(entity != pressed_entity);
+            }
+        }
+    }
+}
+
+fn show_generator_setting_rows(
+    generator_dropdown_q: Query<&Dropdown, (With<WorldGeneratorDropdown>, Changed<Dropdown>)>,
+    mut setting_row_q: Query<(&mut Style, &GeneratorSettingRow)>,
+    world_generators: Res<WorldGenerators>,
+) {
+    for dropdown in generator_dropdown_q.iter() {
+        let generator = dropdown
+            .value()
+            .and_then(|i| world_generators.ids().get(i).cloned());
+
+        for (mut style, row) in setting_row_q.iter_mut() {
+            style.display = if generator.as_ref() == Some(&row.generator) { Display::Flex } else { Display::None };
+        }
+    }
+}
+
+fn apply_generator_setting_preset(
+    preset_dropdown_q: Query<(&Dropdown, &GeneratorSettingPresetDropdown), Changed<Dropdown>>,
+    mut setting_input_q: Query<(&mut TextInputValue, &GeneratorSettingTextInput)>,
+    world_generators: Res<WorldGenerators>,
+) {
+    for (dropdown, preset_dropdown) in preset_dropdown_q.iter() {
+        let Some(setting) = world_generators
+            .settings(&preset_dropdown.generator)
+            .iter()
+            .find(|setting| setting.key == preset_dropdown.key)
+        else {
+            continue;
+        };
+        let Some((_, value)) = dropdown.value().and_then(|i| setting.presets.get(i)) else {
+            continue;
+        };
+        for (mut text_input_value, input) in setting_input_q.iter_mut() {
+            if input.generator == preset_dropdown.generator && input.key == preset_dropdown.key && text_input_value.0 != *value {
+                text_input_value.0 = value.clone();
+            }
+        }
+    }
+}
+
+// The border of a setting's text input turns red while the generator wouldn't accept it
+fn validate_generator_setting_inputs(
+    mut setting_input_q: Query<
+        (&TextInputValue, &GeneratorSettingTextInput, &mut BorderColor),
+        Changed<TextInputValue>,
+    >,
+    world_generators: Res<WorldGenerators>,
+    registry: Res<BlockRegistry>,
+) {
+    for (text_input_value, input, mut border_color) in setting_input_q.iter_mut() {
+        let Some(setting) = world_generators
+            .settings(&input.generator)
+            .iter()
+            .find(|setting| setting.key == input.key)
+        else {
+            continue;
+        };
+        *border_color = match setting.validate(text_input_value.0.trim(), &registry) {
+            Ok(_) => Color::WHITE.into(),
+            Err(_) => Color::srgb(0.8, 0.1, 0.1).into(),
+        };
+    }
+}
+
 fn destroy_world_screen_menu(
     mut commands: Commands,
     default_menu_q: Query<Entity, With<WorldScreenMenu>>,
@@ -612,6 +900,7 @@ fn on_refresh_world_list(
     world_list_q: Query<Entity, With<WorldListScroll>>,
     mut entry_eid_res: ResMut<WorldListEntryEID>,
     asset_server: Res<AssetServer>,
+    world_generators: Res<WorldGenerators>,
 ) {
     for _ in ev.read() {
         let mut world_scroll_view = commands.ui_builder(world_list_q.single());
@@ -678,18 +967,16 @@ fn on_refresh_world_list(
                                                             },
                                                         ));
 
-                                                        let world_preset_string = match world_info
-                                                            .preset
-                                                        {
-                                                            WorldGenPreset::DEFAULT => "Default",
-                                                            WorldGenPreset::FLAT => "Flat",
-                                                            WorldGenPreset::EMPTY => "Empty",
-                                                        };
+                                                        let world_preset_string = world_generators
+                                                            .display_name(&world_info.generator);
 
                                                         parent.spawn(TextBundle::from_section(
                                                             format!(
-                                                                "Type: {}",
-                                                                world_preset_string
+                                                                "Type: {}, {}",
+                                                                world_preset_string,
+                                                                world_info
+                                                                    .game_mode
+                                                                    .display_name()
                                                             ),
                                                             TextStyle {
                                                                 font: font.clone(),
@@ -4,32 +4,41 @@ use bevy_xpbd_2d::{
     components::{LinearVelocity, Position, RigidBody, Rotation},
     math::Vector,
     plugins::{
-        collision::{Collider, Collisions},
-        spatial_query::{ShapeCaster, ShapeHits},
+        collision::{Collider, CollisionLayers, Collisions},
+        spatial_query::{ShapeCaster, ShapeHits, SpatialQueryFilter},
     },
     SubstepSchedule, SubstepSet,
 };
 use serde::{Deserialize, Serialize};
 use std::f32::consts::FRAC_PI_2;
 
-use crate::world::{FromWorld, WorldInfo};
+use crate::world::{FromWorld, WorldInfo, WorldTime};
 use crate::{
-    chunk::{ChunkComponent, TILE_SIZE},
+    block::BlockRegistry,
+    chunk::{ChunkComponent, PlaceMode, TILE_SIZE},
     chunk_manager::{Chunks, LoadChunks, UnloadChunks},
-    utils::{get_chunk_position, get_index_from_position, get_relative_position},
-    GameState,
+    light::combined_light,
+    utils::{
+        get_block_position, get_chunk_position, get_index_from_position, get_relative_position,
+    },
+    GameLayer, GameState,
 };
 use crate::{utils::lerp, GamePauseState};
 
-const PLAYER_SIZE: f32 = 28.0;
+pub const PLAYER_SIZE: f32 = 28.0;
 const GRAVITY_ACCEL: f32 = 98.07;
 const TERMINAL_GRAVITY: f32 = 530.0;
+// Inside a liquid the player sinks slowly, and can swim up by holding jump
+const SWIM_GRAVITY_MULTIPLIER: f32 = 0.2;
+const SWIM_TERMINAL_GRAVITY: f32 = 96.0;
+const SWIM_SPEED: f32 = 5.0 * TILE_SIZE as f32;
 
 #[derive(Component)]
 pub struct Player {
     pub is_on_ground: bool,
     pub direction: i8,
     pub noclip: bool,
+    pub in_liquid: bool,
 }
 
 #[derive(Component)]
@@ -78,6 +87,7 @@ impl Plugin for PlayerPlugin {
             Update,
             (
                 set_player_pos_event,
+                update_in_liquid,
                 player_input,
                 apply_gravity,
                 update_grounded,
@@ -126,8 +136,10 @@ fn spawn_player(
             Name::new("Player"),
             RigidBody::Kinematic,
             player_collider.clone(),
+            CollisionLayers::new(GameLayer::Player, [GameLayer::Blocks]),
             ShapeCaster::new(player_collider, Vector::ZERO, 0.0, Dir2::NEG_Y)
-                .with_max_time_of_impact(0.625),
+                .with_max_time_of_impact(0.625)
+                .with_query_filter(SpatialQueryFilter::from_mask(GameLayer::Blocks)),
             SpriteBundle {
                 sprite: Sprite {
                     custom_size: Some(Vec2::splat(PLAYER_SIZE)),
@@ -145,6 +157,7 @@ fn spawn_player(
                 is_on_ground: false,
                 direction: 0,
                 noclip: world_info_res.is_flying,
+                in_liquid: false,
             },
             FromWorld,
         ))
@@ -250,14 +263,40 @@ fn solve_collisions(
     }
 }
 
+fn update_in_liquid(
+    mut player_query: Query<(&Transform, &mut Player)>,
+    chunks_res: Res<Chunks>,
+    registry: Res<BlockRegistry>,
+) {
+    if let Ok((player_transform, mut player)) = player_query.get_single_mut() {
+        let player_position = get_block_position(player_transform.translation.xy());
+        let chunk_position = get_chunk_position(player_position);
+        player.in_liquid = chunks_res.get(&chunk_position).is_some_and(|chunk| {
+            let index =
+                get_index_from_position(get_relative_position(player_position, chunk_position));
+            registry
+                .get(chunk.layers[PlaceMode::BLOCK as usize][index])
+                .liquid
+                .is_some()
+        });
+    }
+}
+
 fn apply_gravity(mut player_query: Query<(&mut LinearVelocity, &Player)>, time: Res<Time>) {
     if let Ok((mut player_velocity, player)) = player_query.get_single_mut() {
         if !player.noclip {
             if !player.is_on_ground {
-                if player_velocity.y > -TERMINAL_GRAVITY {
-                    player_velocity.y -= (GRAVITY_ACCEL * TILE_SIZE as f32) * time.delta_seconds();
-                } else if player_velocity.y < -TERMINAL_GRAVITY {
-                    player_velocity.y = -TERMINAL_GRAVITY;
+                let (gravity, terminal) = match player.in_liquid {
+                    true => (
+                        GRAVITY_ACCEL * SWIM_GRAVITY_MULTIPLIER,
+                        SWIM_TERMINAL_GRAVITY,
+                    ),
+                    false => (GRAVITY_ACCEL, TERMINAL_GRAVITY),
+                };
+                if player_velocity.y > -terminal {
+                    player_velocity.y -= (gravity * TILE_SIZE as f32) * time.delta_seconds();
+                } else if player_velocity.y < -terminal {
+                    player_velocity.y = -terminal;
                 }
             }
         }
@@ -304,7 +343,9 @@ fn player_input(
             || keyboard_input.pressed(KeyCode::ArrowUp)
         {
             if !player.noclip {
-                if player.is_on_ground {
+                if player.in_liquid {
+                    player_linear_velocity.y = lerp(player_linear_velocity.y, SWIM_SPEED, 0.25);
+                } else if player.is_on_ground {
                     player_linear_velocity.y = jump_force;
                 }
             }
@@ -369,6 +410,7 @@ fn darken_player(
     chunk_pos_res: Res<CurrentChunkPosition>,
     chunks_res: Res<Chunks>,
     player_settings: Res<PlayerSettings>,
+    world_time: Res<WorldTime>,
 ) {
     if let Ok(mut player_sprite) = player_sprite_query.get_single_mut() {
         if let Ok(player_transform) = player_query.get_single() {
@@ -380,9 +422,14 @@ fn darken_player(
                 );
                 let relative = get_relative_position(player_position, chunk_pos_res.position);
 
-                let light = chunk.light[get_index_from_position(relative)] as f32 / 15.0;
+                let light = combined_light(
+                    chunk,
+                    get_index_from_position(relative),
+                    world_time.sky_light_level(),
+                );
                 let c = player_settings.color.to_linear();
-                player_sprite.color = Color::srgb(c.red * light, c.green * light, c.blue * light);
+                player_sprite.color =
+                    Color::srgb(c.red * light.x, c.green * light.y, c.blue * light.z);
             }
         }
     }
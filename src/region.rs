@@ -0,0 +1,255 @@
+use bevy::prelude::*;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+// Chunks are stored in region files, each one holding REGION_WIDTH * REGION_WIDTH chunks.
+//
+// A region file starts with a header, which is an offset table with one entry for each chunk.
+// Each entry is 8 bytes long: the sector where the chunk data starts (u32) and the
+// length of the chunk data in bytes (u32). A sector of 0 means the chunk was never saved.
+// The chunk data itself starts with a byte telling how it was compressed.
+pub const REGION_WIDTH: i32 = 32;
+const REGION_AREA: usize = (REGION_WIDTH * REGION_WIDTH) as usize;
+
+const SECTOR_SIZE: u64 = 4096;
+const HEADER_ENTRY_SIZE: usize = 8;
+const HEADER_SIZE: usize = REGION_AREA * HEADER_ENTRY_SIZE;
+const HEADER_SECTORS: u32 = (HEADER_SIZE as u64 / SECTOR_SIZE) as u32;
+
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_ZLIB: u8 = 1;
+
+#[derive(Clone, Copy, Default)]
+struct HeaderEntry {
+    sector: u32,
+    length: u32,
+}
+
+impl HeaderEntry {
+    fn sector_count(&self) -> u32 {
+        sectors_needed(self.length as usize)
+    }
+}
+
+// The lock is shared between all the clones of the storage, so the async
+// tasks that save and load chunks never touch the same region file at once.
+#[derive(Resource, Clone)]
+pub struct RegionStorage {
+    directory: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl RegionStorage {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn for_world(world_name: &str) -> Self {
+        Self::new(format!("worlds/{}/regions", world_name))
+    }
+
+    fn locate(&self, chunk_pos: IVec2) -> (PathBuf, usize) {
+        let region = chunk_pos.div_euclid(IVec2::splat(REGION_WIDTH));
+        let local = chunk_pos.rem_euclid(IVec2::splat(REGION_WIDTH));
+
+        (
+            self.directory
+                .join(format!("r.{}.{}.bin", region.x, region.y)),
+            (local.x + local.y * REGION_WIDTH) as usize,
+        )
+    }
+
+    // Returns None if the chunk was never saved
+    pub fn read_chunk(&self, chunk_pos: IVec2) -> io::Result<Option<Vec<u8>>> {
+        let _guard = self.lock.lock().unwrap();
+        let (path, index) = self.locate(chunk_pos);
+
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let header = read_header(&mut file)?;
+        let entry = header[index];
+        if entry.sector == 0 || entry.length == 0 {
+            return Ok(None);
+        }
+
+        let mut data = vec![0; entry.length as usize];
+        file.seek(SeekFrom::Start(entry.sector as u64 * SECTOR_SIZE))?;
+        file.read_exact(&mut data)?;
+
+        decompress(&data).map(Some)
+    }
+
+    // The new data is always written into free sectors before the header entry is updated,
+    // so if the game stops in the middle of a save, the old version of the chunk is kept.
+    pub fn write_chunk(&self, chunk_pos: IVec2, data: &[u8]) -> io::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let (path, index) = self.locate(chunk_pos);
+
+        fs::create_dir_all(&self.directory)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+
+        if file.metadata()?.len() < HEADER_SIZE as u64 {
+            file.set_len(HEADER_SIZE as u64)?;
+        }
+
+        let header = read_header(&mut file)?;
+        let payload = compress(data)?;
+        let sector = find_free_sectors(&header, sectors_needed(payload.len()));
+
+        // Pad the data so the file always ends at a sector boundary
+        let mut padded = payload.clone();
+        padded.resize(
+            sectors_needed(payload.len()) as usize * SECTOR_SIZE as usize,
+            0,
+        );
+
+        file.seek(SeekFrom::Start(sector as u64 * SECTOR_SIZE))?;
+        file.write_all(&padded)?;
+        file.sync_data()?;
+
+        let mut entry = [0; HEADER_ENTRY_SIZE];
+        entry[0..4].copy_from_slice(&sector.to_le_bytes());
+        entry[4..8].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+
+        file.seek(SeekFrom::Start((index * HEADER_ENTRY_SIZE) as u64))?;
+        file.write_all(&entry)?;
+        file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+fn read_header(file: &mut File) -> io::Result<[HeaderEntry; REGION_AREA]> {
+    let mut bytes = vec![0; HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut bytes)?;
+
+    let mut header = [HeaderEntry::default(); REGION_AREA];
+    for (i, entry) in header.iter_mut().enumerate() {
+        let b = &bytes[i * HEADER_ENTRY_SIZE..(i + 1) * HEADER_ENTRY_SIZE];
+        entry.sector = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        entry.length = u32::from_le_bytes([b[4], b[5], b[6], b[7]]);
+    }
+
+    Ok(header)
+}
+
+fn sectors_needed(length: usize) -> u32 {
+    (length as u64).div_ceil(SECTOR_SIZE) as u32
+}
+
+// Finds the first gap between the used sectors that fits the chunk data,
+// or the end of the file if there is none.
+// The sectors of the chunk being replaced count as used too.
+fn find_free_sectors(header: &[HeaderEntry; REGION_AREA], count: u32) -> u32 {
+    let mut used: Vec<(u32, u32)> = header
+        .iter()
+        .filter(|entry| entry.sector != 0)
+        .map(|entry| (entry.sector, entry.sector + entry.sector_count()))
+        .collect();
+    used.sort();
+
+    let mut cursor = HEADER_SECTORS;
+    for (start, end) in used {
+        if start >= cursor && start - cursor >= count {
+            return cursor;
+        }
+        cursor = cursor.max(end);
+    }
+
+    cursor
+}
+
+fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(vec![COMPRESSION_ZLIB], Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    match data.first() {
+        Some(&COMPRESSION_NONE) => Ok(data[1..].to_vec()),
+        Some(&COMPRESSION_ZLIB) => {
+            let mut out = Vec::new();
+            ZlibDecoder::new(&data[1..]).read_to_end(&mut out)?;
+            Ok(out)
+        }
+        Some(other) => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unknown compression type {}", other),
+        )),
+        None => Err(io::Error::new(ErrorKind::InvalidData, "empty chunk data")),
+    }
+}
+
+// Moves the chunks of worlds saved with the old one file per chunk format
+// (worlds/<name>/chunks/<position>.bin) into region files.
+// Every chunk file is deleted after it's moved, so this only does work once.
+pub fn migrate_chunk_files(world_name: &str, storage: &RegionStorage) {
+    let chunks_dir = PathBuf::from(format!("worlds/{}/chunks", world_name));
+    let Ok(entries) = fs::read_dir(&chunks_dir) else {
+        return;
+    };
+
+    info!(
+        "Found old chunk files in '{}', moving them into region files...",
+        world_name
+    );
+
+    let mut migrated = 0;
+    let mut failed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(chunk_pos) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(parse_chunk_position)
+        else {
+            warn!("Skipping unknown file in chunks folder: {}", path.display());
+            continue;
+        };
+
+        let result = fs::read(&path)
+            .and_then(|bytes| storage.write_chunk(chunk_pos, &bytes))
+            .and_then(|_| fs::remove_file(&path));
+
+        match result {
+            Ok(_) => migrated += 1,
+            Err(e) => {
+                error!("Failed to migrate chunk at {}: {}", chunk_pos, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed == 0 {
+        let _ = fs::remove_dir(&chunks_dir);
+    }
+
+    info!(
+        "Moved {} chunks into region files ({} failed)",
+        migrated, failed
+    );
+}
+
+// Chunk files were named after the Display of IVec2, which looks like "[x, y]"
+fn parse_chunk_position(stem: &str) -> Option<IVec2> {
+    let (x, y) = stem.strip_prefix('[')?.strip_suffix(']')?.split_once(',')?;
+    Some(IVec2::new(x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
@@ -0,0 +1,169 @@
+use std::fs;
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::{BlockRegistry, BlockType},
+    chunk::{Chunk, PlaceMode},
+    chunk_format::{palette_blocks, BlockPalette},
+    utils::{get_chunk_position, get_index_from_position, get_relative_position},
+};
+
+// One block of a structure, in global block coordinates
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct StructureBlock {
+    pub position: IVec2,
+    pub layer: usize,
+    pub block: BlockType,
+}
+
+// A structure made of blocks relative to its origin,
+// like a tree or a small building. The origin is the block right above the ground.
+pub struct Structure {
+    pub blocks: Vec<(IVec2, PlaceMode, BlockType)>,
+}
+
+impl Structure {
+    pub fn tree(trunk_height: i32, log: BlockType, leaves: BlockType) -> Self {
+        let mut blocks = Vec::new();
+        for y in 0..trunk_height {
+            blocks.push((IVec2::new(0, y), PlaceMode::BLOCK, log));
+        }
+
+        // A round canopy around the top of the trunk
+        let top = trunk_height - 1;
+        for y in -1..=2 {
+            let radius = if y >= 1 { 1 } else { 2 };
+            for x in -radius..=radius {
+                if y == 2 && x != 0 {
+                    continue;
+                }
+                blocks.push((IVec2::new(x, top + y), PlaceMode::BLOCK, leaves));
+            }
+        }
+
+        return Self { blocks };
+    }
+
+    pub fn place(&self, origin: IVec2, out: &mut Vec<StructureBlock>) {
+        for &(offset, layer, block) in self.blocks.iter() {
+            out.push(StructureBlock {
+                position: origin + offset,
+                layer: layer as usize,
+                block,
+            });
+        }
+    }
+}
+
+// Blocks of structures that go into chunks that weren't loaded when the structure was generated.
+// They are written into the chunk when it's loaded or generated, and are kept in
+// the world folder in the meantime.
+#[derive(Resource, Default)]
+pub struct PendingBlocks(pub HashMap<IVec2, Vec<StructureBlock>>);
+
+impl PendingBlocks {
+    pub fn push(&mut self, block: StructureBlock) {
+        self.0
+            .entry(get_chunk_position(block.position))
+            .or_default()
+            .push(block);
+    }
+
+    pub fn take(&mut self, chunk_pos: IVec2) -> Vec<StructureBlock> {
+        return self.0.remove(&chunk_pos).unwrap_or_default();
+    }
+
+    pub fn load(world_name: &str, registry: &BlockRegistry) -> Self {
+        let Ok(bytes) = fs::read(pending_blocks_path(world_name)) else {
+            return Self::default();
+        };
+        let file = match bincode::deserialize::<PendingBlocksFile>(&bytes) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Could not read the pending structure blocks: {}", e);
+                return Self::default();
+            }
+        };
+
+        let palette = palette_blocks(&file.palette, registry, "the pending structure blocks");
+        let mut pending = Self::default();
+        for (chunk_pos, mut blocks) in file.chunks {
+            for block in blocks.iter_mut() {
+                block.block = palette
+                    .get(block.block.0 as usize)
+                    .copied()
+                    .unwrap_or(BlockType::AIR);
+            }
+            // The blocks that are no longer in the registry are left out
+            blocks.retain(|block| block.block != BlockType::AIR);
+            pending.0.insert(chunk_pos, blocks);
+        }
+        return pending;
+    }
+
+    pub fn save(&self, world_name: &str, registry: &BlockRegistry) {
+        let mut palette = BlockPalette::default();
+        let chunks: Vec<(IVec2, Vec<StructureBlock>)> = self
+            .0
+            .iter()
+            .map(|(chunk_pos, blocks)| {
+                let blocks = blocks
+                    .iter()
+                    .map(|block| StructureBlock {
+                        block: BlockType(palette.local_id(block.block, registry)),
+                        ..*block
+                    })
+                    .collect();
+                (*chunk_pos, blocks)
+            })
+            .collect();
+        let file = PendingBlocksFile {
+            palette: palette.names,
+            chunks,
+        };
+        match bincode::serialize(&file) {
+            Ok(bytes) => {
+                if let Err(e) = fs::write(pending_blocks_path(world_name), bytes) {
+                    error!("Error saving the pending structure blocks: {}", e);
+                }
+            }
+            Err(e) => error!("Could not serialize the pending structure blocks: {}", e),
+        }
+    }
+}
+
+// The blocks are saved as indices into the palette, which has the names of the blocks
+#[derive(Serialize, Deserialize)]
+struct PendingBlocksFile {
+    palette: Vec<String>,
+    chunks: Vec<(IVec2, Vec<StructureBlock>)>,
+}
+
+fn pending_blocks_path(world_name: &str) -> String {
+    format!("worlds/{}/pending_blocks.bin", world_name)
+}
+
+// Structures only grow into air, except that logs replace leaves.
+// This way the result is the same no matter in which order the chunks are generated,
+// so worlds with the same seed always end up with the same trees.
+// Returns true if the block was written.
+pub fn apply_structure_block(
+    chunk: &mut Chunk,
+    chunk_pos: IVec2,
+    block: &StructureBlock,
+    registry: &BlockRegistry,
+) -> bool {
+    let index = get_index_from_position(get_relative_position(block.position, chunk_pos));
+    let existing = chunk.layers[block.layer][index];
+
+    let replaceable = existing == BlockType::AIR
+        || (Some(existing) == registry.id("leaves") && Some(block.block) != registry.id("leaves"));
+    if !replaceable {
+        return false;
+    }
+
+    chunk.layers[block.layer][index] = block.block;
+    return true;
+}
@@ -0,0 +1,466 @@
+use std::sync::{Arc, RwLock};
+
+use bevy::{prelude::*, utils::HashMap};
+use noise::{Fbm, NoiseFn, Perlin};
+
+use crate::{
+    biome::{Biome, BIOMES},
+    block::{BlockRegistry, BlockType},
+    chunk::CHUNK_WIDTH,
+    structure::Structure,
+    utils::get_index_from_position,
+    world_generator::{ChunkGenerationResult, WorldGenerator},
+};
+
+// How many blocks wide the hills are
+const TERRAIN_SCALE: f64 = 96.0;
+// How many blocks wide the biomes are, roughly
+const BIOME_SCALE: f64 = 400.0;
+// The heights of the biomes are blended over this many blocks on each side of a column,
+// so there are no cliffs where two biomes meet
+const BIOME_BLEND_RADIUS: i32 = 16;
+const BIOME_BLEND_STEP: i32 = 4;
+// Average depth of the filler blocks under the surface, before the stone starts
+const DIRT_DEPTH: f64 = 4.0;
+// Caves don't get closer to the surface than this, so the ground doesn't look like swiss cheese
+const CAVE_MIN_DEPTH: i32 = 6;
+// How wide the worm caves are, as a band of the cave noise around 0
+const CAVE_WIDTH: f64 = 0.07;
+// Caverns open where the cavern noise is above this
+const CAVERN_THRESHOLD: f64 = 0.45;
+// The surface is split into cells this many blocks wide, with at most one tree in each,
+// so the trees never grow into each other
+const TREE_SPACING: i32 = 8;
+
+// An ore replaces the stone where its noise is above the threshold,
+// and only below max_y so the rare ores are deeper
+struct OreVein {
+    block: BlockType,
+    max_y: i32,
+    threshold: f64,
+}
+
+// The generator of the "default" worlds, with biomes, caves, ores and trees
+pub struct DefaultGenerator {
+    registry: BlockRegistry,
+    // The noise only depends on the seed, so it's built once for each seed
+    terrains: RwLock<HashMap<u64, Arc<TerrainGenerator>>>,
+}
+
+impl DefaultGenerator {
+    pub fn new(registry: &BlockRegistry) -> Self {
+        Self {
+            registry: registry.clone(),
+            terrains: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn terrain(&self, seed: u64) -> Arc<TerrainGenerator> {
+        if let Some(terrain) = self.terrains.read().unwrap().get(&seed) {
+            return terrain.clone();
+        }
+        // Chunk tasks that raced to build it keep the first one, so they all share the same noise
+        let terrain = Arc::new(TerrainGenerator::new(seed, &self.registry));
+        return self
+            .terrains
+            .write()
+            .unwrap()
+            .entry(seed)
+            .or_insert(terrain)
+            .clone();
+    }
+}
+
+impl WorldGenerator for DefaultGenerator {
+    fn generate(&self, chunk_pos: IVec2, seed: u64) -> ChunkGenerationResult {
+        return self.terrain(seed).generate_chunk(chunk_pos);
+    }
+}
+
+// The terrain only depends on the seed, so two worlds with the same seed are identical.
+struct TerrainGenerator {
+    seed: u64,
+    height_noise: Fbm<Perlin>,
+    dirt_noise: Fbm<Perlin>,
+    temperature_noise: Fbm<Perlin>,
+    humidity_noise: Fbm<Perlin>,
+    // The surface and filler blocks of each biome
+    biome_blocks: [(BlockType, BlockType); BIOMES.len()],
+    stone: BlockType,
+    log: BlockType,
+    leaves: BlockType,
+    cave_noise: Fbm<Perlin>,
+    cavern_noise: Fbm<Perlin>,
+    ore_noise: Perlin,
+    ores: Vec<OreVein>,
+}
+
+impl TerrainGenerator {
+    fn new(seed: u64, registry: &BlockRegistry) -> Self {
+        Self {
+            seed,
+            height_noise: Fbm::<Perlin>::new(noise_seed(seed, 0)),
+            dirt_noise: Fbm::<Perlin>::new(noise_seed(seed, 6)),
+            temperature_noise: Fbm::<Perlin>::new(noise_seed(seed, 4)),
+            humidity_noise: Fbm::<Perlin>::new(noise_seed(seed, 5)),
+            biome_blocks: BIOMES.map(|biome| {
+                let settings = biome.settings();
+                (
+                    registry.id(settings.surface).unwrap_or_default(),
+                    registry.id(settings.filler).unwrap_or_default(),
+                )
+            }),
+            stone: registry.id("stone").unwrap_or_default(),
+            log: registry.id("tree_log").unwrap_or_default(),
+            leaves: registry.id("leaves").unwrap_or_default(),
+            cave_noise: Fbm::<Perlin>::new(noise_seed(seed, 1)),
+            cavern_noise: Fbm::<Perlin>::new(noise_seed(seed, 2)),
+            ore_noise: Perlin::new(noise_seed(seed, 3)),
+            ores: [
+                ("coal_ore", 0, 0.45),
+                ("iron_ore", -16, 0.5),
+                ("gold_ore", -48, 0.55),
+            ]
+            .into_iter()
+            .filter_map(|(name, max_y, threshold)| {
+                Some(OreVein {
+                    block: registry.id(name)?,
+                    max_y,
+                    threshold,
+                })
+            })
+            .collect(),
+        }
+    }
+
+    // Hot and dry columns are deserts, cold ones are mountains, and humid ones are forests.
+    // The noise is sampled between the lattice lines, where Perlin noise is always 0
+    fn biome_at(&self, x: i32) -> Biome {
+        let temperature = self.temperature_noise.get([x as f64 / BIOME_SCALE, 0.5]);
+        let humidity = self.humidity_noise.get([x as f64 / BIOME_SCALE, 0.5]);
+
+        if temperature < -0.3 {
+            return Biome::Mountains;
+        } else if temperature > 0.25 && humidity < 0.1 {
+            return Biome::Desert;
+        } else if humidity > 0.15 {
+            return Biome::Forest;
+        }
+        return Biome::Plains;
+    }
+
+    // The offset and amplitude of the hills are a weighted average of the biomes around the column
+    fn surface_height(&self, x: i32) -> i32 {
+        let mut offset = 0.0;
+        let mut amplitude = 0.0;
+        let mut total_weight = 0.0;
+        for dx in (-BIOME_BLEND_RADIUS..=BIOME_BLEND_RADIUS).step_by(BIOME_BLEND_STEP as usize) {
+            let weight = (BIOME_BLEND_RADIUS + 1 - dx.abs()) as f64;
+            let settings = self.biome_at(x + dx).settings();
+            offset += settings.height_offset * weight;
+            amplitude += settings.height_amplitude * weight;
+            total_weight += weight;
+        }
+
+        let noise = self.height_noise.get([x as f64 / TERRAIN_SCALE, 0.5]);
+        return ((offset + noise * amplitude) / total_weight).floor() as i32;
+    }
+
+    fn dirt_depth(&self, x: i32) -> i32 {
+        let noise = self.dirt_noise.get([x as f64 / CHUNK_WIDTH as f64, 0.5]);
+        return (DIRT_DEPTH + noise * 2.0).round() as i32;
+    }
+
+    // Worm caves follow the lines where the noise crosses 0,
+    // and caverns are the peaks of a second noise, which get bigger with depth
+    fn is_cave(&self, x: i32, y: i32, surface: i32) -> bool {
+        if y > surface - CAVE_MIN_DEPTH {
+            return false;
+        }
+
+        let worm = self.cave_noise.get([x as f64 / 48.0, y as f64 / 32.0]);
+        if worm.abs() < CAVE_WIDTH {
+            return true;
+        }
+
+        let depth_bonus = ((surface - y) as f64 / 256.0).min(0.15);
+        let cavern = self.cavern_noise.get([x as f64 / 64.0, y as f64 / 40.0]);
+        return cavern > CAVERN_THRESHOLD - depth_bonus;
+    }
+
+    fn ore_at(&self, x: i32, y: i32) -> Option<BlockType> {
+        for (i, ore) in self.ores.iter().enumerate() {
+            if y > ore.max_y {
+                continue;
+            }
+            // Each ore samples its own slice of the noise, so their veins are independent
+            let noise = self
+                .ore_noise
+                .get([x as f64 / 4.3, y as f64 / 4.3, i as f64 * 10.5]);
+            if noise > ore.threshold {
+                return Some(ore.block);
+            }
+        }
+        return None;
+    }
+
+    // Returns the height of the trunk if a tree grows on this column
+    fn tree_at(&self, x: i32, biome: Biome) -> Option<i32> {
+        let cell = x.div_euclid(TREE_SPACING);
+        let hash = hash_column(self.seed, cell);
+        if hash % 100 >= biome.settings().tree_chance {
+            return None;
+        }
+
+        // Keep the trees far enough from the cell borders for their leaves to stay inside the cell
+        let offset = 2 + ((hash >> 8) % (TREE_SPACING as u64 - 4)) as i32;
+        if x != cell * TREE_SPACING + offset {
+            return None;
+        }
+        return Some(4 + ((hash >> 16) % 3) as i32);
+    }
+
+    fn generate_chunk(&self, chunk_pos: IVec2) -> ChunkGenerationResult {
+        let stone = self.stone;
+        let mut result = ChunkGenerationResult::default();
+
+        for x in 0..CHUNK_WIDTH {
+            let global_x = (chunk_pos.x * CHUNK_WIDTH as i32) + x as i32;
+            let biome = self.biome_at(global_x);
+            let (surface_block, filler) = self.biome_blocks[biome as usize];
+            let surface = self.surface_height(global_x);
+            let dirt_depth = self.dirt_depth(global_x);
+            result.biomes[x] = biome;
+
+            // A tree belongs to the chunk where its trunk starts
+            if let Some(trunk_height) = self.tree_at(global_x, biome) {
+                let origin = IVec2::new(global_x, surface + 1);
+                if origin.y.div_euclid(CHUNK_WIDTH as i32) == chunk_pos.y {
+                    Structure::tree(trunk_height, self.log, self.leaves)
+                        .place(origin, &mut result.structures);
+                }
+            }
+
+            for y in 0..CHUNK_WIDTH {
+                let global_y = (chunk_pos.y * CHUNK_WIDTH as i32) + y as i32;
+                let i = get_index_from_position(UVec2::new(x as u32, y as u32));
+
+                let block = if global_y > surface {
+                    continue;
+                } else if global_y == surface {
+                    surface_block
+                } else if global_y > surface - dirt_depth {
+                    filler
+                } else {
+                    stone
+                };
+
+                // The walls are left behind the caves as their backdrop
+                result.walls[i] = block;
+                if self.is_cave(global_x, global_y, surface) {
+                    continue;
+                }
+                result.blocks[i] = if block == stone {
+                    self.ore_at(global_x, global_y).unwrap_or(stone)
+                } else {
+                    block
+                };
+            }
+        }
+        return result;
+    }
+}
+
+// The noise only takes 32 bit seeds, so all 64 bits of the world seed are mixed into them.
+// Each noise gets its own salt, so they don't all look the same.
+fn noise_seed(seed: u64, salt: i32) -> u32 {
+    let hash = hash_column(seed, salt);
+    return (hash ^ (hash >> 32)) as u32;
+}
+
+// Gives a random looking but deterministic number for each column of the world
+fn hash_column(seed: u64, x: i32) -> u64 {
+    let mut hash = seed ^ (x as i64 as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    return hash ^ (hash >> 31);
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::utils::HashSet;
+
+    use super::*;
+    use crate::{
+        chunk::{Chunk, CHUNK_AREA},
+        liquid::LiquidCell,
+        structure::{apply_structure_block, PendingBlocks},
+        utils::get_chunk_position,
+    };
+
+    const SEEDS: [u64; 4] = [0, 12345, i64::MAX as u64 + 1, u64::MAX];
+
+    fn registry() -> BlockRegistry {
+        return BlockRegistry::from_toml(include_str!("../assets/blocks.toml")).unwrap();
+    }
+
+    // Chunks from below the caves to above the hills, on both sides of x = 0
+    fn chunk_positions() -> Vec<IVec2> {
+        let mut positions = Vec::new();
+        for y in -4..4 {
+            for x in -4..4 {
+                positions.push(IVec2::new(x, y));
+            }
+        }
+        return positions;
+    }
+
+    fn structure_blocks(result: &ChunkGenerationResult) -> Vec<(IVec2, usize, BlockType)> {
+        return result
+            .structures
+            .iter()
+            .map(|block| (block.position, block.layer, block.block))
+            .collect();
+    }
+
+    // Adds the chunks one by one, like the chunk manager does when they are generated:
+    // the pending blocks of other chunks go into a new chunk, and its structures into the loaded ones
+    fn generate_in_order(
+        generator: &DefaultGenerator,
+        seed: u64,
+        positions: &[IVec2],
+        registry: &BlockRegistry,
+    ) -> HashMap<IVec2, Chunk> {
+        let mut chunks: HashMap<IVec2, Chunk> = HashMap::new();
+        let mut pending = PendingBlocks::default();
+        for &chunk_pos in positions {
+            let result = generator.generate(chunk_pos, seed);
+            let mut chunk = Chunk {
+                layers: [result.walls, result.blocks],
+                light: [0; CHUNK_AREA],
+                block_light: [[0; 3]; CHUNK_AREA],
+                dirty: true,
+                last_modified: 0,
+                neighbors_modified: [0; 4],
+                inhabited_time: 0,
+                biomes: result.biomes,
+                block_entities: HashMap::new(),
+                liquid: [LiquidCell::default(); CHUNK_AREA],
+                scheduled_ticks: Vec::new(),
+                signal: [[0; CHUNK_AREA]; 2],
+                active_liquids: HashSet::new(),
+            };
+            for block in pending.take(chunk_pos) {
+                apply_structure_block(&mut chunk, chunk_pos, &block, registry);
+            }
+            chunks.insert(chunk_pos, chunk);
+
+            for block in result.structures.iter() {
+                let target_pos = get_chunk_position(block.position);
+                match chunks.get_mut(&target_pos) {
+                    Some(target) => {
+                        apply_structure_block(target, target_pos, block, registry);
+                    }
+                    None => pending.push(*block),
+                }
+            }
+        }
+        return chunks;
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_chunks() {
+        let registry = registry();
+        let first = DefaultGenerator::new(&registry);
+        let second = DefaultGenerator::new(&registry);
+
+        for seed in SEEDS {
+            for chunk_pos in chunk_positions() {
+                let a = first.generate(chunk_pos, seed);
+                let b = second.generate(chunk_pos, seed);
+                assert_eq!(
+                    a.blocks, b.blocks,
+                    "blocks of {} with seed {}",
+                    chunk_pos, seed
+                );
+                assert_eq!(
+                    a.walls, b.walls,
+                    "walls of {} with seed {}",
+                    chunk_pos, seed
+                );
+                assert_eq!(
+                    a.biomes, b.biomes,
+                    "biomes of {} with seed {}",
+                    chunk_pos, seed
+                );
+                assert_eq!(
+                    structure_blocks(&a),
+                    structure_blocks(&b),
+                    "structures of {} with seed {}",
+                    chunk_pos,
+                    seed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn different_seeds_give_different_chunks() {
+        let registry = registry();
+        let generator = DefaultGenerator::new(&registry);
+
+        for (i, first_seed) in SEEDS.iter().enumerate() {
+            for second_seed in SEEDS.iter().skip(i + 1) {
+                let differs = chunk_positions().into_iter().any(|chunk_pos| {
+                    generator.generate(chunk_pos, *first_seed).blocks
+                        != generator.generate(chunk_pos, *second_seed).blocks
+                });
+                assert!(
+                    differs,
+                    "the seeds {} and {} gave the same chunks",
+                    first_seed, second_seed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn generation_order_does_not_change_the_trees() {
+        let registry = registry();
+        let generator = DefaultGenerator::new(&registry);
+        // Wide enough for some trees to grow across the top of a chunk
+        let mut positions = Vec::new();
+        for y in -2..4 {
+            for x in -16..16 {
+                positions.push(IVec2::new(x, y));
+            }
+        }
+        let reversed: Vec<IVec2> = positions.iter().rev().copied().collect();
+
+        for seed in SEEDS {
+            // Otherwise the chunks can't disagree about anything
+            let crosses_chunks = positions.iter().any(|chunk_pos| {
+                generator
+                    .generate(*chunk_pos, seed)
+                    .structures
+                    .iter()
+                    .any(|block| get_chunk_position(block.position) != *chunk_pos)
+            });
+            assert!(
+                crosses_chunks,
+                "no tree grows into another chunk with seed {}",
+                seed
+            );
+
+            let upwards = generate_in_order(&generator, seed, &positions, &registry);
+            let downwards = generate_in_order(&generator, seed, &reversed, &registry);
+            for chunk_pos in positions.iter() {
+                assert_eq!(
+                    upwards[chunk_pos].layers, downwards[chunk_pos].layers,
+                    "layers of {} with seed {}",
+                    chunk_pos, seed
+                );
+            }
+        }
+    }
+}
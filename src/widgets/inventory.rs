@@ -0,0 +1,709 @@
+use bevy::{prelude::*, ui::FocusPolicy, window::PrimaryWindow};
+use sickle_ui::prelude::*;
+
+use crate::block::BlockRegistry;
+use crate::block_entity::{get_block_entity, get_block_entity_mut, BlockEntity};
+use crate::chunk::TILE_SIZE;
+use crate::chunk_manager::Chunks;
+use crate::dropped_item::DropItem;
+use crate::item_container::{Item, ItemContainer, ItemStack};
+use crate::player::Player;
+use crate::world::PlayerInventory;
+use crate::{GamePauseState, GameState};
+
+const SLOT_SIZE: f32 = 42.0;
+const ICON_SIZE: f32 = TILE_SIZE as f32;
+
+// The keys that select the hotbar slots, from the first to the last
+const HOTBAR_KEYS: [KeyCode; 10] = [
+    KeyCode::Digit1,
+    KeyCode::Digit2,
+    KeyCode::Digit3,
+    KeyCode::Digit4,
+    KeyCode::Digit5,
+    KeyCode::Digit6,
+    KeyCode::Digit7,
+    KeyCode::Digit8,
+    KeyCode::Digit9,
+    KeyCode::Digit0,
+];
+
+// Opens the inventory together with the container of the block entity at the position
+#[derive(Event)]
+pub struct OpenContainer {
+    pub position: IVec2,
+}
+
+// Whether the whole inventory is shown, instead of only the hotbar
+#[derive(Resource, Default)]
+pub struct InventoryOpen(pub bool);
+
+// The position of the block entity whose container is shown above the inventory
+#[derive(Resource, Default)]
+pub struct OpenedContainer(pub Option<IVec2>);
+
+// The stack that follows the mouse while the inventory is open
+#[derive(Resource, Default)]
+pub struct HeldStack {
+    pub stack: Option<ItemStack>,
+    // The slot the stack was picked up from while the mouse button is still down,
+    // so releasing the button over another slot drops the stack there
+    dragged_from: Option<InventorySlot>,
+}
+
+#[derive(Resource)]
+struct InventoryTextures {
+    slot: Handle<Image>,
+    selected_slot: Handle<Image>,
+    blocks: Handle<Image>,
+    blocks_layout: Handle<TextureAtlasLayout>,
+}
+
+#[derive(Component)]
+struct InventoryPanel;
+
+#[derive(Component)]
+struct ContainerPanel;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SlotOwner {
+    Player,
+    // The container of the OpenedContainer
+    BlockEntity,
+}
+
+#[derive(Component, Clone, Copy, PartialEq, Eq)]
+struct InventorySlot {
+    owner: SlotOwner,
+    index: usize,
+}
+
+#[derive(Component)]
+struct InventorySlotIcon;
+
+#[derive(Component)]
+struct InventorySlotAmount;
+
+#[derive(Component)]
+struct HeldItemIcon;
+
+pub struct InventoryWidgetPlugin;
+
+impl Plugin for InventoryWidgetPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<OpenContainer>();
+
+        app.init_resource::<InventoryOpen>();
+        app.init_resource::<OpenedContainer>();
+        app.init_resource::<HeldStack>();
+
+        app.add_systems(OnEnter(GameState::Game), setup);
+        app.add_systems(OnEnter(GamePauseState::Paused), put_away_held_stack);
+        app.add_systems(OnExit(GameState::Game), put_away_held_stack);
+        app.add_systems(
+            Update,
+            (
+                (
+                    hotbar_number_keys,
+                    open_container,
+                    toggle_inventory,
+                    inventory_mouse_input,
+                )
+                    .chain()
+                    .run_if(in_state(GamePauseState::Running)),
+                update_inventory_panels,
+                update_inventory_slots,
+                update_held_item,
+            )
+                .chain()
+                .run_if(in_state(GameState::Game)),
+        );
+    }
+}
+
+pub trait InventoryWidgetExt {
+    fn inventory(&mut self, inventory: &PlayerInventory) -> UiBuilder<Entity>;
+}
+
+impl InventoryWidgetExt for UiBuilder<'_, Entity> {
+    fn inventory(&mut self, inventory: &PlayerInventory) -> UiBuilder<Entity> {
+        return self.container(
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    flex_direction: FlexDirection::Column,
+                    justify_content: JustifyContent::FlexEnd,
+                    align_items: AlignItems::Center,
+                    row_gap: Val::Px(8.0),
+                    padding: UiRect::bottom(Val::Px(8.0)),
+                    ..default()
+                },
+                ..default()
+            },
+            |widget| {
+                widget.named("Inventory Widget");
+
+                // The slots are spawned when a container is opened, as containers can have any size
+                widget
+                    .spawn((panel_bundle(), ContainerPanel))
+                    .named("Container Panel");
+
+                // The rows after the hotbar, which are only shown while the inventory is open
+                widget.container((panel_bundle(), InventoryPanel), |panel| {
+                    panel.named("Inventory Panel");
+                    for row in 1..inventory.rows {
+                        panel.row(|slots| {
+                            for column in 0..inventory.columns {
+                                inventory_slot(
+                                    slots,
+                                    InventorySlot {
+                                        owner: SlotOwner::Player,
+                                        index: inventory.index(row, column),
+                                    },
+                                );
+                            }
+                        });
+                    }
+                });
+
+                widget.row(|hotbar| {
+                    hotbar.named("Hotbar");
+                    for column in 0..inventory.columns {
+                        inventory_slot(
+                            hotbar,
+                            InventorySlot {
+                                owner: SlotOwner::Player,
+                                index: inventory.index(0, column),
+                            },
+                        );
+                    }
+                });
+
+                widget.spawn((
+                    ImageBundle {
+                        style: Style {
+                            position_type: PositionType::Absolute,
+                            width: Val::Px(ICON_SIZE),
+                            height: Val::Px(ICON_SIZE),
+                            ..default()
+                        },
+                        focus_policy: FocusPolicy::Pass,
+                        visibility: Visibility::Hidden,
+                        ..default()
+                    },
+                    HeldItemIcon,
+                ));
+            },
+        );
+    }
+}
+
+fn panel_bundle() -> NodeBundle {
+    return NodeBundle {
+        style: Style {
+            display: Display::None,
+            flex_direction: FlexDirection::Column,
+            padding: UiRect::all(Val::Px(4.0)),
+            ..default()
+        },
+        background_color: Color::srgba(0.0, 0.0, 0.0, 0.5).into(),
+        ..default()
+    };
+}
+
+fn inventory_slot(builder: &mut UiBuilder<Entity>, slot: InventorySlot) {
+    builder.container(
+        (
+            ImageBundle {
+                style: Style {
+                    width: Val::Px(SLOT_SIZE),
+                    height: Val::Px(SLOT_SIZE),
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                ..default()
+            },
+            Interaction::default(),
+            slot,
+        ),
+        |slot_node| {
+            slot_node.named(format!("Inventory Slot {}", slot.index));
+            slot_node.spawn((
+                ImageBundle {
+                    style: Style {
+                        width: Val::Px(ICON_SIZE),
+                        height: Val::Px(ICON_SIZE),
+                        ..default()
+                    },
+                    focus_policy: FocusPolicy::Pass,
+                    visibility: Visibility::Hidden,
+                    ..default()
+                },
+                InventorySlotIcon,
+            ));
+            slot_node
+                .spawn((
+                    TextBundle::from_section(
+                        "",
+                        TextStyle {
+                            font_size: 16.0,
+                            color: Color::WHITE,
+                            ..default()
+                        },
+                    )
+                    .with_style(Style {
+                        position_type: PositionType::Absolute,
+                        right: Val::Px(4.0),
+                        bottom: Val::Px(2.0),
+                        ..default()
+                    }),
+                    InventorySlotAmount,
+                ))
+                .style()
+                .font("fonts/nokiafc22.ttf".to_string());
+        },
+    );
+}
+
+fn setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
+    registry: Res<BlockRegistry>,
+) {
+    let layout = TextureAtlasLayout::from_grid(
+        UVec2::splat(TILE_SIZE as u32),
+        registry.atlas_size as u32,
+        1,
+        None,
+        None,
+    );
+
+    commands.insert_resource(InventoryTextures {
+        slot: asset_server.load("textures/itemSlot.png"),
+        selected_slot: asset_server.load("textures/itemSlot_selected.png"),
+        blocks: asset_server.load("textures/blocks.png"),
+        blocks_layout: texture_atlas_layouts.add(layout),
+    });
+    commands.insert_resource(InventoryOpen(false));
+    commands.insert_resource(OpenedContainer(None));
+    commands.insert_resource(HeldStack::default());
+}
+
+fn hotbar_number_keys(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut inventory_res: ResMut<PlayerInventory>,
+) {
+    for (slot, key) in HOTBAR_KEYS.iter().enumerate() {
+        if keyboard_input.just_pressed(*key) && slot < inventory_res.columns {
+            inventory_res.selected_slot = slot;
+        }
+    }
+}
+
+fn open_container(
+    mut open_container_ev: EventReader<OpenContainer>,
+    mut inventory_open: ResMut<InventoryOpen>,
+    mut opened: ResMut<OpenedContainer>,
+    chunks_res: Res<Chunks>,
+) {
+    for ev in open_container_ev.read() {
+        let has_container = get_block_entity(&chunks_res, ev.position)
+            .is_some_and(|entity| entity.container().is_some());
+        if has_container && opened.0 != Some(ev.position) {
+            opened.0 = Some(ev.position);
+            inventory_open.0 = true;
+        }
+    }
+}
+
+fn toggle_inventory(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut inventory_open: ResMut<InventoryOpen>,
+    mut opened: ResMut<OpenedContainer>,
+    mut held: ResMut<HeldStack>,
+    mut inventory_res: ResMut<PlayerInventory>,
+    mut drop_item_ev: EventWriter<DropItem>,
+    player_q: Query<&Transform, With<Player>>,
+    chunks_res: Res<Chunks>,
+) {
+    // The container closes by itself when its block is broken or unloaded
+    if let Some(position) = opened.0 {
+        if get_block_entity(&chunks_res, position).is_none() {
+            opened.0 = None;
+        }
+    }
+
+    if !keyboard_input.just_pressed(KeyCode::KeyE) {
+        return;
+    }
+
+    inventory_open.0 = !inventory_open.0;
+    if inventory_open.0 {
+        return;
+    }
+    opened.0 = None;
+
+    return_held_stack(
+        &mut held,
+        &mut inventory_res,
+        player_q.get_single().ok(),
+        &mut drop_item_ev,
+    );
+}
+
+// The held stack can't be carried around with the inventory closed, or be saved with the world,
+// so it goes back into the inventory, or is dropped if it came from a container and doesn't fit
+pub fn return_held_stack(
+    held: &mut HeldStack,
+    inventory: &mut PlayerInventory,
+    player_transform: Option<&Transform>,
+    drop_item_ev: &mut EventWriter<DropItem>,
+) {
+    held.dragged_from = None;
+    let Some(rest) = held.stack.take().and_then(|stack| inventory.insert(stack)) else {
+        return;
+    };
+    if let Some(player_transform) = player_transform {
+        drop_item_ev.send(DropItem {
+            position: player_transform.translation.xy(),
+            stack: rest,
+        });
+    }
+}
+
+fn put_away_held_stack(
+    mut held: ResMut<HeldStack>,
+    mut inventory_res: ResMut<PlayerInventory>,
+    mut drop_item_ev: EventWriter<DropItem>,
+    player_q: Query<&Transform, With<Player>>,
+) {
+    return_held_stack(
+        &mut held,
+        &mut inventory_res,
+        player_q.get_single().ok(),
+        &mut drop_item_ev,
+    );
+}
+
+fn inventory_mouse_input(
+    mouse_button_input: Res<ButtonInput<MouseButton>>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    slots_q: Query<(&InventorySlot, &Interaction)>,
+    inventory_open: Res<InventoryOpen>,
+    opened: Res<OpenedContainer>,
+    mut inventory_res: ResMut<PlayerInventory>,
+    mut chunks_res: ResMut<Chunks>,
+    mut held: ResMut<HeldStack>,
+) {
+    if !inventory_open.0 {
+        return;
+    }
+
+    let hovered = slots_q
+        .iter()
+        .find(|(_, interaction)| **interaction != Interaction::None)
+        .map(|(slot, _)| *slot);
+
+    let mut dropped_drag = false;
+    if mouse_button_input.just_released(MouseButton::Left) {
+        if let (Some(from), Some(slot)) = (held.dragged_from.take(), hovered) {
+            dropped_drag = from != slot;
+        }
+    }
+
+    let Some(slot) = hovered else {
+        return;
+    };
+    let left_pressed = mouse_button_input.just_pressed(MouseButton::Left);
+    let right_pressed = mouse_button_input.just_pressed(MouseButton::Right);
+    if !dropped_drag && !left_pressed && !right_pressed {
+        return;
+    }
+
+    // The chunks are only borrowed mutably on clicks, so they aren't marked as changed on every frame
+    let player_items = &mut inventory_res.items;
+    let container_items = opened
+        .0
+        .and_then(|position| get_block_entity_mut(&mut chunks_res, position))
+        .and_then(BlockEntity::container_mut);
+
+    if left_pressed
+        && held.stack.is_none()
+        && keyboard_input.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight])
+    {
+        quick_move(player_items, container_items, slot);
+        return;
+    }
+
+    let container = match (slot.owner, container_items) {
+        (SlotOwner::Player, _) => player_items,
+        (SlotOwner::BlockEntity, Some(container_items)) => container_items,
+        (SlotOwner::BlockEntity, None) => return,
+    };
+
+    if dropped_drag {
+        drop_held_stack(container, &mut held, slot.index);
+    } else if left_pressed {
+        if held.stack.is_some() {
+            drop_held_stack(container, &mut held, slot.index);
+        } else {
+            held.stack = container.take(slot.index, u32::MAX);
+            held.dragged_from = held.stack.map(|_| slot);
+        }
+    } else if right_pressed {
+        // Right clicking picks up half of the stack, or puts one of the held items down
+        let Some(mut stack) = held.stack else {
+            held.stack = container.split(slot.index);
+            return;
+        };
+        if let Some(one) = stack.split(1) {
+            if let Some(rest) = container.insert_into(slot.index, one) {
+                stack.merge(rest);
+            }
+        }
+        held.stack = Some(stack).filter(|stack| stack.amount > 0);
+    }
+}
+
+// Puts the held stack into the slot, merging it with the same item or swapping it with another one
+fn drop_held_stack(container: &mut ItemContainer, held: &mut HeldStack, index: usize) {
+    let Some(stack) = held.stack.take() else {
+        return;
+    };
+
+    if container
+        .get_slot(index)
+        .is_some_and(|existing| existing.item != stack.item)
+    {
+        held.stack = container.set_slot(index, Some(stack));
+    } else {
+        held.stack = container.insert_into(index, stack);
+    }
+}
+
+// With a container open, the stack moves between it and the player inventory.
+// Otherwise it moves from the hotbar to the rest of the inventory, or the other way around.
+fn quick_move(
+    player_items: &mut ItemContainer,
+    container_items: Option<&mut ItemContainer>,
+    slot: InventorySlot,
+) {
+    match (slot.owner, container_items) {
+        (SlotOwner::Player, Some(container_items)) => {
+            move_stack(player_items, slot.index, container_items);
+        }
+        (SlotOwner::BlockEntity, Some(container_items)) => {
+            move_stack(container_items, slot.index, player_items);
+        }
+        (SlotOwner::Player, None) => {
+            let Some(stack) = player_items.take(slot.index, u32::MAX) else {
+                return;
+            };
+            let target = if slot.index < player_items.columns {
+                player_items.columns..player_items.len()
+            } else {
+                0..player_items.columns
+            };
+            if let Some(rest) = player_items.insert_in(target, stack) {
+                player_items.insert_into(slot.index, rest);
+            }
+        }
+        (SlotOwner::BlockEntity, None) => {}
+    }
+}
+
+fn move_stack(from: &mut ItemContainer, index: usize, to: &mut ItemContainer) {
+    let Some(stack) = from.take(index, u32::MAX) else {
+        return;
+    };
+    if let Some(rest) = to.insert(stack) {
+        from.insert_into(index, rest);
+    }
+}
+
+fn update_inventory_panels(
+    mut commands: Commands,
+    inventory_open: Res<InventoryOpen>,
+    opened: Res<OpenedContainer>,
+    chunks_res: Res<Chunks>,
+    mut panel_q: Query<&mut Style, (With<InventoryPanel>, Without<ContainerPanel>)>,
+    mut container_panel_q: Query<(Entity, &mut Style), With<ContainerPanel>>,
+) {
+    if inventory_open.is_changed() {
+        if let Ok(mut style) = panel_q.get_single_mut() {
+            style.display = if inventory_open.0 {
+                Display::Flex
+            } else {
+                Display::None
+            };
+        }
+    }
+
+    if !opened.is_changed() {
+        return;
+    }
+    let Ok((container_panel, mut style)) = container_panel_q.get_single_mut() else {
+        return;
+    };
+
+    commands.entity(container_panel).despawn_descendants();
+    let container = opened
+        .0
+        .and_then(|position| get_block_entity(&chunks_res, position))
+        .and_then(BlockEntity::container);
+    let Some(container) = container else {
+        style.display = Display::None;
+        return;
+    };
+
+    style.display = Display::Flex;
+    let mut panel = commands.ui_builder(container_panel);
+    for row in 0..container.rows {
+        panel.row(|slots| {
+            for column in 0..container.columns {
+                inventory_slot(
+                    slots,
+                    InventorySlot {
+                        owner: SlotOwner::BlockEntity,
+                        index: container.index(row, column),
+                    },
+                );
+            }
+        });
+    }
+}
+
+fn show_item_icon(
+    commands: &mut Commands,
+    entity: Entity,
+    image: &mut UiImage,
+    visibility: &mut Visibility,
+    stack: Option<&ItemStack>,
+    textures: &InventoryTextures,
+    registry: &BlockRegistry,
+) {
+    match stack.and_then(|stack| stack.item.block()) {
+        Some(block) => {
+            image.texture = textures.blocks.clone();
+            commands.entity(entity).insert(TextureAtlas {
+                layout: textures.blocks_layout.clone(),
+                index: registry.get(block).atlas_index,
+            });
+            *visibility = Visibility::Inherited;
+        }
+        None => *visibility = Visibility::Hidden,
+    }
+}
+
+fn amount_text(stack: Option<&ItemStack>) -> String {
+    return match stack {
+        None => String::new(),
+        // Tools don't have textures yet, so their name is shown instead
+        Some(ItemStack {
+            item: Item::Tool(kind),
+            ..
+        }) => format!("{:?}", kind),
+        Some(stack) if stack.amount == 1 => String::new(),
+        Some(stack) => stack.amount.to_string(),
+    };
+}
+
+fn update_inventory_slots(
+    mut commands: Commands,
+    inventory_res: Res<PlayerInventory>,
+    opened: Res<OpenedContainer>,
+    chunks_res: Res<Chunks>,
+    textures: Res<InventoryTextures>,
+    registry: Res<BlockRegistry>,
+    new_slots_q: Query<(), Added<InventorySlot>>,
+    mut slots_q: Query<(&InventorySlot, &mut UiImage, &Children)>,
+    mut icons_q: Query<
+        (&mut UiImage, &mut Visibility),
+        (With<InventorySlotIcon>, Without<InventorySlot>),
+    >,
+    mut amounts_q: Query<&mut Text, With<InventorySlotAmount>>,
+) {
+    let container_changed = opened.0.is_some() && chunks_res.is_changed();
+    if !inventory_res.is_changed() && !container_changed && new_slots_q.is_empty() {
+        return;
+    }
+
+    let container = opened
+        .0
+        .and_then(|position| get_block_entity(&chunks_res, position))
+        .and_then(BlockEntity::container);
+
+    for (slot, mut image, children) in slots_q.iter_mut() {
+        let (stack, selected) = match slot.owner {
+            SlotOwner::Player => (
+                inventory_res.get_slot(slot.index),
+                slot.index == inventory_res.selected_index(),
+            ),
+            SlotOwner::BlockEntity => (
+                container.and_then(|container| container.get_slot(slot.index)),
+                false,
+            ),
+        };
+
+        image.texture = if selected {
+            textures.selected_slot.clone()
+        } else {
+            textures.slot.clone()
+        };
+
+        for &child in children.iter() {
+            if let Ok((mut icon_image, mut visibility)) = icons_q.get_mut(child) {
+                show_item_icon(
+                    &mut commands,
+                    child,
+                    &mut icon_image,
+                    &mut visibility,
+                    stack,
+                    &textures,
+                    &registry,
+                );
+            }
+            if let Ok(mut text) = amounts_q.get_mut(child) {
+                text.sections[0].value = amount_text(stack);
+            }
+        }
+    }
+}
+
+fn update_held_item(
+    mut commands: Commands,
+    window_q: Query<&Window, With<PrimaryWindow>>,
+    held: Res<HeldStack>,
+    textures: Res<InventoryTextures>,
+    registry: Res<BlockRegistry>,
+    mut held_icon_q: Query<(Entity, &mut Style, &mut UiImage, &mut Visibility), With<HeldItemIcon>>,
+) {
+    let Ok((entity, mut style, mut image, mut visibility)) = held_icon_q.get_single_mut() else {
+        return;
+    };
+
+    if held.is_changed() {
+        show_item_icon(
+            &mut commands,
+            entity,
+            &mut image,
+            &mut visibility,
+            held.stack.as_ref(),
+            &textures,
+            &registry,
+        );
+    }
+
+    if let Some(cursor) = window_q
+        .get_single()
+        .ok()
+        .and_then(|window| window.cursor_position())
+    {
+        style.left = Val::Px(cursor.x - ICON_SIZE / 2.0);
+        style.top = Val::Px(cursor.y - ICON_SIZE / 2.0);
+    }
+}
@@ -1,44 +1,244 @@
-use crate::chunk::{self, BlockType, PlaceMode, CHUNK_AREA, CHUNK_WIDTH, TILE_SIZE};
-use crate::chunk_manager::{ChunkManagerPlugin, FinishedSavingChunks, TryPlaceBlock, UnloadChunks};
+use crate::block::{BlockRegistry, BlockType};
+use crate::block_entity::get_block_entity;
+use crate::chunk::{self, PlaceMode, RemeshChunks, CHUNK_AREA, CHUNK_WIDTH, TILE_SIZE};
+use crate::chunk_manager::{
+    ChunkManagerPlugin, Chunks, FinishedSavingChunks, TryPlaceBlock, UnloadChunks,
+};
+use crate::circuit::{interactive_layer, InteractCircuit};
 
-use crate::item_container::{Item, ItemContainer, ItemStack};
+use crate::item_container::{Item, ItemContainer, ItemStack, TOOL_KINDS};
+use crate::light::MAX_LIGHT;
 use crate::pause_menu::{InPauseState, PauseMenuPlugin};
 use crate::player::{Player, PlayerPlugin};
 
-use crate::widgets::inventory::{InventoryWidgetExt, InventoryWidgetPlugin};
+use crate::widgets::inventory::{
+    InventoryOpen, InventoryWidgetExt, InventoryWidgetPlugin, OpenContainer,
+};
+use crate::world_generator::GeneratorSettings;
 use crate::{utils::*, GamePauseState, GameState, MainCamera};
+use bevy::ecs::schedule::ScheduleLabel;
 use bevy::render::render_asset::RenderAssetUsages;
-use bevy::sprite::MaterialMesh2dBundle;
+use bevy::sprite::{MaterialMesh2dBundle, Mesh2dHandle};
 use bevy::{input::mouse::MouseWheel, prelude::*, sprite::SpriteBundle, window::PrimaryWindow};
 use bevy_xpbd_2d::{prelude::*, SubstepSchedule, SubstepSet};
 use serde::{Deserialize, Serialize};
 use sickle_ui::prelude::{UiContainerExt, UiRowExt};
 use sickle_ui::ui_builder::{UiBuilderExt, UiRoot};
-
-#[derive(Clone, Copy, Debug, PartialEq, Reflect, Default, Serialize, Deserialize)]
-pub enum WorldGenPreset {
-    #[default]
-    DEFAULT,
-    FLAT,
-    EMPTY,
-}
+use std::fs;
 
 #[derive(Debug, Resource, Default, Reflect, Serialize, Deserialize, Clone)]
 #[reflect(Resource)]
 pub struct WorldInfo {
     pub display_name: String,
     pub name: String,
-    pub preset: WorldGenPreset,
+    // The id of the world generator in the WorldGenerators
+    #[serde(alias = "preset")]
+    pub generator: String,
+    // TOML integers are signed, so the seed is saved as an i64 with the same bits
+    #[serde(default, with = "seed_as_i64")]
+    pub seed: u64,
     pub player_position: Option<Vec2>, // THIS IS IN BLOCK UNITS!!!
     pub is_flying: bool,
+    // Worlds created before the game modes existed are creative, like they were played before
+    #[serde(default)]
+    pub game_mode: GameMode,
+    #[serde(default)]
+    pub tick: u64,
+    #[serde(default)]
+    pub time: WorldTime,
+    #[serde(default)]
+    #[reflect(ignore)]
+    pub generator_settings: GeneratorSettings,
+}
+
+mod seed_as_i64 {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(seed: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        return serializer.serialize_i64(*seed as i64);
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        return Ok(i64::deserialize(deserializer)? as u64);
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Reflect, Serialize, Deserialize)]
+pub enum GameMode {
+    // Every block can be placed without running out, and broken blocks don't drop anything
+    #[default]
+    Creative,
+    // Placing a block takes it from the inventory, and breaking it drops it as an item
+    Survival,
+}
+
+// In the order they are shown in the world creation menu
+pub const GAME_MODES: [GameMode; 2] = [GameMode::Creative, GameMode::Survival];
+
+impl GameMode {
+    pub fn display_name(&self) -> &'static str {
+        return match self {
+            GameMode::Creative => "Creative",
+            GameMode::Survival => "Survival",
+        };
+    }
+}
+
+// The amount of fixed updates the world has been running for.
+// It's loaded from and saved into the world info.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct WorldTick(pub u64);
+
+// Runs once for every world tick, right after the tick advances.
+// The systems that make the world change by itself, like liquids and block ticks, go here.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WorldTickSchedule;
+
+// The sky light never goes below this level, even at midnight
+const MIN_SKY_LIGHT: u8 = 4;
+
+// How far into the current day the world is, from 0.0 to 1.0.
+// 0.0 is midnight, 0.25 is sunrise, 0.5 is noon and 0.75 is sunset.
+// The length of a whole day is in seconds.
+#[derive(Debug, Resource, Reflect, Serialize, Deserialize, Clone)]
+#[reflect(Resource)]
+pub struct WorldTime {
+    pub time_of_day: f32,
+    pub day_length: f32,
+}
+
+impl Default for WorldTime {
+    fn default() -> Self {
+        Self {
+            time_of_day: 0.3,
+            day_length: 1200.0,
+        }
+    }
+}
+
+impl WorldTime {
+    // Height of the sun in the sky, 1.0 at noon and -1.0 at midnight
+    fn sun_height(&self) -> f32 {
+        (std::f32::consts::TAU * (self.time_of_day - 0.25)).sin()
+    }
+
+    // From 0.0 at night to 1.0 during the day, with a smooth transition at sunrise and sunset
+    pub fn daylight(&self) -> f32 {
+        (self.sun_height() * 2.5 + 0.5).clamp(0.0, 1.0)
+    }
+
+    // The sky light level is quantized, so the chunks only need to be remeshed when it changes
+    pub fn sky_light_level(&self) -> u8 {
+        MIN_SKY_LIGHT + ((MAX_LIGHT - MIN_SKY_LIGHT) as f32 * self.daylight()).round() as u8
+    }
+
+    // How strong the orange tint of sunrise and sunset is
+    fn twilight(&self) -> f32 {
+        (1.0 - self.sun_height().abs() * 4.0).clamp(0.0, 1.0)
+    }
 }
 
+// The size of the survival inventory, and the smallest size of the creative one
+const INVENTORY_ROWS: usize = 3;
+const INVENTORY_COLUMNS: usize = 10;
+
+// The first row of the inventory is the hotbar,
+// and the block in the selected hotbar slot is the one the player places
 #[derive(Resource, Deref, DerefMut)]
-pub struct PlayerInventory(pub ItemContainer);
+pub struct PlayerInventory {
+    #[deref]
+    pub items: ItemContainer,
+    pub selected_slot: usize,
+}
+
+impl PlayerInventory {
+    pub fn selected_stack(&self) -> Option<&ItemStack> {
+        return self.items.get_item(0, self.selected_slot);
+    }
+
+    pub fn selected_block(&self) -> Option<BlockType> {
+        return self.selected_stack().and_then(|stack| stack.item.block());
+    }
+
+    // The index of the selected hotbar slot in the container
+    pub fn selected_index(&self) -> usize {
+        return self.items.index(0, self.selected_slot);
+    }
+
+    // Only survival worlds save the inventory, as the creative one is refilled when the world is loaded
+    pub fn load(&mut self, world_name: &str, registry: &BlockRegistry) {
+        self.items.clear();
+        let Ok(bytes) = fs::read(inventory_path(world_name)) else {
+            return;
+        };
+        let Some(payload) = bytes.strip_prefix(&INVENTORY_MAGIC) else {
+            error!("The player inventory file is not an inventory");
+            return;
+        };
+        let items = bincode::deserialize::<InventoryFile>(payload).map(|file| {
+            let InventoryFile { palette, mut items } = file;
+            items.map_blocks(|block| {
+                let name = palette.get(block.0 as usize)?;
+                let id = registry.id(name);
+                if id.is_none() {
+                    warn!("Unknown block '{}' in the player inventory, removing it", name);
+                }
+                return id;
+            });
+            items
+        });
+        match items {
+            Ok(items) if items.rows == self.items.rows && items.columns == self.items.columns => {
+                self.items = items;
+            }
+            Ok(_) => error!("The saved inventory doesn't have the size of the player inventory"),
+            Err(e) => error!("Could not read the player inventory: {}", e),
+        }
+    }
+
+    pub fn save(&self, world_name: &str, registry: &BlockRegistry) {
+        let mut palette: Vec<String> = Vec::new();
+        let mut items = self.items.clone();
+        items.map_blocks(|block| {
+            let name = &registry.get(block).name;
+            let index = match palette.iter().position(|known| known == name) {
+                Some(index) => index,
+                None => {
+                    palette.push(name.clone());
+                    palette.len() - 1
+                }
+            };
+            return Some(BlockType(index as u16));
+        });
+
+        let mut bytes = Vec::from(INVENTORY_MAGIC);
+        match bincode::serialize_into(&mut bytes, &InventoryFile { palette, items }) {
+            Ok(()) => {
+                if let Err(e) = fs::write(inventory_path(world_name), bytes) {
+                    error!("Error saving the player inventory: {}", e);
+                }
+            }
+            Err(e) => error!("Could not serialize the player inventory: {}", e),
+        }
+    }
+}
+
+// The inventory file starts with this magic number, followed by an InventoryFile
+const INVENTORY_MAGIC: [u8; 4] = *b"MJIV";
+
+// The block items are saved as indices into the palette, which has the names of the blocks
+#[derive(Serialize, Deserialize)]
+struct InventoryFile {
+    palette: Vec<String>,
+    items: ItemContainer,
+}
+
+fn inventory_path(world_name: &str) -> String {
+    format!("worlds/{}/inventory.bin", world_name)
+}
 
 #[derive(Component)]
 struct BlockCursor {
-    block_type: BlockType,
     layer: PlaceMode,
     block_position: IVec2,
     relative_position: UVec2,
@@ -48,6 +248,22 @@ struct BlockCursor {
 #[derive(Component)]
 struct CursorBlockIcon;
 
+// The crack stages drawn over the block being broken
+#[derive(Component)]
+struct BlockCrack;
+
+const CRACK_STAGES: usize = 8;
+
+// How many blocks away from the player the cursor can reach
+const BLOCK_REACH: f32 = 7.0;
+
+// The block the player is breaking, and for how many seconds the button has been held on it
+#[derive(Resource, Default)]
+struct BlockBreaking {
+    target: Option<(IVec2, PlaceMode)>,
+    progress: f32,
+}
+
 #[derive(Component)]
 struct CursorPlaceModeIcon;
 
@@ -64,24 +280,44 @@ impl Plugin for WorldPlugin {
         app.insert_resource(WorldInfo {
             display_name: "".to_string(),
             name: "".to_string(),
-            preset: WorldGenPreset::default(),
+            generator: "default".to_string(),
+            seed: 0,
             player_position: None,
             is_flying: false,
+            game_mode: GameMode::Creative,
+            tick: 0,
+            time: WorldTime::default(),
+            generator_settings: GeneratorSettings::new(),
+        })
+        .insert_resource(WorldTick::default())
+        .insert_resource(BlockBreaking::default())
+        .insert_resource(WorldTime::default())
+        .insert_resource(PlayerInventory {
+            items: ItemContainer::new(INVENTORY_ROWS, INVENTORY_COLUMNS),
+            selected_slot: 0,
         })
-        .insert_resource(PlayerInventory(ItemContainer::new(3, 10)))
         .insert_resource(Gravity(Vec2::NEG_Y * (9.81 * TILE_SIZE as f32)))
         .register_type::<WorldInfo>()
+        .register_type::<WorldTime>()
+        .init_schedule(WorldTickSchedule)
         .add_plugins((ChunkManagerPlugin, PlayerPlugin, PauseMenuPlugin, InventoryWidgetPlugin))
         .add_systems(
             OnEnter(GameState::Game),
-            (config_camera, setup, setup_sky_bg)
+            (config_camera, load_world_time, setup, setup_sky_bg)
                 .chain()
                 .run_if(in_state(GameState::Game)),
         )
         .add_systems(
             Update,
             (
-                (switch_place_mode, mouse_scroll_input, force_reload_chunks)
+                (
+                    switch_place_mode,
+                    mouse_scroll_input,
+                    update_cursor_block_icon,
+                    break_block_input,
+                    force_reload_chunks,
+                    update_sky_colors,
+                )
                     .run_if(in_state(GamePauseState::Running)),
                 // The pause input system will be ran in both running and paused states
                 pause_input,
@@ -89,6 +325,13 @@ impl Plugin for WorldPlugin {
             )
                 .run_if(in_state(GameState::Game)),
         )
+        .add_systems(
+            FixedUpdate,
+            (advance_world_tick, run_world_tick_schedule, advance_world_time)
+                .chain()
+                .run_if(in_state(GameState::Game))
+                .run_if(in_state(GamePauseState::Running)),
+        )
         .add_systems(
             SubstepSchedule,
             (
@@ -126,33 +369,96 @@ fn setup(
     asset_server: Res<AssetServer>,
     mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
     mut inventory_res: ResMut<PlayerInventory>,
+    registry: Res<BlockRegistry>,
+    world_info: Res<WorldInfo>,
 ) {
-    commands.spawn((
-        Name::new("Cursor"),
-        SpriteBundle {
-            sprite: Sprite {
-                color: Color::srgba(1.0, 1.0, 1.0, 0.5),
-                custom_size: Some(Vec2::splat(TILE_SIZE as f32)),
-                anchor: bevy::sprite::Anchor::BottomLeft,
+    inventory_res.selected_slot = 0;
+    match world_info.game_mode {
+        GameMode::Creative => {
+            // Every block and tool starts as a full stack in the inventory, which gets as many rows as they need
+            let items: Vec<Item> = (1..registry.len())
+                .map(|id| BlockType(id as u16))
+                .filter(|block| registry.get(*block).item)
+                .map(Item::Block)
+                .chain(TOOL_KINDS.into_iter().map(Item::Tool))
+                .collect();
+            let rows = items.len().div_ceil(INVENTORY_COLUMNS).max(INVENTORY_ROWS);
+            inventory_res.items = ItemContainer::new(rows, INVENTORY_COLUMNS);
+            for item in items {
+                if let Some(overflow) = inventory_res.insert(ItemStack::new(item, item.max_stack())) {
+                    warn!("The creative inventory is full, leaving out {:?}", overflow.item);
+                }
+            }
+        }
+        GameMode::Survival => {
+            inventory_res.items = ItemContainer::new(INVENTORY_ROWS, INVENTORY_COLUMNS);
+            // Players start with one of each tool, since there is no other way to get them yet
+            if fs::metadata(inventory_path(&world_info.name)).is_err() {
+                for kind in TOOL_KINDS {
+                    inventory_res.insert(ItemStack::new(Item::Tool(kind), 1));
+                }
+            } else {
+                inventory_res.load(&world_info.name, &registry);
+            }
+        }
+    }
+    let first_block = inventory_res.selected_block().unwrap_or_default();
+
+    let crack_layout = TextureAtlasLayout::from_grid(
+        UVec2::splat(TILE_SIZE as u32),
+        CRACK_STAGES as u32,
+        1,
+        None,
+        None,
+    );
+
+    commands
+        .spawn((
+            Name::new("Cursor"),
+            SpriteBundle {
+                sprite: Sprite {
+                    color: Color::srgba(1.0, 1.0, 1.0, 0.5),
+                    custom_size: Some(Vec2::splat(TILE_SIZE as f32)),
+                    anchor: bevy::sprite::Anchor::BottomLeft,
+                    ..default()
+                },
+                transform: Transform::from_translation(Vec3::new(0.0, 0.0, 2.0)),
+                visibility: Visibility::Hidden,
                 ..default()
             },
-            transform: Transform::from_translation(Vec3::new(0.0, 0.0, 2.0)),
-            visibility: Visibility::Hidden,
-            ..default()
-        },
-        BlockCursor {
-            block_type: BlockType::GRASS,
-            layer: PlaceMode::BLOCK,
-            block_position: IVec2::ZERO,
-            chunk_position: IVec2::ZERO,
-            relative_position: UVec2::ZERO,
-        },
-        FromWorld,
-    ));
+            BlockCursor {
+                layer: PlaceMode::BLOCK,
+                block_position: IVec2::ZERO,
+                chunk_position: IVec2::ZERO,
+                relative_position: UVec2::ZERO,
+            },
+            FromWorld,
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                Name::new("Block Crack"),
+                SpriteBundle {
+                    texture: asset_server.load("textures/cracks.png"),
+                    sprite: Sprite {
+                        custom_size: Some(Vec2::splat(TILE_SIZE as f32)),
+                        anchor: bevy::sprite::Anchor::BottomLeft,
+                        ..default()
+                    },
+                    transform: Transform::from_translation(Vec3::new(0.0, 0.0, 0.1)),
+                    visibility: Visibility::Hidden,
+                    ..default()
+                },
+                TextureAtlas {
+                    layout: texture_atlas_layouts.add(crack_layout),
+                    index: 0,
+                },
+                BlockCrack,
+            ));
+        });
 
     let layout = TextureAtlasLayout::from_grid(
         UVec2::splat(TILE_SIZE as u32),
-        BlockType::SIZE as u32,
+        registry.atlas_size as u32,
         1,
         None,
         None,
@@ -174,7 +480,7 @@ fn setup(
             },
             TextureAtlas {
                 layout: texture_atlas_layouts.add(layout),
-                index: 0,
+                index: registry.get(first_block).atlas_index,
             },
             CursorBlockIcon,
             FromWorld,
@@ -204,20 +510,71 @@ fn setup(
             ));
         });
 
-    inventory_res.set_item(1, 1, Some(ItemStack {item: Item::TEST, amount: 1}));
-
     commands
         .ui_builder(UiRoot)
-        .container(NodeBundle { ..default() }, |gr| {
-            gr.named("Inventory");
-            gr.inventory(&inventory_res);
-        });
+        .container(
+            (
+                NodeBundle {
+                    style: Style {
+                        width: Val::Percent(100.0),
+                        height: Val::Percent(100.0),
+                        ..default()
+                    },
+                    ..default()
+                },
+                FromWorld,
+            ),
+            |gr| {
+                gr.named("Inventory");
+                gr.inventory(&inventory_res);
+            },
+        );
+}
+
+// The colors of each row of vertices of the sky background, from the horizon upwards
+const DAY_SKY: [[f32; 3]; 4] = [
+    [1.0, 1.0, 1.0],
+    [0.48, 0.48, 0.67],
+    [0.125, 0.125, 1.0],
+    [0.0, 0.0, 0.5],
+];
+const TWILIGHT_SKY: [[f32; 3]; 4] = [
+    [1.0, 0.6, 0.3],
+    [0.9, 0.45, 0.35],
+    [0.4, 0.3, 0.6],
+    [0.05, 0.05, 0.3],
+];
+const NIGHT_SKY: [[f32; 3]; 4] = [
+    [0.1, 0.1, 0.2],
+    [0.05, 0.05, 0.15],
+    [0.02, 0.02, 0.1],
+    [0.0, 0.0, 0.05],
+];
+
+fn sky_colors(world_time: &WorldTime) -> Vec<[f32; 4]> {
+    let daylight = world_time.daylight();
+    let twilight = world_time.twilight();
+
+    let mut colors = vec![[0.0, 0.0, 0.0, 1.0]; 2];
+    for row in 0..DAY_SKY.len() {
+        let color = Vec3::from(NIGHT_SKY[row])
+            .lerp(Vec3::from(DAY_SKY[row]), daylight)
+            .lerp(Vec3::from(TWILIGHT_SKY[row]), twilight)
+            .extend(1.0)
+            .to_array();
+        colors.push(color);
+        colors.push(color);
+    }
+    colors.extend([[0.0, 0.0, 0.0, 1.0]; 2]);
+
+    colors
 }
 
 fn setup_sky_bg(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
+    world_time: Res<WorldTime>,
 ) {
     // clear color: 0.48, 0.48, 0.67
 
@@ -242,23 +599,7 @@ fn setup_sky_bg(
             [-0.5, 3.0, 0.0],   // 11
         ],
     )
-    .with_inserted_attribute(
-        Mesh::ATTRIBUTE_COLOR,
-        vec![
-            [0.0, 0.0, 0.0, 1.0],
-            [0.0, 0.0, 0.0, 1.0],
-            [1.0, 1.0, 1.0, 1.0],
-            [1.0, 1.0, 1.0, 1.0],
-            [0.48, 0.48, 0.67, 1.0],
-            [0.48, 0.48, 0.67, 1.0],
-            [0.125, 0.125, 1.0, 1.0],
-            [0.125, 0.125, 1.0, 1.0],
-            [0.0, 0.0, 0.5, 1.0],
-            [0.0, 0.0, 0.5, 1.0],
-            [0.0, 0.0, 0.0, 1.0],
-            [0.0, 0.0, 0.0, 1.0],
-        ],
-    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, sky_colors(&world_time))
     .with_inserted_indices(bevy::render::mesh::Indices::U32(vec![
         0, 1, 2, 2, 3, 0, 3, 2, 4, 4, 5, 3, 5, 4, 6, 6, 7, 5, 7, 6, 8, 8, 9, 7, 9, 8, 10, 10, 11, 9,
     ]));
@@ -292,6 +633,41 @@ fn destroy_game(
     }
 }
 
+fn load_world_time(
+    mut world_tick: ResMut<WorldTick>,
+    mut world_time: ResMut<WorldTime>,
+    world_info: Res<WorldInfo>,
+) {
+    world_tick.0 = world_info.tick;
+    *world_time = world_info.time.clone();
+}
+
+fn advance_world_tick(mut world_tick: ResMut<WorldTick>) {
+    world_tick.0 += 1;
+}
+
+fn run_world_tick_schedule(world: &mut World) {
+    world.run_schedule(WorldTickSchedule);
+}
+
+fn advance_world_time(
+    time: Res<Time>,
+    mut world_time: ResMut<WorldTime>,
+    mut remesh_chunk_ev: EventWriter<RemeshChunks>,
+    chunks_res: Res<Chunks>,
+) {
+    let sky_level = world_time.sky_light_level();
+
+    let day_length = world_time.day_length.max(1.0);
+    world_time.time_of_day = (world_time.time_of_day + time.delta_seconds() / day_length).fract();
+
+    if world_time.sky_light_level() != sky_level {
+        remesh_chunk_ev.send(RemeshChunks {
+            positions: chunks_res.keys().copied().collect(),
+        });
+    }
+}
+
 fn pause_input(
     input: Res<ButtonInput<KeyCode>>,
     in_pause_state: Res<State<InPauseState>>,
@@ -357,7 +733,18 @@ fn block_input(
     player_query: Query<&Transform, With<Player>>,
     mouse_button_input: Res<ButtonInput<MouseButton>>,
     mut try_place_block_ev: EventWriter<TryPlaceBlock>,
+    inventory_res: Res<PlayerInventory>,
+    inventory_open: Res<InventoryOpen>,
+    chunks_res: Res<Chunks>,
+    mut open_container_ev: EventWriter<OpenContainer>,
+    mut interact_circuit_ev: EventWriter<InteractCircuit>,
+    registry: Res<BlockRegistry>,
 ) {
+    // The clicks go to the inventory while it's open
+    if inventory_open.0 {
+        return;
+    }
+
     let cursor = cursor_q.single();
     let player_transform = player_query.single();
 
@@ -370,29 +757,128 @@ fn block_input(
         .block_position
         .as_vec2()
         .distance(player_position.as_vec2())
-        > 7.0
+        > BLOCK_REACH
     {
         return;
     }
 
+    // Right clicking a block with a container opens it instead of placing a block against it
+    if mouse_button_input.just_pressed(MouseButton::Right) {
+        let has_container = get_block_entity(&chunks_res, cursor.block_position)
+            .is_some_and(|entity| entity.container().is_some());
+        if has_container {
+            open_container_ev.send(OpenContainer {
+                position: cursor.block_position,
+            });
+            return;
+        }
+        // Switches and buttons are used the same way
+        if let Some(layer) = interactive_layer(&chunks_res, &registry, cursor.block_position) {
+            interact_circuit_ev.send(InteractCircuit {
+                position: cursor.block_position,
+                layer,
+            });
+            return;
+        }
+    }
+
     if player_position != cursor.block_position || cursor.layer == PlaceMode::WALL {
         if mouse_button_input.just_pressed(MouseButton::Right) {
-            try_place_block_ev.send(TryPlaceBlock {
-                position: cursor.relative_position,
-                chunk_position: cursor.chunk_position,
-                layer: cursor.layer,
-                block_type: cursor.block_type,
-            });
+            if let Some(block_type) = inventory_res.selected_block() {
+                try_place_block_ev.send(TryPlaceBlock {
+                    position: cursor.relative_position,
+                    chunk_position: cursor.chunk_position,
+                    layer: cursor.layer,
+                    block_type,
+                    by_player: true,
+                    replaces: None,
+                });
+            }
         }
     }
-    if mouse_button_input.just_pressed(MouseButton::Left) {
-        try_place_block_ev.send(TryPlaceBlock {
-            position: cursor.relative_position,
-            chunk_position: cursor.chunk_position,
-            layer: cursor.layer,
-            block_type: BlockType::AIR,
-        });
+}
+
+// Holding the left button on a block breaks it once it's been held for the break time of the block,
+// and moving the cursor to another block starts over. Blocks break instantly in creative.
+fn break_block_input(
+    cursor_q: Query<&BlockCursor>,
+    player_query: Query<&Transform, With<Player>>,
+    mut crack_q: Query<(&mut TextureAtlas, &mut Visibility), With<BlockCrack>>,
+    mouse_button_input: Res<ButtonInput<MouseButton>>,
+    mut try_place_block_ev: EventWriter<TryPlaceBlock>,
+    mut breaking: ResMut<BlockBreaking>,
+    chunks_res: Res<Chunks>,
+    registry: Res<BlockRegistry>,
+    inventory_res: Res<PlayerInventory>,
+    inventory_open: Res<InventoryOpen>,
+    world_info_res: Res<WorldInfo>,
+    time: Res<Time>,
+) {
+    let Ok((mut crack_atlas, mut crack_visibility)) = crack_q.get_single_mut() else {
+        return;
+    };
+    let (Ok(cursor), Ok(player_transform)) = (cursor_q.get_single(), player_query.get_single()) else {
+        return;
+    };
+
+    let player_position = IVec2::new(
+        (player_transform.translation.x / TILE_SIZE as f32).floor() as i32,
+        (player_transform.translation.y / TILE_SIZE as f32).floor() as i32,
+    );
+    let in_reach = cursor.block_position.as_vec2().distance(player_position.as_vec2()) <= BLOCK_REACH;
+
+    let index = get_index_from_position(cursor.relative_position);
+    let block = chunks_res
+        .get(&cursor.chunk_position)
+        .map(|chunk| chunk.layers[cursor.layer as usize][index])
+        .unwrap_or(BlockType::AIR);
+
+    let target = Some((cursor.block_position, cursor.layer));
+    if breaking.target != target {
+        breaking.target = target;
+        breaking.progress = 0.0;
+    }
+
+    if inventory_open.0
+        || !in_reach
+        || block == BlockType::AIR
+        || !mouse_button_input.pressed(MouseButton::Left)
+    {
+        breaking.progress = 0.0;
+        *crack_visibility = Visibility::Hidden;
+        return;
+    }
+
+    let break_time = match world_info_res.game_mode {
+        GameMode::Creative => 0.0,
+        GameMode::Survival => {
+            let tool = inventory_res.selected_stack().and_then(|stack| stack.item.tool());
+            registry.get(block).break_time(tool)
+        }
+    };
+    // Instant breaks need a new click for each block, so holding the button doesn't dig through everything
+    if break_time <= 0.0 && !mouse_button_input.just_pressed(MouseButton::Left) {
+        return;
+    }
+
+    breaking.progress += time.delta_seconds();
+    if breaking.progress < break_time {
+        let stage = (breaking.progress / break_time * CRACK_STAGES as f32) as usize;
+        crack_atlas.index = stage.min(CRACK_STAGES - 1);
+        *crack_visibility = Visibility::Inherited;
+        return;
     }
+
+    try_place_block_ev.send(TryPlaceBlock {
+        position: cursor.relative_position,
+        chunk_position: cursor.chunk_position,
+        layer: cursor.layer,
+        block_type: BlockType::AIR,
+        by_player: true,
+        replaces: None,
+    });
+    breaking.progress = 0.0;
+    *crack_visibility = Visibility::Hidden;
 }
 
 fn camera_follow_player(
@@ -440,11 +926,32 @@ fn update_sky_bg(
     //sky_transform.translation.y = camera_transform.translation.y - 200.0;
 }
 
+fn update_sky_colors(
+    sky_q: Query<&Mesh2dHandle, With<SkyBackground>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut last_colors: Local<Vec<[f32; 4]>>,
+    world_time: Res<WorldTime>,
+) {
+    // Getting the mesh mutably sends it to the GPU again, so it's only done when the colors change
+    let colors = sky_colors(&world_time);
+    if colors == *last_colors {
+        return;
+    }
+    let Ok(sky_mesh) = sky_q.get_single() else {
+        return;
+    };
+    let Some(mesh) = meshes.get_mut(sky_mesh.0.id()) else {
+        return;
+    };
+
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors.clone());
+    *last_colors = colors;
+}
+
 fn mouse_scroll_input(
     keyboard_input: Res<ButtonInput<KeyCode>>,
     mut camera_query: Query<&mut Transform, With<MainCamera>>,
-    mut cursor_query: Query<&mut BlockCursor>,
-    mut cursor_block_icon_q: Query<&mut TextureAtlas, With<CursorBlockIcon>>,
+    mut inventory_res: ResMut<PlayerInventory>,
     mut mouse_scroll_event: EventReader<MouseWheel>,
 ) {
     const CAMERA_MIN_ZOOM: f32 = 0.05;
@@ -472,25 +979,34 @@ fn mouse_scroll_input(
                 }
             }
         } else {
-            let mut cursor = cursor_query.single_mut();
+            // Scrolling goes through the hotbar slots, and wraps around at its ends
+            let columns = inventory_res.columns;
             if ev.y > 0.0 {
-                // Scrolling up
-                if cursor.block_type < BlockType::GLASS {
-                    cursor.block_type = enum_iterator::next(&cursor.block_type).unwrap();
-                }
+                inventory_res.selected_slot = (inventory_res.selected_slot + 1) % columns;
             } else if ev.y < 0.0 {
-                // Scrolling down
-                if cursor.block_type > BlockType::GRASS {
-                    cursor.block_type = enum_iterator::previous(&cursor.block_type).unwrap();
-                }
+                inventory_res.selected_slot = (inventory_res.selected_slot + columns - 1) % columns;
             }
-
-            let mut icon_tex_atlas = cursor_block_icon_q.single_mut();
-            icon_tex_atlas.index = cursor.block_type as usize - 1;
         }
     }
 }
 
+fn update_cursor_block_icon(
+    inventory_res: Res<PlayerInventory>,
+    mut cursor_block_icon_q: Query<&mut TextureAtlas, With<CursorBlockIcon>>,
+    registry: Res<BlockRegistry>,
+) {
+    if !inventory_res.is_changed() {
+        return;
+    }
+
+    let Some(block) = inventory_res.selected_block() else {
+        return;
+    };
+    if let Ok(mut icon_tex_atlas) = cursor_block_icon_q.get_single_mut() {
+        icon_tex_atlas.index = registry.get(block).atlas_index;
+    }
+}
+
 fn update_cursor(
     window_query: Query<&Window, With<PrimaryWindow>>,
     mut cursor_q: Query<(
@@ -506,6 +1022,8 @@ fn update_cursor(
     camera_q: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
     player_q: Query<&Transform, (With<Player>, Without<BlockCursor>, Without<CursorBlockIcon>)>,
     time: Res<Time>,
+    inventory_res: Res<PlayerInventory>,
+    inventory_open: Res<InventoryOpen>,
 ) {
     if let Ok(window) = window_query.get_single() {
         let (mut cursor_transform, mut cursor, mut cursor_sprite, mut cursor_visibility) =
@@ -524,7 +1042,10 @@ fn update_cursor(
         {
             *cursor_visibility = Visibility::Visible;
 
-            if let Ok(player_transform) = player_q.get_single() {
+            // The icon shows the block in the selected hotbar slot, so it's hidden when there is none
+            if inventory_open.0 || inventory_res.selected_block().is_none() {
+                *cursor_icon_visibility = Visibility::Hidden;
+            } else if let Ok(player_transform) = player_q.get_single() {
                 let player_position = IVec2::new(
                     (player_transform.translation.x / TILE_SIZE as f32).floor() as i32,
                     (player_transform.translation.y / TILE_SIZE as f32).floor() as i32,
@@ -533,7 +1054,7 @@ fn update_cursor(
                     .block_position
                     .as_vec2()
                     .distance(player_position.as_vec2())
-                    > 7.0
+                    > BLOCK_REACH
                 {
                     *cursor_icon_visibility = Visibility::Hidden;
                 } else {
@@ -0,0 +1,478 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use bevy::prelude::*;
+
+use crate::{
+    biome::Biome,
+    block::{BlockRegistry, BlockType},
+    chunk::{CHUNK_AREA, CHUNK_WIDTH},
+    structure::StructureBlock,
+    terrain::DefaultGenerator,
+    utils::get_index_from_position,
+};
+
+pub struct ChunkGenerationResult {
+    pub blocks: [BlockType; CHUNK_AREA],
+    pub walls: [BlockType; CHUNK_AREA],
+    pub biomes: [Biome; CHUNK_WIDTH],
+    // Blocks of the structures that start in this chunk, which can also be in other chunks
+    pub structures: Vec<StructureBlock>,
+}
+
+impl Default for ChunkGenerationResult {
+    fn default() -> Self {
+        Self {
+            blocks: [BlockType::AIR; CHUNK_AREA],
+            walls: [BlockType::AIR; CHUNK_AREA],
+            biomes: [Biome::default(); CHUNK_WIDTH],
+            structures: Vec::new(),
+        }
+    }
+}
+
+// The settings given to the generator of a world, which are saved in its world.toml
+pub type GeneratorSettings = BTreeMap<String, String>;
+
+pub trait WorldGenerator: Send + Sync {
+    // Runs in the chunk loading tasks, and must always give the same chunk for the same seed
+    fn generate(&self, chunk_pos: IVec2, seed: u64) -> ChunkGenerationResult;
+}
+
+// Builds the generator of a world from its settings
+pub type GeneratorFactory =
+    Arc<dyn Fn(&BlockRegistry, &GeneratorSettings) -> Box<dyn WorldGenerator> + Send + Sync>;
+
+// Checks the value of a setting before a world is created with it
+pub type SettingValidator = Arc<dyn Fn(&str, &BlockRegistry) -> Result<(), String> + Send + Sync>;
+
+// A setting of a generator, shown as a text field in the world creation menu
+#[derive(Clone)]
+pub struct GeneratorSetting {
+    pub key: String,
+    pub label: String,
+    pub default: String,
+    // Named values the field can be filled with, shown in a dropdown next to it
+    pub presets: Vec<(String, String)>,
+    pub validator: SettingValidator,
+}
+
+impl GeneratorSetting {
+    pub fn new(
+        key: &str,
+        label: &str,
+        default: &str,
+        validator: impl Fn(&str, &BlockRegistry) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            key: key.to_string(),
+            label: label.to_string(),
+            default: default.to_string(),
+            presets: Vec::new(),
+            validator: Arc::new(validator),
+        }
+    }
+
+    pub fn with_presets(mut self, presets: &[(&str, &str)]) -> Self {
+        self.presets = presets
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        return self;
+    }
+
+    pub fn validate(&self, value: &str, registry: &BlockRegistry) -> Result<(), String> {
+        return (self.validator)(value, registry);
+    }
+}
+
+#[derive(Clone)]
+struct RegisteredGenerator {
+    id: String,
+    display_name: String,
+    settings: Vec<GeneratorSetting>,
+    factory: GeneratorFactory,
+}
+
+// Every generator a world can be created with, in the order they are shown in the menu.
+// The id is what gets saved into world.toml, so it should never change.
+#[derive(Resource, Default, Clone)]
+pub struct WorldGenerators {
+    generators: Vec<RegisteredGenerator>,
+}
+
+impl WorldGenerators {
+    pub fn register(
+        &mut self,
+        id: &str,
+        display_name: &str,
+        settings: Vec<GeneratorSetting>,
+        factory: impl Fn(&BlockRegistry, &GeneratorSettings) -> Box<dyn WorldGenerator>
+            + Send
+            + Sync
+            + 'static,
+    ) {
+        if self.find(id).is_some() {
+            warn!("The world generator '{}' was registered twice", id);
+            return;
+        }
+        self.generators.push(RegisteredGenerator {
+            id: id.to_string(),
+            display_name: display_name.to_string(),
+            settings,
+            factory: Arc::new(factory),
+        });
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        return self
+            .generators
+            .iter()
+            .map(|generator| generator.id.clone())
+            .collect();
+    }
+
+    // Worlds created before the generators could be registered have their preset
+    // saved in upper case, like "DEFAULT"
+    fn find(&self, id: &str) -> Option<&RegisteredGenerator> {
+        return self
+            .generators
+            .iter()
+            .find(|generator| generator.id.eq_ignore_ascii_case(id));
+    }
+
+    pub fn create(
+        &self,
+        id: &str,
+        registry: &BlockRegistry,
+        settings: &GeneratorSettings,
+    ) -> Option<Box<dyn WorldGenerator>> {
+        return self
+            .find(id)
+            .map(|generator| (generator.factory)(registry, settings));
+    }
+
+    // Unknown generators, like the ones of a plugin that was removed, are shown with their id
+    pub fn display_name(&self, id: &str) -> String {
+        return match self.find(id) {
+            Some(generator) => generator.display_name.clone(),
+            None => id.to_string(),
+        };
+    }
+
+    pub fn settings(&self, id: &str) -> &[GeneratorSetting] {
+        return match self.find(id) {
+            Some(generator) => &generator.settings,
+            None => &[],
+        };
+    }
+
+    // Fills in the defaults of the settings that aren't given, and checks every value
+    pub fn validate_settings(
+        &self,
+        id: &str,
+        registry: &BlockRegistry,
+        mut settings: GeneratorSettings,
+    ) -> Result<GeneratorSettings, String> {
+        for setting in self.settings(id) {
+            let value = settings
+                .entry(setting.key.clone())
+                .or_insert_with(|| setting.default.clone());
+            setting
+                .validate(value, registry)
+                .map_err(|e| format!("{}: {}", setting.label, e))?;
+        }
+        return Ok(settings);
+    }
+}
+
+pub trait WorldGeneratorAppExt {
+    fn register_world_generator(
+        &mut self,
+        id: &str,
+        display_name: &str,
+        settings: Vec<GeneratorSetting>,
+        factory: impl Fn(&BlockRegistry, &GeneratorSettings) -> Box<dyn WorldGenerator>
+            + Send
+            + Sync
+            + 'static,
+    ) -> &mut Self;
+}
+
+impl WorldGeneratorAppExt for App {
+    fn register_world_generator(
+        &mut self,
+        id: &str,
+        display_name: &str,
+        settings: Vec<GeneratorSetting>,
+        factory: impl Fn(&BlockRegistry, &GeneratorSettings) -> Box<dyn WorldGenerator>
+            + Send
+            + Sync
+            + 'static,
+    ) -> &mut Self {
+        self.init_resource::<WorldGenerators>();
+        self.world_mut().resource_mut::<WorldGenerators>().register(
+            id,
+            display_name,
+            settings,
+            factory,
+        );
+        return self;
+    }
+}
+
+// The generator of the world being played, cloned into the chunk loading tasks
+#[derive(Resource, Clone)]
+pub struct CurrentWorldGenerator {
+    pub generator: Arc<dyn WorldGenerator>,
+    pub seed: u64,
+}
+
+impl CurrentWorldGenerator {
+    pub fn generate(&self, chunk_pos: IVec2) -> ChunkGenerationResult {
+        return self.generator.generate(chunk_pos, self.seed);
+    }
+}
+
+pub struct WorldGeneratorPlugin;
+
+impl Plugin for WorldGeneratorPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_world_generator("default", "Default", Vec::new(), |registry, _| {
+            Box::new(DefaultGenerator::new(registry))
+        })
+        .register_world_generator(
+            "flat",
+            "Flat",
+            vec![GeneratorSetting::new(
+                "layers",
+                "Layers",
+                DEFAULT_FLAT_LAYERS,
+                |text, registry| parse_flat_layers(text, registry).map(|_| ()),
+            )
+            .with_presets(&FLAT_LAYER_PRESETS)],
+            |registry, settings| Box::new(FlatGenerator::new(registry, settings)),
+        )
+        .register_world_generator("empty", "Empty", Vec::new(), |registry, _| {
+            Box::new(EmptyGenerator::new(registry))
+        });
+    }
+}
+
+// The lowest layer of a flat world starts at this height
+pub const FLAT_BOTTOM: i32 = -64;
+// The most blocks a single layer of a flat world can have
+const MAX_FLAT_LAYER_COUNT: u32 = 4096;
+// The most blocks all the layers of a flat world can have together
+const MAX_FLAT_HEIGHT: usize = 4096;
+
+// Grass at y = 8, like the flat worlds made before the layers could be configured
+pub const DEFAULT_FLAT_LAYERS: &str = "stone*32,dirt*40,grass";
+
+// The presets of the layers setting, shown in the world creation menu
+pub const FLAT_LAYER_PRESETS: [(&str, &str); 4] = [
+    ("Classic", DEFAULT_FLAT_LAYERS),
+    ("Desert", "stone*56,sand*17"),
+    ("Stone Floor", "stone*73"),
+    ("Glass Floor", "stone*72,glass"),
+];
+
+// Parses layers like "stone*40,dirt*3,grass", from the bottom to the top.
+// Each layer is the name of a block, optionally followed by how many blocks high it is.
+pub fn parse_flat_layers(text: &str, registry: &BlockRegistry) -> Result<Vec<BlockType>, String> {
+    let mut layers = Vec::new();
+    for layer in text.split(',') {
+        let layer = layer.trim();
+        let (name, count) = match layer.split_once('*') {
+            Some((name, count)) => match count.trim().parse::<u32>() {
+                Ok(count) if count > 0 && count <= MAX_FLAT_LAYER_COUNT => (name.trim(), count),
+                _ => {
+                    return Err(format!(
+                        "'{}' should be a number of blocks from 1 to {}",
+                        count.trim(),
+                        MAX_FLAT_LAYER_COUNT
+                    ))
+                }
+            },
+            None => (layer, 1),
+        };
+
+        if name.is_empty() {
+            return Err("there is an empty layer".to_string());
+        }
+        let Some(block) = registry.id(name) else {
+            return Err(format!("there is no block called '{}'", name));
+        };
+        if layers.len() + count as usize > MAX_FLAT_HEIGHT {
+            return Err(format!(
+                "the layers can't be more than {} blocks high",
+                MAX_FLAT_HEIGHT
+            ));
+        }
+        layers.extend(std::iter::repeat(block).take(count as usize));
+    }
+    return Ok(layers);
+}
+
+pub struct FlatGenerator {
+    // From the bottom to the top, one block for each y starting at FLAT_BOTTOM
+    layers: Vec<BlockType>,
+}
+
+impl FlatGenerator {
+    pub fn new(registry: &BlockRegistry, settings: &GeneratorSettings) -> Self {
+        let text = settings
+            .get("layers")
+            .map(|text| text.as_str())
+            .unwrap_or(DEFAULT_FLAT_LAYERS);
+        let layers = match parse_flat_layers(text, registry) {
+            Ok(layers) => layers,
+            Err(e) => {
+                error!("Invalid flat world layers '{}': {}", text, e);
+                parse_flat_layers(DEFAULT_FLAT_LAYERS, registry).unwrap_or_default()
+            }
+        };
+        Self { layers }
+    }
+
+    // The first layer also fills everything under the bottom, so there is no void to fall into
+    fn layer_at(&self, y: i32) -> BlockType {
+        if y < FLAT_BOTTOM {
+            return self.layers.first().copied().unwrap_or(BlockType::AIR);
+        }
+        return self
+            .layers
+            .get((y - FLAT_BOTTOM) as usize)
+            .copied()
+            .unwrap_or(BlockType::AIR);
+    }
+}
+
+impl WorldGenerator for FlatGenerator {
+    fn generate(&self, chunk_pos: IVec2, _seed: u64) -> ChunkGenerationResult {
+        let mut result = ChunkGenerationResult::default();
+        for y in 0..CHUNK_WIDTH {
+            let block = self.layer_at(chunk_pos.y * CHUNK_WIDTH as i32 + y as i32);
+            for x in 0..CHUNK_WIDTH {
+                let i = get_index_from_position(UVec2::new(x as u32, y as u32));
+                result.blocks[i] = block;
+                result.walls[i] = block;
+            }
+        }
+        return result;
+    }
+}
+
+// A small stone platform at the origin, and nothing else
+pub struct EmptyGenerator {
+    stone: BlockType,
+}
+
+impl EmptyGenerator {
+    pub fn new(registry: &BlockRegistry) -> Self {
+        Self {
+            stone: registry.id("stone").unwrap_or_default(),
+        }
+    }
+}
+
+impl WorldGenerator for EmptyGenerator {
+    fn generate(&self, chunk_pos: IVec2, _seed: u64) -> ChunkGenerationResult {
+        let mut result = ChunkGenerationResult::default();
+        if chunk_pos == IVec2::ZERO {
+            for x in 0..(CHUNK_WIDTH / 2) {
+                result.blocks[x] = self.stone;
+            }
+        } else if chunk_pos == IVec2::new(-1, 0) {
+            for x in 0..(CHUNK_WIDTH / 2) {
+                result.blocks[(CHUNK_WIDTH / 2) + x] = self.stone;
+            }
+        }
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> BlockRegistry {
+        return BlockRegistry::from_toml(include_str!("../assets/blocks.toml")).unwrap();
+    }
+
+    #[test]
+    fn layers_are_parsed_from_the_bottom() {
+        let registry = registry();
+        let stone = registry.id("stone").unwrap();
+        let dirt = registry.id("dirt").unwrap();
+        let grass = registry.id("grass").unwrap();
+
+        let layers = parse_flat_layers("stone*40,dirt*3,grass", &registry).unwrap();
+        assert_eq!(layers.len(), 44);
+        assert!(layers[..40].iter().all(|block| *block == stone));
+        assert!(layers[40..43].iter().all(|block| *block == dirt));
+        assert_eq!(layers[43], grass);
+    }
+
+    #[test]
+    fn whitespace_around_the_layers_is_ignored() {
+        let registry = registry();
+        assert_eq!(
+            parse_flat_layers(" stone * 2 , grass ", &registry),
+            parse_flat_layers("stone*2,grass", &registry)
+        );
+    }
+
+    #[test]
+    fn invalid_layers_are_rejected() {
+        let registry = registry();
+        for text in [
+            "stone*40,cheese",
+            "stone*0",
+            "stone*",
+            "stone*two",
+            "stone*-1",
+            "*3",
+            "stone,,grass",
+            "",
+            "stone*4096,grass",
+        ] {
+            assert!(
+                parse_flat_layers(text, &registry).is_err(),
+                "'{}' was accepted",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn flat_top_layer_is_at_the_bottom_plus_the_height() {
+        let registry = registry();
+        let grass = registry.id("grass").unwrap();
+        let mut settings = GeneratorSettings::new();
+        settings.insert("layers".to_string(), "stone*40,dirt*3,grass".to_string());
+        let generator = FlatGenerator::new(&registry, &settings);
+
+        let top = FLAT_BOTTOM + 44 - 1;
+        assert_eq!(generator.layer_at(top), grass);
+        assert_eq!(generator.layer_at(top + 1), BlockType::AIR);
+        assert_eq!(
+            generator.layer_at(FLAT_BOTTOM - 10),
+            registry.id("stone").unwrap()
+        );
+
+        // The same in the generated chunk that has the top layer
+        let chunk_y = top.div_euclid(CHUNK_WIDTH as i32);
+        let y = top.rem_euclid(CHUNK_WIDTH as i32) as u32;
+        let result = generator.generate(IVec2::new(-3, chunk_y), 0);
+        assert_eq!(
+            result.blocks[get_index_from_position(UVec2::new(0, y))],
+            grass
+        );
+        if y + 1 < CHUNK_WIDTH as u32 {
+            assert_eq!(
+                result.blocks[get_index_from_position(UVec2::new(0, y + 1))],
+                BlockType::AIR
+            );
+        }
+    }
+}